@@ -1,3 +1,5 @@
+use arq::{storage::ByteRate, CommitSelector};
+use chrono::prelude::*;
 use gumdrop::Options;
 use std::path::PathBuf;
 use uuid::Uuid;
@@ -7,6 +9,29 @@ pub enum Command {
     ListComputers(ListComputerOpts),
     ListFolders(ListFolderOpts),
     ListFiles(ListFileOpts),
+    ListCommits(ListCommitOpts),
+    Diff(DiffOpts),
+    History(HistoryOpts),
+    Find(FindOpts),
+    ChangePassword(ChangePasswordOpts),
+    CheckPassword(CheckPasswordOpts),
+}
+
+impl Command {
+    /// The computer the command operates on, if it has one
+    pub fn computer(&self) -> Option<&Uuid> {
+        match self {
+            Command::ListComputers(_) => None,
+            Command::ListFolders(opts) => Some(&opts.computer),
+            Command::ListFiles(opts) => Some(&opts.computer),
+            Command::ListCommits(opts) => Some(&opts.computer),
+            Command::Diff(opts) => Some(&opts.computer),
+            Command::History(opts) => Some(&opts.computer),
+            Command::Find(opts) => Some(&opts.computer),
+            Command::ChangePassword(opts) => Some(&opts.computer),
+            Command::CheckPassword(opts) => Some(&opts.computer),
+        }
+    }
 }
 
 #[derive(Debug, Options)]
@@ -28,8 +53,183 @@ pub struct ListFileOpts {
     #[options(help = "The folder to list", meta = "UUID", required)]
     pub folder: Uuid,
 
-    #[options(help = "A regex describing the path of the file(s) to list")]
+    #[options(
+        help = "A glob describing the path of the file(s) to list, e.g. \"Documents/**/*.pdf\" (default all)",
+        meta = "GLOB"
+    )]
     pub path: String,
+
+    #[options(
+        no_short,
+        help = "The commit to use: HEAD, HEAD~n, or a SHA1 or a prefix of one (default HEAD)",
+        meta = "COMMIT"
+    )]
+    pub commit: Option<CommitSelector>,
+
+    #[options(
+        no_short,
+        help = "Use the newest commit at or before this time, e.g. \"2021-03-02 09:00\"",
+        meta = "TIME",
+        parse(try_from_str = "parse_time")
+    )]
+    pub as_of: Option<DateTime<Utc>>,
+
+    #[options(help = "How many trees to fetch at once", meta = "N")]
+    pub jobs: Option<usize>,
+}
+
+#[derive(Debug, Options)]
+pub struct ListCommitOpts {
+    #[options(help = "The computer to operate on", meta = "UUID", required)]
+    pub computer: Uuid,
+
+    #[options(help = "The folder to list", meta = "UUID", required)]
+    pub folder: Uuid,
+
+    #[options(
+        no_short,
+        help = "List commits found in the reflog that are no longer part of the history"
+    )]
+    pub orphaned: bool,
+}
+
+#[derive(Debug, Options)]
+pub struct DiffOpts {
+    #[options(help = "The computer to operate on", meta = "UUID", required)]
+    pub computer: Uuid,
+
+    #[options(help = "The folder to compare", meta = "UUID", required)]
+    pub folder: Uuid,
+
+    #[options(
+        no_short,
+        help = "The older commit: HEAD~n, or a SHA1 or a prefix of one",
+        meta = "COMMIT"
+    )]
+    pub from: Option<CommitSelector>,
+
+    #[options(
+        no_short,
+        help = "Use the newest commit at or before this time as the older commit",
+        meta = "TIME",
+        parse(try_from_str = "parse_time")
+    )]
+    pub from_as_of: Option<DateTime<Utc>>,
+
+    #[options(no_short, help = "The newer commit (default HEAD)", meta = "COMMIT")]
+    pub to: Option<CommitSelector>,
+
+    #[options(
+        no_short,
+        help = "Use the newest commit at or before this time as the newer commit",
+        meta = "TIME",
+        parse(try_from_str = "parse_time")
+    )]
+    pub to_as_of: Option<DateTime<Utc>>,
+
+    #[options(no_short, help = "Print each change as a line of JSON")]
+    pub json: bool,
+}
+
+#[derive(Debug, Options)]
+pub struct HistoryOpts {
+    #[options(help = "The computer to operate on", meta = "UUID", required)]
+    pub computer: Uuid,
+
+    #[options(help = "The folder to search", meta = "UUID", required)]
+    pub folder: Uuid,
+
+    #[options(
+        help = "The file or directory, relative to the folder or under its local path",
+        meta = "PATH",
+        required
+    )]
+    pub path: PathBuf,
+
+    #[options(
+        no_short,
+        help = "The commit whose history to search: HEAD, HEAD~n, or a SHA1 or a prefix of one (default HEAD)",
+        meta = "COMMIT"
+    )]
+    pub commit: Option<CommitSelector>,
+
+    #[options(
+        no_short,
+        help = "Search the history of the newest commit at or before this time, e.g. \"2021-03-02 09:00\"",
+        meta = "TIME",
+        parse(try_from_str = "parse_time")
+    )]
+    pub as_of: Option<DateTime<Utc>>,
+
+    #[options(no_short, help = "Print each version as a line of JSON")]
+    pub json: bool,
+}
+
+#[derive(Debug, Options)]
+pub struct FindOpts {
+    #[options(help = "The computer to search", meta = "UUID", required)]
+    pub computer: Uuid,
+
+    #[options(
+        help = "A glob matching the file name, e.g. \"*.xlsx\" (default all)",
+        meta = "GLOB"
+    )]
+    pub name: Option<String>,
+
+    #[options(no_short, help = "The smallest size to look for", meta = "BYTES")]
+    pub min_size: Option<u64>,
+
+    #[options(no_short, help = "The largest size to look for", meta = "BYTES")]
+    pub max_size: Option<u64>,
+
+    #[options(
+        no_short,
+        help = "Only find files modified at or after this time",
+        meta = "TIME",
+        parse(try_from_str = "parse_time")
+    )]
+    pub modified_after: Option<DateTime<Utc>>,
+
+    #[options(
+        no_short,
+        help = "Only find files modified before this time",
+        meta = "TIME",
+        parse(try_from_str = "parse_time")
+    )]
+    pub modified_before: Option<DateTime<Utc>>,
+
+    #[options(
+        no_short,
+        help = "Search every commit in each folder's history, not just the selected one"
+    )]
+    pub all_commits: bool,
+
+    #[options(
+        no_short,
+        help = "The commit to search in each folder, or to start from with --all-commits: HEAD, HEAD~n, or a SHA1 or a prefix of one (default HEAD)",
+        meta = "COMMIT"
+    )]
+    pub commit: Option<CommitSelector>,
+
+    #[options(
+        no_short,
+        help = "Search the newest commit at or before this time, e.g. \"2021-03-02 09:00\"",
+        meta = "TIME",
+        parse(try_from_str = "parse_time")
+    )]
+    pub as_of: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Options)]
+pub struct ChangePasswordOpts {
+    #[options(help = "The computer to operate on", meta = "UUID", required)]
+    pub computer: Uuid,
+}
+
+#[derive(Debug, Options)]
+pub struct CheckPasswordOpts {
+    #[options(help = "The computer to operate on", meta = "UUID", required)]
+    pub computer: Uuid,
 }
 
 #[derive(Debug, Options)]
@@ -46,9 +246,190 @@ pub struct Args {
     #[options(help = "Encryption password", meta = "PWD")]
     pub password: String,
 
+    #[options(
+        no_short,
+        help = "Limit download bandwidth, e.g. 500K or 5M (overrides config file)",
+        meta = "RATE"
+    )]
+    pub limit_rate: Option<ByteRate>,
+
+    #[options(
+        no_short,
+        help = "Write storage request metrics to a Prometheus text file",
+        meta = "PATH"
+    )]
+    pub metrics_file: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        help = "Record every storage request into an archive file for later replay",
+        meta = "PATH"
+    )]
+    pub record: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        help = "Serve storage requests from an archive created with --record",
+        meta = "PATH"
+    )]
+    pub replay: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        help = "Keep derived encryption keys in this directory between runs; it is as sensitive as the password (overrides config file)",
+        meta = "DIR"
+    )]
+    pub key_cache: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        help = "Keep decrypted trees and commits in this directory between runs (overrides config file)",
+        meta = "DIR"
+    )]
+    pub tree_cache: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        help = "Encrypt the entries of the tree cache with keys derived from the password"
+    )]
+    pub encrypt_tree_cache: bool,
+
+    #[options(
+        no_short,
+        help = "Check restored data against its SHA1 and fail on a mismatch"
+    )]
+    pub verify: bool,
+
     #[options(command)]
     pub cmd: Option<Command>,
 }
 
+impl DiffOpts {
+    /// The older commit, from --from or --from-as-of, one of which is needed
+    pub fn older_commit(&self) -> Result<CommitSelector, String> {
+        alternatives(self.from.clone(), self.from_as_of, "--from", "--from-as-of")?
+            .ok_or_else(|| "--from or --from-as-of is needed".to_string())
+    }
+
+    /// The newer commit, from --to or --to-as-of (default HEAD)
+    pub fn newer_commit(&self) -> Result<CommitSelector, String> {
+        alternatives(self.to.clone(), self.to_as_of, "--to", "--to-as-of")
+            .map(Option::unwrap_or_default)
+    }
+}
+
+/// Combines the --commit and --as-of options, which are alternatives
+pub fn commit_selector(
+    commit: Option<CommitSelector>,
+    as_of: Option<DateTime<Utc>>,
+) -> Result<CommitSelector, String> {
+    alternatives(commit, as_of, "--commit", "--as-of").map(Option::unwrap_or_default)
+}
+
+/// Combines an option naming a commit with the option giving a time
+/// instead, if either was used
+fn alternatives(
+    commit: Option<CommitSelector>,
+    as_of: Option<DateTime<Utc>>,
+    commit_option: &str,
+    as_of_option: &str,
+) -> Result<Option<CommitSelector>, String> {
+    match (commit, as_of) {
+        (Some(_), Some(_)) => Err(format!(
+            "{} and {} can't be used together",
+            commit_option, as_of_option
+        )),
+        (Some(commit), None) => Ok(Some(commit)),
+        (None, Some(time)) => Ok(Some(CommitSelector::AsOf(time))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Parses a time given on the command line: either RFC 3339, or a date and
+/// optional time in the local time zone
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let formats = [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+    ];
+    let naive = formats
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .map(|d| d.and_hms(0, 0, 0))
+        })
+        .ok_or_else(|| format!("Not a time: {:?}", s))?;
+
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| format!("{:?} doesn't exist in the local time zone", s))
+}
+
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+
+    #[test]
+    fn times_are_parsed() {
+        assert_eq!(
+            parse_time("2021-03-02T09:00:00+10:00"),
+            Ok(Utc.ymd(2021, 3, 1).and_hms(23, 0, 0))
+        );
+
+        let local = |h, m, s| Local.ymd(2021, 3, 2).and_hms(h, m, s).with_timezone(&Utc);
+        assert_eq!(parse_time("2021-03-02 09:00"), Ok(local(9, 0, 0)));
+        assert_eq!(parse_time("2021-03-02T09:00:30"), Ok(local(9, 0, 30)));
+        assert_eq!(parse_time("2021-03-02"), Ok(local(0, 0, 0)));
+
+        assert!(parse_time("last Tuesday").is_err());
+    }
+
+    #[test]
+    fn commit_options_are_alternatives() {
+        let time = Utc.ymd(2021, 3, 1).and_hms(23, 0, 0);
+        assert_eq!(commit_selector(None, None), Ok(CommitSelector::Head(0)));
+        assert_eq!(
+            commit_selector(Some(CommitSelector::Head(2)), None),
+            Ok(CommitSelector::Head(2))
+        );
+        assert_eq!(
+            commit_selector(None, Some(time)),
+            Ok(CommitSelector::AsOf(time))
+        );
+        assert!(commit_selector(Some(CommitSelector::Head(2)), Some(time)).is_err());
+    }
+
+    #[test]
+    fn diffs_need_an_older_commit() {
+        let time = Utc.ymd(2021, 3, 1).and_hms(23, 0, 0);
+        let parse = |args: &[&str]| DiffOpts::parse_args_default(args).unwrap();
+        let uuids = [
+            "--computer",
+            "3FE5A4C6-ED3B-4D4C-8D3F-3F4A3C5A6B7C",
+            "--folder",
+            "408E376B-ECF7-4688-902A-1E7671BC5B9A",
+        ];
+
+        let opts = parse(&uuids);
+        assert!(opts.older_commit().is_err());
+        assert_eq!(opts.newer_commit(), Ok(CommitSelector::Head(0)));
+
+        let opts = parse(&[&uuids[..], &["--from-as-of", "2021-03-02T09:00:00+10:00"]].concat());
+        assert_eq!(opts.older_commit(), Ok(CommitSelector::AsOf(time)));
+
+        let both = ["--to", "HEAD~1", "--to-as-of", "2021-03-02"];
+        let opts = parse(&[&uuids[..], &["--from", "HEAD~2"], &both].concat());
+        assert_eq!(opts.older_commit(), Ok(CommitSelector::Head(2)));
+        assert!(opts.newer_commit().is_err());
+    }
+}
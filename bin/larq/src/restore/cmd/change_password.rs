@@ -0,0 +1,23 @@
+use crate::{cli::ChangePasswordOpts, prompt::read_password};
+use arq::{crypto::Password, format_uuid, RepoError, Repository};
+use log::{error, info};
+
+/// Asks for the new password at the terminal, or reads it from standard
+/// input, so that it never shows up in the process list or shell history.
+pub async fn change_password(
+    repo: &Repository,
+    password: &Password,
+    args: ChangePasswordOpts,
+) -> Result<(), RepoError> {
+    let computer_id = format_uuid(&args.computer);
+
+    let new_password = read_password("New encryption password: ", true).map_err(|e| {
+        error!("Reading the new password failed: {}", e);
+        RepoError::InputError
+    })?;
+    repo.change_password(&computer_id, password, &new_password)
+        .await?;
+
+    info!("Changed encryption password for {}", computer_id);
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use crate::cli::CheckPasswordOpts;
+use arq::{crypto::Password, format_uuid, Repository};
+use log::{error, info};
+
+/// Succeeds only if the password is correct, so that scripts can rely on the
+/// exit status.
+pub async fn check_password(
+    repo: &Repository,
+    password: &Password,
+    args: CheckPasswordOpts,
+) -> Result<(), ()> {
+    let computer_id = format_uuid(&args.computer);
+
+    let valid = repo
+        .verify_password(&computer_id, password)
+        .await
+        .map_err(|e| {
+            error!("Password check failed with error: {:?}", e);
+        })?;
+
+    if valid {
+        info!("Password is correct for {}", computer_id);
+        Ok(())
+    } else {
+        error!("Password is incorrect for {}", computer_id);
+        Err(())
+    }
+}
@@ -0,0 +1,74 @@
+use crate::cli::DiffOpts;
+use arq::{format_uuid, Change, DiffEntry, RepoError, Repository};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use log::{error, info};
+use serde::Serialize;
+
+/// A change as printed with --json
+#[derive(Serialize)]
+struct JsonChange {
+    path: String,
+    change: Change,
+    is_tree: bool,
+    old_size: Option<u64>,
+    new_size: Option<u64>,
+    old_mod_time: Option<DateTime<Utc>>,
+    new_mod_time: Option<DateTime<Utc>>,
+}
+
+impl From<&DiffEntry> for JsonChange {
+    fn from(entry: &DiffEntry) -> JsonChange {
+        JsonChange {
+            path: entry.path.to_string_lossy().into_owned(),
+            change: entry.change,
+            is_tree: entry.is_tree(),
+            old_size: entry.old.as_ref().map(|n| n.data_size),
+            new_size: entry.new.as_ref().map(|n| n.data_size),
+            old_mod_time: entry.old.as_ref().map(|n| n.mod_time),
+            new_mod_time: entry.new.as_ref().map(|n| n.mod_time),
+        }
+    }
+}
+
+/// Prints what changed in a folder between two of its commits
+pub async fn diff(repo: &Repository, args: DiffOpts) -> Result<(), RepoError> {
+    let input_error = |e: String| {
+        error!("{}", e);
+        RepoError::InputError
+    };
+    let from = args.older_commit().map_err(input_error)?;
+    let to = args.newer_commit().map_err(input_error)?;
+
+    let computer = repo.get_computer(format_uuid(&args.computer)).await?;
+    let folder = computer.get_folder(&format_uuid(&args.folder)).await?;
+
+    let from = folder.select_commit(&from).await?;
+    let to = folder.select_commit(&to).await?;
+    info!("Comparing {} with {}", from.id(), to.id());
+
+    let json = args.json;
+    from.diff(&to)
+        .try_for_each(|entry| async move {
+            if json {
+                let line = serde_json::to_string(&JsonChange::from(&entry))
+                    .map_err(|_| RepoError::MalformedData)?;
+                println!("{}", line);
+            } else {
+                println!("{}", describe(&entry));
+            }
+            Ok(())
+        })
+        .await
+}
+
+fn describe(entry: &DiffEntry) -> String {
+    let label = match entry.change {
+        Change::Added => "added",
+        Change::Removed => "removed",
+        Change::Modified => "modified",
+        Change::MetadataChanged => "metadata",
+    };
+    let slash = if entry.is_tree() { "/" } else { "" };
+    format!("{:<8}  {}{}", label, entry.path.display(), slash)
+}
@@ -0,0 +1,87 @@
+use crate::cli::{commit_selector, FindOpts};
+use arq::{format_uuid, Commit, FileQuery, FileSearch, RepoError, Repository, WalkEntry};
+use futures::{pin_mut, TryStreamExt};
+use log::{error, info, warn};
+
+fn print_matches(folder: &str, commit: &Commit<'_>, entries: &[WalkEntry]) {
+    for entry in entries {
+        println!(
+            "{}  {}  {}  {} bytes  modified {}",
+            folder,
+            commit.id(),
+            entry.path.display(),
+            entry.node.data_size,
+            entry.node.mod_time.to_rfc3339()
+        );
+    }
+}
+
+/// Prints the matches in one commit, or warns if it can't be searched
+async fn search_commit(folder: &str, commit: &Commit<'_>, search: &mut FileSearch) {
+    match commit.search(search).await {
+        Ok(entries) => print_matches(folder, commit, &entries),
+        Err(e) => warn!("Skipping commit {} of {}: {:?}", commit.id(), folder, e),
+    }
+}
+
+/// Searches every folder of a computer for files by name, size and
+/// modification time. A subtree shared between commits, or even folders, is
+/// only searched once. Folders that can't be read or lack the selected
+/// commit, and commits that can't be searched, are skipped with a warning.
+pub async fn find(repo: &Repository, args: FindOpts) -> Result<(), RepoError> {
+    let selector = commit_selector(args.commit, args.as_of).map_err(|e| {
+        error!("{}", e);
+        RepoError::InputError
+    })?;
+
+    let mut query = FileQuery::new()
+        .with_size(args.min_size, args.max_size)
+        .with_mod_time(args.modified_after, args.modified_before);
+    if let Some(name) = args.name.as_ref() {
+        query = query.with_name(name).map_err(|_| {
+            error!("Invalid name pattern {:?}", name);
+            RepoError::InputError
+        })?;
+    }
+    let mut search = FileSearch::new(query);
+
+    let computer = repo.get_computer(format_uuid(&args.computer)).await?;
+    for info in computer.list_folders().await? {
+        let folder_id = format_uuid(info.id());
+        info!("Searching {} ({:?})", info.name(), info.local_path());
+        let folder = match computer.get_folder(&folder_id).await {
+            Ok(folder) => folder,
+            Err(e) => {
+                warn!("Skipping {}: {:?}", folder_id, e);
+                continue;
+            }
+        };
+
+        let commit = match folder.select_commit(&selector).await {
+            Ok(commit) => commit,
+            Err(e) => {
+                warn!("Skipping {}: no commit {}: {:?}", folder_id, selector, e);
+                continue;
+            }
+        };
+
+        if !args.all_commits {
+            search_commit(&folder_id, &commit, &mut search).await;
+            continue;
+        }
+
+        let history = folder.history_from(commit);
+        pin_mut!(history);
+        loop {
+            match history.try_next().await {
+                Ok(Some(commit)) => search_commit(&folder_id, &commit, &mut search).await,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Skipping the rest of {}'s history: {:?}", folder_id, e);
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
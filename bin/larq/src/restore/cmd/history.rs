@@ -0,0 +1,78 @@
+use crate::cli::{commit_selector, HistoryOpts};
+use arq::{format_uuid, PathVersion, RepoError, Repository, SHA1};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+
+/// A commit as printed with --json
+#[derive(Serialize)]
+struct JsonCommit<'a> {
+    id: &'a SHA1,
+    timestamp: &'a DateTime<Utc>,
+}
+
+/// A version as printed with --json
+#[derive(Serialize)]
+struct JsonVersion<'a> {
+    blobs: Vec<&'a SHA1>,
+    size: u64,
+    mod_time: &'a DateTime<Utc>,
+    commits: Vec<JsonCommit<'a>>,
+}
+
+impl<'a> From<&'a PathVersion> for JsonVersion<'a> {
+    fn from(version: &'a PathVersion) -> JsonVersion<'a> {
+        JsonVersion {
+            blobs: version.node.data_blob_keys.iter().map(|k| &k.sha).collect(),
+            size: version.node.data_size,
+            mod_time: &version.node.mod_time,
+            commits: version
+                .commits
+                .iter()
+                .map(|c| JsonCommit {
+                    id: &c.id,
+                    timestamp: &c.timestamp,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Prints each distinct version of a path in a folder's history, newest
+/// first, along with the commits it appears in
+pub async fn history(repo: &Repository, args: HistoryOpts) -> Result<(), RepoError> {
+    let selector = commit_selector(args.commit, args.as_of).map_err(|e| {
+        error!("{}", e);
+        RepoError::InputError
+    })?;
+
+    let computer = repo.get_computer(format_uuid(&args.computer)).await?;
+    let folder = computer.get_folder(&format_uuid(&args.folder)).await?;
+
+    info!("Finding versions of {:?}", folder.relative_path(&args.path));
+
+    for version in folder.path_history(&args.path, &selector).await? {
+        if args.json {
+            let line = serde_json::to_string(&JsonVersion::from(&version))
+                .map_err(|_| RepoError::MalformedData)?;
+            println!("{}", line);
+            continue;
+        }
+
+        let node = &version.node;
+        let blob = node
+            .data_blob_keys
+            .first()
+            .map_or_else(|| "-".to_string(), |k| k.sha.to_string());
+        println!(
+            "{}  {} bytes  modified {}",
+            blob,
+            node.data_size,
+            node.mod_time.to_rfc3339()
+        );
+        for commit in version.commits.iter() {
+            println!("    {}  {}", commit.id, commit.timestamp.to_rfc3339());
+        }
+    }
+    Ok(())
+}
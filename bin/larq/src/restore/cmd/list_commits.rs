@@ -0,0 +1,56 @@
+use crate::cli::ListCommitOpts;
+use arq::{format_uuid, Commit, RepoError, Repository};
+use futures::TryStreamExt;
+
+/// Prints every backup record of a folder, newest first, or the orphaned
+/// ones in the order the reflog mentions them, followed by any orphans
+/// that are too old to read
+pub async fn list_commits(repo: &Repository, args: ListCommitOpts) -> Result<(), RepoError> {
+    let computer = repo.get_computer(format_uuid(&args.computer)).await?;
+    let folder = computer.get_folder(&format_uuid(&args.folder)).await?;
+
+    if args.orphaned {
+        let orphans = folder.orphaned_commits().await?;
+        for commit in orphans.commits.iter() {
+            println!("{}", describe(commit));
+        }
+        for id in orphans.unsupported.iter() {
+            println!(
+                "{}  written before Arq stretched keys, so can't be read",
+                id
+            );
+        }
+        return Ok(());
+    }
+
+    folder
+        .history()
+        .try_for_each(|commit| async move {
+            println!("{}", describe(&commit));
+            Ok(())
+        })
+        .await
+}
+
+fn describe(commit: &Commit) -> String {
+    let status = match commit.is_complete() {
+        Some(true) => "complete",
+        Some(false) => "incomplete",
+        None => "-",
+    };
+
+    let mut line = format!(
+        "{}  {}  {:<10}  {}",
+        commit.id(),
+        commit.timestamp().to_rfc3339(),
+        status,
+        commit.arq_version().unwrap_or("-")
+    );
+    if !commit.file_errors().is_empty() {
+        line += &format!("  ({} file errors)", commit.file_errors().len());
+    }
+    if let Some(comment) = commit.comment() {
+        line += &format!("  {}", comment);
+    }
+    line
+}
@@ -1,17 +1,38 @@
-use crate::cli::ListFileOpts;
-use arq::{format_uuid, RepoError, Repository};
-use log::info;
+use crate::cli::{commit_selector, ListFileOpts};
+use arq::{format_uuid, PathFilter, RepoError, Repository};
+use futures::TryStreamExt;
+use log::{error, info};
 
 pub async fn list_files(repo: &Repository, args: ListFileOpts) -> Result<(), RepoError> {
+    let filter = PathFilter::new(&args.path).map_err(|_| {
+        error!("Invalid path pattern {:?}", args.path);
+        RepoError::InputError
+    })?;
+    let selector = commit_selector(args.commit, args.as_of).map_err(|e| {
+        error!("{}", e);
+        RepoError::InputError
+    })?;
+
     // fetch computer info
     let computer = repo.get_computer(format_uuid(&args.computer)).await?;
     let folder = computer.get_folder(&format_uuid(&args.folder)).await?;
 
     info!("Folder: {:?}", folder.local_path());
 
-    let latest_commit = folder.get_latest_commit().await?;
+    let mut commit = folder.select_commit(&selector).await?;
+    if let Some(jobs) = args.jobs {
+        commit = commit.with_concurrency(jobs);
+    }
 
-    info!("Committed at: {:?}", latest_commit.timestamp());
+    info!("Commit {} made at: {:?}", commit.id(), commit.timestamp());
 
-    latest_commit.list_files("**/*").await
+    commit
+        .walk(filter)
+        .try_for_each(|entry| async move {
+            if !entry.node.is_tree {
+                println!("{}: {} bytes", entry.path.display(), entry.node.data_size);
+            }
+            Ok(())
+        })
+        .await
 }
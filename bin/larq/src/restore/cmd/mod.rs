@@ -1,7 +1,19 @@
+mod change_password;
+mod check_password;
+mod diff;
+mod find;
+mod history;
+mod list_commits;
 mod list_computers;
 mod list_files;
 mod list_folders;
 
+pub use change_password::*;
+pub use check_password::*;
+pub use diff::*;
+pub use find::*;
+pub use history::*;
+pub use list_commits::*;
 pub use list_computers::*;
 pub use list_files::*;
 pub use list_folders::*;
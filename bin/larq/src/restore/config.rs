@@ -1,23 +1,119 @@
+use arq::{s3::Provider, storage::ByteRate};
 use log::{debug, error};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fs::File;
 use std::io::{self, Read};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum StorageClass {
+    #[default]
     Standard,
     Glacier,
 }
 
+/// Connection details for a backup set stored on an SFTP server. Exactly one
+/// of `password` or `private_key` should be set; if neither is, we fall back
+/// to the SSH agent.
+#[derive(Debug, Deserialize, Eq, PartialEq, Clone)]
+pub struct SftpConfig {
+    pub host: String,
+
+    #[serde(default = "default_sftp_port")]
+    pub port: u16,
+
+    pub user: String,
+    pub password: Option<String>,
+    pub private_key: Option<PathBuf>,
+    pub passphrase: Option<String>,
+
+    #[serde(default)]
+    pub root: String,
+
+    pub known_hosts: Option<PathBuf>,
+}
+
+fn default_sftp_port() -> u16 {
+    22
+}
+
+// The S3 settings are all optional so that a config file for another
+// backend doesn't have to supply them.
 #[derive(Debug, Deserialize, Eq, PartialEq, Clone)]
 pub struct Config {
+    #[serde(default)]
     pub class: StorageClass,
+
+    #[serde(default)]
     pub access_key_id: String,
+
+    #[serde(default)]
     pub secret_key: String,
+
+    #[serde(default)]
     pub region: String,
+
+    #[serde(default)]
     pub bucket_name: String,
+
+    /// Which S3-compatible service holds the bucket: "aws", "b2" or "gcs"
+    #[serde(
+        default = "default_provider",
+        deserialize_with = "deserialize_provider"
+    )]
+    pub provider: Provider,
+
+    /// Overrides the provider's endpoint, e.g. to point at a local emulator
+    pub endpoint: Option<String>,
+
+    /// The folder inside the bucket that holds the backup set
+    #[serde(default)]
+    pub prefix: String,
+
+    /// Whether the objects directory is split into subdirectories. Defaults
+    /// to whatever Arq does for the chosen provider.
+    pub shard_objects: Option<bool>,
+
+    pub sftp: Option<SftpConfig>,
+
+    #[serde(default, deserialize_with = "deserialize_rate")]
+    pub limit_rate: Option<ByteRate>,
+
+    /// Where to keep derived encryption keys between runs. Anyone who can
+    /// read it can check guesses at the password cheaply, so it needs the
+    /// same protection as the password.
+    pub key_cache: Option<PathBuf>,
+
+    /// Where to keep decrypted trees and commits between runs. Unless they
+    /// are encrypted, the cache is only as private as this directory.
+    pub tree_cache: Option<PathBuf>,
+
+    /// Whether to encrypt the entries of the tree cache, with keys derived
+    /// from the password
+    #[serde(default)]
+    pub encrypt_tree_cache: bool,
+}
+
+fn default_provider() -> Provider {
+    Provider::Aws
+}
+
+fn deserialize_provider<'de, D>(deserializer: D) -> Result<Provider, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let text = String::deserialize(deserializer)?;
+    text.parse()
+        .map_err(|_| serde::de::Error::custom(format!("unknown provider {:?}", text)))
+}
+
+fn deserialize_rate<'de, D>(deserializer: D) -> Result<Option<ByteRate>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let text = String::deserialize(deserializer)?;
+    text.parse().map(Some).map_err(serde::de::Error::custom)
 }
 
 #[derive(Debug)]
@@ -81,8 +177,80 @@ mod test {
             secret_key: "secret_key".to_string(),
             class: StorageClass::Glacier,
             bucket_name: "some-bucket".to_string(),
+            provider: Provider::Aws,
+            endpoint: None,
+            prefix: String::new(),
+            shard_objects: None,
+            sftp: None,
+            limit_rate: None,
+            key_cache: None,
+            tree_cache: None,
+            encrypt_tree_cache: false,
         };
 
         assert_eq!(expected, cfg)
     }
+
+    #[test]
+    fn parse_config_with_rate_limit() {
+        let text = " \
+                    region = \"ap-southeast-2\"\n \
+                    access_key_id = \"ACCESS_KEY_ID\"\n \
+                    secret_key = \"secret_key\"\n \
+                    class = \"glacier\"\n \
+                    bucket_name = \"some-bucket\"\n \
+                    limit_rate = \"5M\"\n";
+
+        let cfg = toml::from_str::<Config>(text).unwrap();
+        assert_eq!(cfg.limit_rate, Some(ByteRate::from(5 * 1024 * 1024)));
+    }
+
+    #[test]
+    fn parse_b2_config() {
+        let text = r#"
+            provider = "b2"
+            region = "us-west-004"
+            access_key_id = "0040123456789ab0000000001"
+            secret_key = "K004secret"
+            bucket_name = "arq-backups"
+            prefix = "arq"
+        "#;
+
+        let cfg = toml::from_str::<Config>(text).unwrap();
+        assert_eq!(cfg.provider, Provider::B2);
+        assert_eq!(cfg.prefix, "arq");
+        assert_eq!(cfg.endpoint, None);
+        assert_eq!(cfg.shard_objects, None);
+    }
+
+    #[test]
+    fn parse_unknown_provider() {
+        assert!(toml::from_str::<Config>("provider = \"azure\"").is_err());
+    }
+
+    #[test]
+    fn parse_sftp_config() {
+        let text = r#"
+            [sftp]
+            host = "backup.example.com"
+            user = "arq"
+            private_key = "/home/arq/.ssh/id_ed25519"
+            root = "/srv/arq"
+        "#;
+
+        let cfg = toml::from_str::<Config>(text).unwrap();
+        let expected = SftpConfig {
+            host: "backup.example.com".to_string(),
+            port: 22,
+            user: "arq".to_string(),
+            password: None,
+            private_key: Some(PathBuf::from("/home/arq/.ssh/id_ed25519")),
+            passphrase: None,
+            root: "/srv/arq".to_string(),
+            known_hosts: None,
+        };
+
+        assert_eq!(cfg.sftp, Some(expected));
+        assert_eq!(cfg.class, StorageClass::Standard);
+    }
 }
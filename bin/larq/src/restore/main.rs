@@ -1,18 +1,31 @@
 mod cli;
 mod cmd;
 mod config;
+mod prompt;
 
 use gumdrop::Options;
-use log::{debug, error, LevelFilter};
-use std::{process::exit, sync::Arc};
+use log::{debug, error, info, warn, LevelFilter};
+use std::{path::PathBuf, process::exit, sync::Arc};
 
-use arq::s3;
+#[cfg(feature = "sftp")]
+use arq::sftp;
+use arq::{
+    crypto::Password,
+    s3,
+    storage::{
+        CachedStore, KeyLayout, LayoutStore, Metrics, MetricsStore, RateLimitedStore,
+        RecordingStore, ReplayStore, Store,
+    },
+    TreeCache,
+};
 use cli::{Args, Command};
 use config::Config;
+#[cfg(feature = "sftp")]
+use config::SftpConfig;
 use simple_logger::SimpleLogger;
 
 fn main() {
-    let args = Args::parse_args_default_or_exit();
+    let mut args = Args::parse_args_default_or_exit();
 
     let log_level = match args.verbose {
         0 => LevelFilter::Warn,
@@ -31,33 +44,249 @@ fn main() {
         }
     };
 
-    if let Some(cmd) = args.cmd {
+    if let Some(cmd) = args.cmd.take() {
+        // Move the password somewhere it will be wiped once we're done
+        let password = Password::new(std::mem::take(&mut args.password));
+        let key_cache = args.key_cache.clone().or_else(|| cfg.key_cache.clone());
+        let tree_cache = open_tree_cache(&cfg, &args);
+        let metrics = Arc::new(Metrics::new());
         let runtime = tokio::runtime::Runtime::new().unwrap();
-        let result = runtime.block_on(dispatch_cmd(&cfg, &args.password, cmd));
+        let result = runtime.block_on(async {
+            let store = match args.replay.as_ref() {
+                Some(path) => match ReplayStore::open(path) {
+                    Ok(replay) => Arc::new(replay) as Arc<dyn Store>,
+                    Err(e) => {
+                        error!("Opening replay archive {:?} failed: {}", path, e);
+                        return 1;
+                    }
+                },
+                // Writes go straight to the backend, past the cache
+                None if matches!(cmd, Command::ChangePassword(_)) => build_transport(&cfg),
+                None => build_store(&cfg, &args, &metrics),
+            };
+
+            match args.record.as_ref() {
+                Some(path) => {
+                    let recorder = Arc::new(RecordingStore::new(store));
+                    let result = dispatch_cmd(
+                        recorder.clone(),
+                        password,
+                        key_cache,
+                        tree_cache,
+                        args.verify,
+                        cmd,
+                    )
+                    .await;
+                    info!("Saving request archive to {:?}", path);
+                    if let Err(e) = recorder.save(path) {
+                        error!("Saving request archive to {:?} failed: {}", path, e);
+                        return 1;
+                    }
+                    result
+                }
+                None => {
+                    dispatch_cmd(store, password, key_cache, tree_cache, args.verify, cmd).await
+                }
+            }
+        });
 
         drop(runtime);
+        report_metrics(&args, &metrics);
         exit(result);
     }
 }
 
-async fn dispatch_cmd(cfg: &Config, secret: &str, cmd: Command) -> i32 {
-    let transport = s3::Store::new(
+#[cfg(feature = "sftp")]
+fn connect_sftp(cfg: &SftpConfig) -> Result<sftp::Store, sftp::Error> {
+    let auth = match (&cfg.password, &cfg.private_key) {
+        (Some(password), _) => sftp::Auth::Password(password.clone()),
+        (None, Some(path)) => sftp::Auth::PrivateKey {
+            path: path.clone(),
+            passphrase: cfg.passphrase.clone(),
+        },
+        (None, None) => sftp::Auth::Agent,
+    };
+
+    let opts = sftp::Options {
+        host: cfg.host.clone(),
+        port: cfg.port,
+        user: cfg.user.clone(),
+        auth,
+        root: cfg.root.clone(),
+        known_hosts: cfg.known_hosts.clone(),
+    };
+
+    sftp::Store::connect(&opts)
+}
+
+fn connect_s3(cfg: &Config) -> s3::Store {
+    let region = cfg
+        .provider
+        .region(&cfg.region, cfg.endpoint.as_deref())
+        .expect("Valid region");
+
+    s3::Store::for_provider(
+        cfg.provider,
         &cfg.bucket_name,
         &cfg.access_key_id,
         &cfg.secret_key,
-        rusoto_core::Region::ApSoutheast2,
-        Some(std::path::PathBuf::from("./cache")),
+        region,
     )
-    .expect("Transport construction");
-    let repo = arq::Repository::new(secret, Arc::new(transport));
+    .expect("Transport construction")
+}
 
-    let result = match cmd {
-        Command::ListComputers(_) => cmd::list_computers(&repo).await,
-        Command::ListFolders(opts) => cmd::list_folders(&repo, opts).await,
-        Command::ListFiles(opts) => cmd::list_files(&repo, opts).await.map_err(|e| {
-            log::error!("Failed: {:?}", e);
-        }),
+fn build_transport(cfg: &Config) -> Arc<dyn Store> {
+    let (transport, layout): (Arc<dyn Store>, KeyLayout) = match cfg.sftp.as_ref() {
+        #[cfg(feature = "sftp")]
+        Some(sftp_cfg) => {
+            let layout = KeyLayout {
+                prefix: String::new(),
+                shard_objects: cfg.shard_objects.unwrap_or(false),
+            };
+            let store = connect_sftp(sftp_cfg).expect("SFTP connection");
+            (Arc::new(store), layout)
+        }
+        #[cfg(not(feature = "sftp"))]
+        Some(_) => {
+            error!("This larq-restore was built without SFTP support");
+            exit(1)
+        }
+        None => {
+            let layout = KeyLayout {
+                prefix: cfg.prefix.clone(),
+                shard_objects: cfg
+                    .shard_objects
+                    .unwrap_or_else(|| cfg.provider.shards_objects()),
+            };
+            (Arc::new(connect_s3(cfg)), layout)
+        }
+    };
+
+    if layout == KeyLayout::default() {
+        transport
+    } else {
+        Arc::new(LayoutStore::new(transport, layout))
+    }
+}
+
+fn build_store(cfg: &Config, args: &Args, metrics: &Arc<Metrics>) -> Arc<dyn Store> {
+    let transport = build_transport(cfg);
+    let mut store: Arc<dyn Store> = Arc::new(MetricsStore::new(transport, metrics.clone()));
+
+    if let Some(rate) = args.limit_rate.or(cfg.limit_rate) {
+        info!("Limiting download rate to {}", rate);
+        store = Arc::new(RateLimitedStore::new(store, rate));
+    }
+
+    let cache =
+        CachedStore::new(store, std::path::PathBuf::from("./cache")).with_metrics(metrics.clone());
+    Arc::new(cache)
+}
+
+fn report_metrics(args: &Args, metrics: &Metrics) {
+    if args.verbose > 0 {
+        eprint!("{}", metrics);
+    }
+
+    if let Some(path) = args.metrics_file.as_ref() {
+        let result = std::fs::File::create(path).and_then(|f| {
+            let mut w = std::io::BufWriter::new(f);
+            metrics.write_prometheus(&mut w)
+        });
+        if let Err(e) = result {
+            error!("Writing metrics to {:?} failed: {}", path, e);
+        }
+    }
+}
+
+/// Opens the tree cache, if there is one, along with whether to encrypt it
+fn open_tree_cache(cfg: &Config, args: &Args) -> Option<(Arc<dyn TreeCache>, bool)> {
+    let dir = args.tree_cache.as_ref().or(cfg.tree_cache.as_ref())?;
+    match arq::FileTreeCache::open(dir) {
+        Ok(cache) => Some((
+            Arc::new(cache),
+            args.encrypt_tree_cache || cfg.encrypt_tree_cache,
+        )),
+        Err(e) => {
+            warn!("Opening tree cache {:?} failed: {}", dir, e);
+            None
+        }
+    }
+}
+
+async fn dispatch_cmd(
+    store: Arc<dyn Store>,
+    secret: Password,
+    key_cache: Option<PathBuf>,
+    tree_cache: Option<(Arc<dyn TreeCache>, bool)>,
+    verify: bool,
+    cmd: Command,
+) -> i32 {
+    let mut repo = match arq::Repository::new(&secret, store) {
+        Ok(repo) => repo,
+        Err(e) => {
+            error!("Opening repository failed: {:?}", e);
+            return 1;
+        }
     };
+    if let Some(dir) = key_cache {
+        match arq::FileKeyCache::open(&dir) {
+            Ok(cache) => repo = repo.with_key_cache(Box::new(cache)),
+            Err(e) => warn!("Opening key cache {:?} failed: {}", dir, e),
+        }
+    }
+    match tree_cache {
+        Some((cache, true)) => repo = repo.with_encrypted_tree_cache(cache),
+        Some((cache, false)) => repo = repo.with_tree_cache(cache),
+        None => {}
+    }
+    if verify {
+        repo = repo.with_blob_verification();
+    }
+
+    // Only the password commands need the password itself; everything else
+    // works from the keys derived from it
+    let result =
+        match cmd {
+            Command::CheckPassword(opts) => cmd::check_password(&repo, &secret, opts).await,
+            Command::ChangePassword(opts) => cmd::change_password(&repo, &secret, opts)
+                .await
+                .map_err(|e| {
+                    log::error!("Changing password failed: {:?}", e);
+                }),
+            cmd => run_cmd(&repo, secret, cmd).await,
+        };
 
     result.map(|_| 0).unwrap_or(1)
 }
+
+async fn run_cmd(repo: &arq::Repository, secret: Password, cmd: Command) -> Result<(), ()> {
+    if let Some(computer) = cmd.computer() {
+        let computer_id = arq::format_uuid(computer);
+        repo.unlock(&computer_id, &secret).await.map_err(|e| {
+            error!("Unlocking {} failed: {:?}", computer_id, e);
+        })?;
+    }
+    drop(secret);
+
+    match cmd {
+        Command::ListComputers(_) => cmd::list_computers(repo).await,
+        Command::ListFolders(opts) => cmd::list_folders(repo, opts).await,
+        Command::ListFiles(opts) => cmd::list_files(repo, opts).await.map_err(|e| {
+            log::error!("Failed: {:?}", e);
+        }),
+        Command::ListCommits(opts) => cmd::list_commits(repo, opts).await.map_err(|e| {
+            log::error!("Failed: {:?}", e);
+        }),
+        Command::Diff(opts) => cmd::diff(repo, opts).await.map_err(|e| {
+            log::error!("Failed: {:?}", e);
+        }),
+        Command::History(opts) => cmd::history(repo, opts).await.map_err(|e| {
+            log::error!("Failed: {:?}", e);
+        }),
+        Command::Find(opts) => cmd::find(repo, opts).await.map_err(|e| {
+            log::error!("Failed: {:?}", e);
+        }),
+        Command::CheckPassword(_) | Command::ChangePassword(_) => unreachable!(),
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::{self, BufRead, Write};
+
+use arq::crypto::Password;
+
+/// Reads a password from the terminal without echoing it, or as the first
+/// line of standard input when that isn't a terminal, so that passwords
+/// never have to appear on the command line. `confirm` asks a second time
+/// at the terminal, to catch typos.
+pub fn read_password(prompt: &str, confirm: bool) -> io::Result<Password> {
+    if !atty::is(atty::Stream::Stdin) {
+        return read_line().map(Password::new);
+    }
+
+    let password = ask(prompt)?;
+    if confirm && ask("Repeat to confirm: ")?.as_str() != password.as_str() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Passwords don't match",
+        ));
+    }
+    Ok(password)
+}
+
+fn ask(prompt: &str) -> io::Result<Password> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let _echo = EchoOff::new()?;
+    read_line().map(Password::new)
+}
+
+fn read_line() -> io::Result<String> {
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "No password given",
+        ));
+    }
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+    Ok(line)
+}
+
+/// Stops the terminal echoing what is typed, until dropped
+#[cfg(unix)]
+struct EchoOff(libc::termios);
+
+#[cfg(unix)]
+impl EchoOff {
+    fn new() -> io::Result<EchoOff> {
+        let mut term = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut term) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let saved = EchoOff(term);
+
+        // Still echo the newline, so that whatever comes next starts on a
+        // line of its own
+        term.c_lflag &= !libc::ECHO;
+        term.c_lflag |= libc::ECHONL;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &term) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(saved)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+/// Elsewhere, the password is echoed
+#[cfg(not(unix))]
+struct EchoOff;
+
+#[cfg(not(unix))]
+impl EchoOff {
+    fn new() -> io::Result<EchoOff> {
+        Ok(EchoOff)
+    }
+}
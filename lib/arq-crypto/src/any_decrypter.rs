@@ -0,0 +1,164 @@
+use std::io::{self, Read};
+
+use crate::{CryptoError, ObjectDecrypter, ObjectDecrypterV1, ObjectDecrypterV2};
+
+const V1_HEADER: &[u8] = b"encrypted";
+const OBJECT_HEADER: &[u8] = b"ARQO";
+
+/// The most of an object's start needed to tell which scheme it uses
+const PEEK_LEN: usize = V1_HEADER.len() + OBJECT_HEADER.len();
+
+/// Which decrypter an object needs, and where its ciphertext starts
+fn detect(start: &[u8]) -> (bool, usize) {
+    match start.strip_prefix(V1_HEADER) {
+        Some(rest) => (rest.starts_with(OBJECT_HEADER), V1_HEADER.len()),
+        None => (start.starts_with(OBJECT_HEADER), 0),
+    }
+}
+
+/**
+ * Decrypts each object with whichever scheme its header calls for, so that
+ * backup sets holding objects from more than one version of Arq, e.g. sets
+ * that were migrated to a key file, can be read throughout.
+ *
+ * `ARQO` EncryptedObjects, with or without an `encrypted` prefix, need the
+ * master keys. Everything else is taken to be a legacy object, with any
+ * `encrypted` prefix removed, and needs the legacy key. An object whose key
+ * isn't available fails with `BadKey`.
+ */
+pub struct ObjectDecrypterAny {
+    v1: Option<ObjectDecrypterV1>,
+    v2: Option<ObjectDecrypterV2>,
+}
+
+impl ObjectDecrypterAny {
+    pub fn new(v1: Option<ObjectDecrypterV1>, v2: Option<ObjectDecrypterV2>) -> Self {
+        ObjectDecrypterAny { v1, v2 }
+    }
+
+    fn pick(&self, is_object: bool) -> Result<&dyn ObjectDecrypter, CryptoError> {
+        let decrypter = if is_object {
+            self.v2.as_ref().map(|d| d as &dyn ObjectDecrypter)
+        } else {
+            self.v1.as_ref().map(|d| d as &dyn ObjectDecrypter)
+        };
+        decrypter.ok_or(CryptoError::BadKey)
+    }
+}
+
+impl ObjectDecrypter for ObjectDecrypterAny {
+    fn decrypt_object(&self, object_bytes: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (is_object, offset) = detect(object_bytes);
+        self.pick(is_object)?
+            .decrypt_object(&object_bytes[offset..])
+    }
+
+    fn decrypt_stream<'a>(
+        &'a self,
+        mut object: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, CryptoError> {
+        let mut start = Vec::with_capacity(PEEK_LEN);
+        (&mut object)
+            .take(PEEK_LEN as u64)
+            .read_to_end(&mut start)
+            .map_err(|_| CryptoError::MalformedData)?;
+
+        let (is_object, offset) = detect(&start);
+        start.drain(..offset);
+        self.pick(is_object)?
+            .decrypt_stream(Box::new(io::Cursor::new(start).chain(object)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CryptoKey, MasterKeys};
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // These objects were put together from doc/format.txt with Python's
+    // `cryptography` package rather than with our own encrypters, so that a
+    // mistake in both halves can't cancel out.
+
+    /// "legacy tree", encrypted as by Arq 4 and earlier with the password
+    /// "correct horse" and the computer salt "saltsalt"
+    const LEGACY_OBJECT: &str = "656e6372797074656403989eee4b16b732abc75d7b78cc9e42";
+
+    /// "arq5 tree" as an EncryptedObject under the master keys 00..1f and
+    /// 20..3f, with fixed IVs and session key
+    const ARQO_OBJECT: &str = "4152514f3d84e33d9ebd0607e0be571a2c3798f80b19bd378378de496242cd0d\
+                               09087c06a0a1a2a3a4a5a6a7a8a9aaabacadaeaf9f3b7504926f8bd36e3118e9\
+                               03a4cd4a45524be76cd073296d6540e444d38e58ae5981b2bf87a1ee585e28d4\
+                               f8765db85729fd18a2b277054e40fff7cafb73d7d5b6b11b0fef4055669d979d\
+                               de8ad5b6";
+
+    fn v1() -> ObjectDecrypterV1 {
+        ObjectDecrypterV1::new(CryptoKey::new("correct horse", b"saltsalt").unwrap())
+    }
+
+    fn v2() -> ObjectDecrypterV2 {
+        let mut encryption = [0; 32];
+        let mut hmac = [0; 32];
+        for i in 0..32 {
+            encryption[i] = i as u8;
+            hmac[i] = 32 + i as u8;
+        }
+        ObjectDecrypterV2::new(MasterKeys::new(encryption, hmac, None))
+    }
+
+    fn decrypt_stream(decrypter: &dyn ObjectDecrypter, object: &[u8]) -> Vec<u8> {
+        let mut plaintext = Vec::new();
+        decrypter
+            .decrypt_stream(Box::new(object))
+            .unwrap()
+            .read_to_end(&mut plaintext)
+            .unwrap();
+        plaintext
+    }
+
+    #[test]
+    fn each_object_is_decrypted_by_its_header() {
+        let decrypter = ObjectDecrypterAny::new(Some(v1()), Some(v2()));
+        let legacy = unhex(LEGACY_OBJECT);
+        let arqo = unhex(ARQO_OBJECT);
+
+        assert_eq!(decrypter.decrypt_object(&legacy).unwrap(), b"legacy tree");
+        assert_eq!(decrypter.decrypt_object(&arqo).unwrap(), b"arq5 tree");
+        assert_eq!(decrypt_stream(&decrypter, &legacy), b"legacy tree");
+        assert_eq!(decrypt_stream(&decrypter, &arqo), b"arq5 tree");
+
+        // An EncryptedObject may come with the legacy prefix too
+        let prefixed = [V1_HEADER, &arqo].concat();
+        assert_eq!(decrypter.decrypt_object(&prefixed).unwrap(), b"arq5 tree");
+        assert_eq!(decrypt_stream(&decrypter, &prefixed), b"arq5 tree");
+
+        // Legacy objects without the prefix are still understood
+        let bare = &legacy[V1_HEADER.len()..];
+        assert_eq!(decrypter.decrypt_object(bare).unwrap(), b"legacy tree");
+    }
+
+    #[test]
+    fn objects_need_their_own_keys() {
+        let legacy_only = ObjectDecrypterAny::new(Some(v1()), None);
+        assert!(matches!(
+            legacy_only.decrypt_object(&unhex(ARQO_OBJECT)),
+            Err(CryptoError::BadKey)
+        ));
+
+        let master_only = ObjectDecrypterAny::new(None, Some(v2()));
+        assert!(matches!(
+            master_only.decrypt_object(&unhex(LEGACY_OBJECT)),
+            Err(CryptoError::BadKey)
+        ));
+        assert!(matches!(
+            master_only.decrypt_stream(Box::new(&b"short"[..])),
+            Err(CryptoError::BadKey)
+        ));
+    }
+}
@@ -0,0 +1,208 @@
+// The primitives the rest of the crate is built on. Exactly one backend is
+// used at a time: OpenSSL if the `openssl` feature is enabled, otherwise the
+// pure-Rust RustCrypto implementation. Both are compiled when both features
+// are enabled, so that their known-answer tests can be run side by side.
+
+#[cfg(feature = "openssl")]
+mod openssl;
+
+// Only the known-answer tests use it when OpenSSL is enabled as well
+#[cfg(feature = "rustcrypto")]
+#[cfg_attr(feature = "openssl", allow(dead_code))]
+mod rustcrypto;
+
+#[cfg(feature = "openssl")]
+pub(crate) use self::openssl::*;
+
+#[cfg(all(feature = "rustcrypto", not(feature = "openssl")))]
+pub(crate) use self::rustcrypto::*;
+
+use crate::CryptoError;
+
+#[cfg(not(any(feature = "openssl", feature = "rustcrypto")))]
+compile_error!("arq-crypto needs either the `openssl` or the `rustcrypto` feature");
+
+pub(crate) const AES_KEY_LEN: usize = 32;
+pub(crate) const AES_BLOCK_LEN: usize = 16;
+
+/// An AES-256 key and IV, as produced by `bytes_to_key_sha1`
+pub(crate) type KeyAndIv = (
+    zeroize::Zeroizing<[u8; AES_KEY_LEN]>,
+    zeroize::Zeroizing<[u8; AES_BLOCK_LEN]>,
+);
+
+/// An unexpected failure inside the crypto library, whichever one it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LibraryError(String);
+
+impl LibraryError {
+    pub(crate) fn wrap<E: std::fmt::Display>(err: E) -> CryptoError {
+        CryptoError::LibraryError(LibraryError(err.to_string()))
+    }
+}
+
+impl std::fmt::Display for LibraryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::CryptoError;
+
+    // Known-answer tests, run against every backend that's compiled in
+    macro_rules! known_answer_tests {
+        ($backend:ident) => {
+            mod $backend {
+                use super::super::$backend::*;
+                use super::*;
+
+                #[test]
+                fn pbkdf2() {
+                    // RFC 6070
+                    let mut out = [0; 20];
+                    pbkdf2_sha1(b"password", b"salt", 2, &mut out).ok().unwrap();
+                    assert_eq!(hex(&out), "ea6c014dc72d6f8ccd1ed92ace1d41f0d8de8957");
+                }
+
+                #[test]
+                fn bytes_to_key() {
+                    let mut derived = [0; 48];
+                    pbkdf2_sha1(b"hunter2", b"saltsalt", 1000, &mut derived)
+                        .ok()
+                        .unwrap();
+                    let (key, iv) = bytes_to_key_sha1(&derived, b"saltsalt", 1000).ok().unwrap();
+                    assert_eq!(
+                        hex(&key[..]),
+                        "7b52920eec3ca182b453b65ba77715152118b00c039e3fbbe15cbfe77be130ff"
+                    );
+                    assert_eq!(hex(&iv[..]), "9715eb0aad73d62c2d124b837de5e2d2");
+                }
+
+                #[test]
+                fn hmac() {
+                    // RFC 4231, test case 2
+                    let mac = hmac_sha256(b"Jefe", &[b"what do ya want ", b"for nothing?"])
+                        .ok()
+                        .unwrap();
+                    assert_eq!(
+                        hex(&mac),
+                        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+                    );
+                }
+
+                #[test]
+                fn aes_cbc() {
+                    // NIST SP 800-38A, F.2.5
+                    let key =
+                        unhex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4");
+                    let iv = unhex("000102030405060708090a0b0c0d0e0f");
+                    let plaintext = unhex("6bc1bee22e409f96e93d7e117393172a");
+
+                    let ciphertext = aes256_cbc_encrypt(&key, &iv, &plaintext).ok().unwrap();
+                    assert_eq!(ciphertext.len(), 32);
+                    assert_eq!(hex(&ciphertext[..16]), "f58c4c04d6e5f1ba779eabfb5f7bfbd6");
+
+                    let decrypted = aes256_cbc_decrypt(&key, &iv, &ciphertext).ok().unwrap();
+                    assert_eq!(decrypted, plaintext);
+
+                    // Truncated ciphertext can't be decrypted
+                    assert!(matches!(
+                        aes256_cbc_decrypt(&key, &iv, &ciphertext[..20]),
+                        Err(CryptoError::BadKey)
+                    ));
+                }
+
+                #[test]
+                fn incremental_hmac() {
+                    // RFC 4231, test case 6: keys longer than a block are
+                    // hashed first
+                    let mut mac = HmacSha256::new(&[0xaa; 131]).ok().unwrap();
+                    mac.update(b"Test Using Larger Than ");
+                    mac.update(b"Block-Size Key - Hash Key First");
+                    assert_eq!(
+                        hex(&mac.finish()),
+                        "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+                    );
+
+                    let mut mac = HmacSha256::new(b"Jefe").ok().unwrap();
+                    mac.update(b"what do ya want for nothing?");
+                    assert_eq!(
+                        Some(mac.finish()),
+                        hmac_sha256(b"Jefe", &[b"what do ya want for nothing?"]).ok()
+                    );
+                }
+
+                #[test]
+                fn incremental_aes_cbc() {
+                    let key = [7; 32];
+                    let iv = [9; 16];
+                    let plaintext: Vec<u8> = (0..1000).map(|n| n as u8).collect();
+                    let ciphertext = aes256_cbc_encrypt(&key, &iv, &plaintext).ok().unwrap();
+
+                    for step in [1, 15, 16, 17, 100, 2000].iter() {
+                        let mut decrypter = CbcDecrypter::new(&key, &iv).ok().unwrap();
+                        let mut output = Vec::new();
+                        for chunk in ciphertext.chunks(*step) {
+                            decrypter.update(chunk, &mut output).ok().unwrap();
+                        }
+                        decrypter.finish(&mut output).ok().unwrap();
+                        assert_eq!(output, plaintext);
+                    }
+
+                    // A wrong key shows up as bad padding
+                    let mut decrypter = CbcDecrypter::new(&[8; 32], &iv).ok().unwrap();
+                    let mut output = Vec::new();
+                    decrypter.update(&ciphertext, &mut output).ok().unwrap();
+                    assert!(matches!(
+                        decrypter.finish(&mut output),
+                        Err(CryptoError::BadKey)
+                    ));
+                }
+
+                #[test]
+                fn sha1() {
+                    // FIPS 180-2, appendix A
+                    let mut hasher = Sha1Hasher::new();
+                    hasher.update(b"a");
+                    hasher.update(b"bc");
+                    assert_eq!(
+                        hex(&hasher.finish()),
+                        "a9993e364706816aba3e25717850c26c9cd0d89d"
+                    );
+                }
+
+                #[test]
+                fn comparison_and_randomness() {
+                    assert!(constant_time_eq(b"abc", b"abc"));
+                    assert!(!constant_time_eq(b"abc", b"abd"));
+                    assert!(!constant_time_eq(b"abc", b"ab"));
+
+                    let mut a = [0; 32];
+                    let mut b = [0; 32];
+                    random_bytes(&mut a).ok().unwrap();
+                    random_bytes(&mut b).ok().unwrap();
+                    assert_ne!(a, b);
+                }
+            }
+        };
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[cfg(feature = "openssl")]
+    known_answer_tests!(openssl);
+
+    #[cfg(feature = "rustcrypto")]
+    known_answer_tests!(rustcrypto);
+}
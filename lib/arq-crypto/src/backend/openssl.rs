@@ -0,0 +1,181 @@
+use openssl::{
+    hash::MessageDigest,
+    memcmp,
+    pkey::PKey,
+    rand::rand_bytes,
+    sha::{sha256, Sha1, Sha256},
+    sign::Signer,
+    symm::{decrypt, encrypt, Cipher, Crypter, Mode},
+};
+use zeroize::Zeroizing;
+
+use super::{KeyAndIv, LibraryError, AES_BLOCK_LEN, AES_KEY_LEN};
+use crate::CryptoError;
+
+const SHA256_BLOCK_LEN: usize = 64;
+
+pub(crate) fn pbkdf2_sha1(
+    password: &[u8],
+    salt: &[u8],
+    rounds: usize,
+    out: &mut [u8],
+) -> Result<(), CryptoError> {
+    openssl::pkcs5::pbkdf2_hmac(password, salt, rounds, MessageDigest::sha1(), out)
+        .map_err(LibraryError::wrap)
+}
+
+/// OpenSSL's `EVP_BytesToKey`, with SHA1 as the digest and sized for
+/// AES-256-CBC
+pub(crate) fn bytes_to_key_sha1(
+    data: &[u8],
+    salt: &[u8],
+    count: usize,
+) -> Result<KeyAndIv, CryptoError> {
+    let k = openssl::pkcs5::bytes_to_key(
+        Cipher::aes_256_cbc(),
+        MessageDigest::sha1(),
+        data,
+        Some(salt),
+        count as i32,
+    )
+    .map_err(LibraryError::wrap)?;
+
+    let k_key = Zeroizing::new(k.key);
+    let k_iv = Zeroizing::new(k.iv.unwrap_or_default());
+
+    let mut key = Zeroizing::new([0; AES_KEY_LEN]);
+    let mut iv = Zeroizing::new([0; AES_BLOCK_LEN]);
+    key.copy_from_slice(&k_key);
+    iv.copy_from_slice(&k_iv);
+    Ok((key, iv))
+}
+
+pub(crate) fn aes256_cbc_encrypt(
+    key: &[u8],
+    iv: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    encrypt(Cipher::aes_256_cbc(), key, Some(iv), data).map_err(LibraryError::wrap)
+}
+
+pub(crate) fn aes256_cbc_decrypt(
+    key: &[u8],
+    iv: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    decrypt(Cipher::aes_256_cbc(), key, Some(iv), data).map_err(|_| CryptoError::BadKey)
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> Result<Vec<u8>, CryptoError> {
+    let key = PKey::hmac(key).map_err(LibraryError::wrap)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(LibraryError::wrap)?;
+    for part in parts {
+        signer.update(part).map_err(LibraryError::wrap)?;
+    }
+    signer.sign_to_vec().map_err(LibraryError::wrap)
+}
+
+/// Incremental AES-256-CBC decryption with PKCS7 padding
+pub(crate) struct CbcDecrypter {
+    crypter: Crypter,
+}
+
+impl CbcDecrypter {
+    pub(crate) fn new(key: &[u8], iv: &[u8]) -> Result<CbcDecrypter, CryptoError> {
+        Crypter::new(Cipher::aes_256_cbc(), Mode::Decrypt, key, Some(iv))
+            .map(|crypter| CbcDecrypter { crypter })
+            .map_err(LibraryError::wrap)
+    }
+
+    /// Decrypts as much of `input` as possible, appending the plaintext to
+    /// `output`. The last block is held back until `finish`, as it holds the
+    /// padding.
+    pub(crate) fn update(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        let start = output.len();
+        output.resize(start + input.len() + AES_BLOCK_LEN, 0);
+        let n = self
+            .crypter
+            .update(input, &mut output[start..])
+            .map_err(LibraryError::wrap)?;
+        output.truncate(start + n);
+        Ok(())
+    }
+
+    pub(crate) fn finish(&mut self, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        let start = output.len();
+        output.resize(start + AES_BLOCK_LEN, 0);
+        let n = self
+            .crypter
+            .finalize(&mut output[start..])
+            .map_err(|_| CryptoError::BadKey)?;
+        output.truncate(start + n);
+        Ok(())
+    }
+}
+
+/// Incremental HMAC-SHA256. OpenSSL's `Signer` borrows its key, so this is
+/// built directly on SHA256 instead.
+pub(crate) struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    pub(crate) fn new(key: &[u8]) -> Result<HmacSha256, CryptoError> {
+        let mut block = Zeroizing::new([0; SHA256_BLOCK_LEN]);
+        if key.len() > SHA256_BLOCK_LEN {
+            block[..32].copy_from_slice(&sha256(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut pad = Zeroizing::new([0; SHA256_BLOCK_LEN]);
+        let mut inner = Sha256::new();
+        let mut outer = Sha256::new();
+        for (p, b) in pad.iter_mut().zip(block.iter()) {
+            *p = b ^ 0x36;
+        }
+        inner.update(&pad[..]);
+        for (p, b) in pad.iter_mut().zip(block.iter()) {
+            *p = b ^ 0x5c;
+        }
+        outer.update(&pad[..]);
+
+        Ok(HmacSha256 { inner, outer })
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        let mut outer = self.outer;
+        outer.update(&self.inner.finish());
+        outer.finish().to_vec()
+    }
+}
+
+/// Incremental SHA1
+pub(crate) struct Sha1Hasher(Sha1);
+
+impl Sha1Hasher {
+    pub(crate) fn new() -> Sha1Hasher {
+        Sha1Hasher(Sha1::new())
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub(crate) fn finish(self) -> [u8; 20] {
+        self.0.finish()
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && memcmp::eq(a, b)
+}
+
+pub(crate) fn random_bytes(buf: &mut [u8]) -> Result<(), CryptoError> {
+    rand_bytes(buf).map_err(LibraryError::wrap)
+}
@@ -0,0 +1,186 @@
+use aes::Aes256;
+use cbc::cipher::{
+    block_padding::Pkcs7, generic_array::GenericArray, BlockDecryptMut, BlockEncryptMut, KeyIvInit,
+};
+use hmac::{Hmac, Mac};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+use super::{KeyAndIv, LibraryError, AES_BLOCK_LEN, AES_KEY_LEN};
+use crate::CryptoError;
+
+pub(crate) fn pbkdf2_sha1(
+    password: &[u8],
+    salt: &[u8],
+    rounds: usize,
+    out: &mut [u8],
+) -> Result<(), CryptoError> {
+    pbkdf2::pbkdf2::<Hmac<Sha1>>(password, salt, rounds as u32, out).map_err(LibraryError::wrap)
+}
+
+/// OpenSSL's `EVP_BytesToKey`, with SHA1 as the digest and sized for
+/// AES-256-CBC
+pub(crate) fn bytes_to_key_sha1(
+    data: &[u8],
+    salt: &[u8],
+    count: usize,
+) -> Result<KeyAndIv, CryptoError> {
+    let mut material = Zeroizing::new(Vec::with_capacity(AES_KEY_LEN + AES_BLOCK_LEN + 20));
+    let mut digest = Zeroizing::new(Vec::new());
+
+    while material.len() < AES_KEY_LEN + AES_BLOCK_LEN {
+        let mut hasher = Sha1::new();
+        hasher.update(&digest[..]);
+        hasher.update(data);
+        hasher.update(salt);
+        *digest = hasher.finalize().to_vec();
+
+        for _ in 1..count {
+            *digest = Sha1::digest(&digest[..]).to_vec();
+        }
+
+        material.extend_from_slice(&digest);
+    }
+
+    let mut key = Zeroizing::new([0; AES_KEY_LEN]);
+    let mut iv = Zeroizing::new([0; AES_BLOCK_LEN]);
+    key.copy_from_slice(&material[..AES_KEY_LEN]);
+    iv.copy_from_slice(&material[AES_KEY_LEN..AES_KEY_LEN + AES_BLOCK_LEN]);
+    Ok((key, iv))
+}
+
+pub(crate) fn aes256_cbc_encrypt(
+    key: &[u8],
+    iv: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let encryptor =
+        cbc::Encryptor::<Aes256>::new_from_slices(key, iv).map_err(LibraryError::wrap)?;
+    Ok(encryptor.encrypt_padded_vec_mut::<Pkcs7>(data))
+}
+
+pub(crate) fn aes256_cbc_decrypt(
+    key: &[u8],
+    iv: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let decryptor =
+        cbc::Decryptor::<Aes256>::new_from_slices(key, iv).map_err(LibraryError::wrap)?;
+    decryptor
+        .decrypt_padded_vec_mut::<Pkcs7>(data)
+        .map_err(|_| CryptoError::BadKey)
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> Result<Vec<u8>, CryptoError> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).map_err(LibraryError::wrap)?;
+    for part in parts {
+        mac.update(part);
+    }
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Incremental AES-256-CBC decryption with PKCS7 padding
+pub(crate) struct CbcDecrypter {
+    cipher: cbc::Decryptor<Aes256>,
+    pending: Vec<u8>,
+}
+
+impl CbcDecrypter {
+    pub(crate) fn new(key: &[u8], iv: &[u8]) -> Result<CbcDecrypter, CryptoError> {
+        let cipher =
+            cbc::Decryptor::<Aes256>::new_from_slices(key, iv).map_err(LibraryError::wrap)?;
+        Ok(CbcDecrypter {
+            cipher,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Decrypts as much of `input` as possible, appending the plaintext to
+    /// `output`. The last block is held back until `finish`, as it holds the
+    /// padding.
+    pub(crate) fn update(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        self.pending.extend_from_slice(input);
+        let ready = match self.pending.len() % AES_BLOCK_LEN {
+            0 => self.pending.len().saturating_sub(AES_BLOCK_LEN),
+            partial => self.pending.len() - partial,
+        };
+
+        for block in self.pending[..ready].chunks_exact_mut(AES_BLOCK_LEN) {
+            self.cipher
+                .decrypt_block_mut(GenericArray::from_mut_slice(block));
+        }
+        output.extend_from_slice(&self.pending[..ready]);
+        self.pending.drain(..ready);
+        Ok(())
+    }
+
+    pub(crate) fn finish(&mut self, output: &mut Vec<u8>) -> Result<(), CryptoError> {
+        if self.pending.len() != AES_BLOCK_LEN {
+            return Err(CryptoError::BadKey);
+        }
+
+        let block = &mut self.pending[..];
+        self.cipher
+            .decrypt_block_mut(GenericArray::from_mut_slice(block));
+
+        let padding = block[AES_BLOCK_LEN - 1] as usize;
+        if padding == 0
+            || padding > AES_BLOCK_LEN
+            || block[AES_BLOCK_LEN - padding..]
+                .iter()
+                .any(|b| *b as usize != padding)
+        {
+            return Err(CryptoError::BadKey);
+        }
+
+        output.extend_from_slice(&block[..AES_BLOCK_LEN - padding]);
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+/// Incremental HMAC-SHA256
+pub(crate) struct HmacSha256(Hmac<Sha256>);
+
+impl HmacSha256 {
+    pub(crate) fn new(key: &[u8]) -> Result<HmacSha256, CryptoError> {
+        <Hmac<Sha256> as Mac>::new_from_slice(key)
+            .map(HmacSha256)
+            .map_err(LibraryError::wrap)
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.0.finalize().into_bytes().to_vec()
+    }
+}
+
+/// Incremental SHA1
+pub(crate) struct Sha1Hasher(Sha1);
+
+impl Sha1Hasher {
+    pub(crate) fn new() -> Sha1Hasher {
+        Sha1Hasher(Sha1::new())
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        Digest::update(&mut self.0, data);
+    }
+
+    pub(crate) fn finish(self) -> [u8; 20] {
+        self.0.finalize().into()
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && bool::from(a.ct_eq(b))
+}
+
+pub(crate) fn random_bytes(buf: &mut [u8]) -> Result<(), CryptoError> {
+    getrandom::getrandom(buf).map_err(LibraryError::wrap)
+}
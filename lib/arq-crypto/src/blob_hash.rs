@@ -0,0 +1,40 @@
+use crate::backend;
+
+/**
+ * Calculates the name Arq gives a blob: the SHA1 of a salt followed by the
+ * blob's data. The salt is the third master key or, in backup sets with only
+ * two master keys, the computer's UUID.
+ */
+pub struct BlobHasher(backend::Sha1Hasher);
+
+impl BlobHasher {
+    pub fn new(salt: &[u8]) -> BlobHasher {
+        let mut hasher = backend::Sha1Hasher::new();
+        hasher.update(salt);
+        BlobHasher(hasher)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> [u8; 20] {
+        self.0.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn salt_is_prepended() {
+        let mut salted = BlobHasher::new(b"ab");
+        salted.update(b"c");
+
+        let mut unsalted = BlobHasher::new(b"");
+        unsalted.update(b"abc");
+
+        assert_eq!(salted.finish(), unsalted.finish());
+    }
+}
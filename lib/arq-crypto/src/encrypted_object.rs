@@ -0,0 +1,457 @@
+use std::{io::Read, sync::Mutex};
+
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+    backend, stream::DecryptingReader, CryptoError, ObjectDecrypter, ObjectEncrypter, SecretBytes,
+};
+
+// An EncryptedObject is laid out as
+//
+//   header                            "ARQO"
+//   HMAC-SHA256                       32 bytes
+//   master IV                         16 bytes
+//   encrypted data IV + session key   64 bytes (48 bytes plus padding)
+//   ciphertext                        ...
+//
+// See doc/format.txt for the details.
+
+const HEADER: &[u8] = b"ARQO";
+const HMAC_LEN: usize = 32;
+const IV_LEN: usize = 16;
+const SESSION_KEY_LEN: usize = 32;
+const ENCRYPTED_SESSION_LEN: usize = 64;
+
+const HMAC_OFFSET: usize = HEADER.len();
+const MASTER_IV_OFFSET: usize = HMAC_OFFSET + HMAC_LEN;
+const SESSION_OFFSET: usize = MASTER_IV_OFFSET + IV_LEN;
+const CIPHERTEXT_OFFSET: usize = SESSION_OFFSET + ENCRYPTED_SESSION_LEN;
+
+/// Arq replaces its session key after this many objects
+const SESSION_KEY_USES: usize = 256;
+
+pub const MASTER_KEY_LEN: usize = 32;
+
+/**
+ * The randomly-generated keys stored in a backup set's encryption dat file.
+ * The first is used for encryption, the second for HMACs and the (optional)
+ * third for salting object SHA1s.
+ */
+#[derive(Debug)]
+pub struct MasterKeys {
+    keys: SecretBytes,
+}
+
+impl MasterKeys {
+    pub fn new(
+        encryption: [u8; MASTER_KEY_LEN],
+        hmac: [u8; MASTER_KEY_LEN],
+        salt: Option<[u8; MASTER_KEY_LEN]>,
+    ) -> MasterKeys {
+        let count = if salt.is_some() { 3 } else { 2 };
+        let mut keys = SecretBytes::zeroed(count * MASTER_KEY_LEN);
+        let buf = keys.as_mut_bytes();
+        buf[..MASTER_KEY_LEN].copy_from_slice(&encryption);
+        buf[MASTER_KEY_LEN..2 * MASTER_KEY_LEN].copy_from_slice(&hmac);
+        if let Some(salt) = salt {
+            buf[2 * MASTER_KEY_LEN..].copy_from_slice(&salt);
+        }
+        MasterKeys { keys }
+    }
+
+    /// Takes the keys as they are laid out in an encryption dat file, i.e.
+    /// two or three keys back to back.
+    pub fn from_secret(keys: SecretBytes) -> Result<MasterKeys, CryptoError> {
+        match keys.len() {
+            n if n == 2 * MASTER_KEY_LEN || n == 3 * MASTER_KEY_LEN => Ok(MasterKeys { keys }),
+            _ => Err(CryptoError::MalformedData),
+        }
+    }
+
+    /// Generates a fresh set of three master keys
+    pub fn generate() -> Result<MasterKeys, CryptoError> {
+        let mut keys = SecretBytes::zeroed(3 * MASTER_KEY_LEN);
+        backend::random_bytes(keys.as_mut_bytes())?;
+        Ok(MasterKeys { keys })
+    }
+
+    pub fn encryption_key(&self) -> &[u8] {
+        &self.keys.as_bytes()[..MASTER_KEY_LEN]
+    }
+
+    pub fn hmac_key(&self) -> &[u8] {
+        &self.keys.as_bytes()[MASTER_KEY_LEN..2 * MASTER_KEY_LEN]
+    }
+
+    /// The key used to salt object SHA1s. Only present in backup sets with
+    /// an `encryptionv3.dat` file.
+    pub fn salt_key(&self) -> Option<&[u8]> {
+        let keys = self.keys.as_bytes();
+        if keys.len() > 2 * MASTER_KEY_LEN {
+            Some(&keys[2 * MASTER_KEY_LEN..])
+        } else {
+            None
+        }
+    }
+
+    /// The keys as laid out in an encryption dat file
+    pub fn as_bytes(&self) -> &[u8] {
+        self.keys.as_bytes()
+    }
+
+    fn hmac(&self, parts: &[&[u8]]) -> Result<Vec<u8>, CryptoError> {
+        hmac_sha256(self.hmac_key(), parts)
+    }
+}
+
+/// Calculates the HMAC-SHA256 of the concatenation of `parts`
+pub fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> Result<Vec<u8>, CryptoError> {
+    backend::hmac_sha256(key, parts)
+}
+
+fn random_iv() -> Result<[u8; IV_LEN], CryptoError> {
+    let mut iv = [0; IV_LEN];
+    backend::random_bytes(&mut iv)?;
+    Ok(iv)
+}
+
+/// Decrypts objects in the `ARQO` EncryptedObject format
+#[derive(Debug)]
+pub struct ObjectDecrypterV2 {
+    keys: MasterKeys,
+}
+
+impl ObjectDecrypterV2 {
+    pub fn new(keys: MasterKeys) -> Self {
+        ObjectDecrypterV2 { keys }
+    }
+}
+
+impl ObjectDecrypterV2 {
+    /// Recovers the data IV and session key
+    fn decrypt_session(
+        &self,
+        master_iv: &[u8],
+        encrypted_session: &[u8],
+    ) -> Result<SecretBytes, CryptoError> {
+        let session =
+            backend::aes256_cbc_decrypt(self.keys.encryption_key(), master_iv, encrypted_session)
+                .map(SecretBytes::new)?;
+        if session.len() != IV_LEN + SESSION_KEY_LEN {
+            return Err(CryptoError::MalformedData);
+        }
+        Ok(session)
+    }
+}
+
+impl ObjectDecrypter for ObjectDecrypterV2 {
+    fn decrypt_object(&self, object_bytes: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if object_bytes.len() < CIPHERTEXT_OFFSET || &object_bytes[..HMAC_OFFSET] != HEADER {
+            return Err(CryptoError::MalformedData);
+        }
+
+        let expected_hmac = &object_bytes[HMAC_OFFSET..MASTER_IV_OFFSET];
+        let master_iv = &object_bytes[MASTER_IV_OFFSET..SESSION_OFFSET];
+        let encrypted_session = &object_bytes[SESSION_OFFSET..CIPHERTEXT_OFFSET];
+        let ciphertext = &object_bytes[CIPHERTEXT_OFFSET..];
+
+        let hmac = self
+            .keys
+            .hmac(&[master_iv, encrypted_session, ciphertext])?;
+        if !backend::constant_time_eq(&hmac, expected_hmac) {
+            return Err(CryptoError::HmacMismatch);
+        }
+
+        let session = self.decrypt_session(master_iv, encrypted_session)?;
+        let (data_iv, session_key) = session.as_bytes().split_at(IV_LEN);
+        backend::aes256_cbc_decrypt(session_key, data_iv, ciphertext)
+    }
+
+    fn decrypt_stream<'a>(
+        &'a self,
+        mut object: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, CryptoError> {
+        let mut header = [0; CIPHERTEXT_OFFSET];
+        object
+            .read_exact(&mut header)
+            .map_err(|_| CryptoError::MalformedData)?;
+        if &header[..HMAC_OFFSET] != HEADER {
+            return Err(CryptoError::MalformedData);
+        }
+
+        let mut expected_hmac = [0; HMAC_LEN];
+        expected_hmac.copy_from_slice(&header[HMAC_OFFSET..MASTER_IV_OFFSET]);
+        let master_iv = &header[MASTER_IV_OFFSET..SESSION_OFFSET];
+        let encrypted_session = &header[SESSION_OFFSET..CIPHERTEXT_OFFSET];
+
+        // The ciphertext part of the HMAC is added as it is read
+        let mut hmac = backend::HmacSha256::new(self.keys.hmac_key())?;
+        hmac.update(master_iv);
+        hmac.update(encrypted_session);
+
+        let session = self.decrypt_session(master_iv, encrypted_session)?;
+        let (data_iv, session_key) = session.as_bytes().split_at(IV_LEN);
+        let cipher = backend::CbcDecrypter::new(session_key, data_iv)?;
+
+        Ok(Box::new(DecryptingReader::new(
+            object,
+            cipher,
+            Some((hmac, expected_hmac)),
+        )))
+    }
+}
+
+struct SessionKey {
+    key: [u8; SESSION_KEY_LEN],
+    uses: usize,
+}
+
+impl Drop for SessionKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/**
+ * Produces objects in the `ARQO` EncryptedObject format. As Arq does, the
+ * randomly-generated session key is reused for a number of objects before
+ * being replaced, while the IVs are fresh for every object.
+ */
+pub struct ObjectEncrypterV2 {
+    keys: MasterKeys,
+    session: Mutex<Option<SessionKey>>,
+}
+
+impl ObjectEncrypterV2 {
+    pub fn new(keys: MasterKeys) -> Self {
+        ObjectEncrypterV2 {
+            keys,
+            session: Mutex::new(None),
+        }
+    }
+
+    fn session_key(&self) -> Result<Zeroizing<[u8; SESSION_KEY_LEN]>, CryptoError> {
+        let mut session = self.session.lock().unwrap();
+        match session.as_mut() {
+            Some(s) if s.uses < SESSION_KEY_USES => {
+                s.uses += 1;
+                Ok(Zeroizing::new(s.key))
+            }
+            _ => {
+                let mut fresh = SessionKey {
+                    key: [0; SESSION_KEY_LEN],
+                    uses: 1,
+                };
+                backend::random_bytes(&mut fresh.key)?;
+                let key = Zeroizing::new(fresh.key);
+                *session = Some(fresh);
+                Ok(key)
+            }
+        }
+    }
+
+    fn encrypt_with(
+        &self,
+        plaintext: &[u8],
+        master_iv: &[u8; IV_LEN],
+        data_iv: &[u8; IV_LEN],
+        session_key: &[u8; SESSION_KEY_LEN],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let ciphertext = backend::aes256_cbc_encrypt(session_key, data_iv, plaintext)?;
+
+        let mut session = SecretBytes::zeroed(IV_LEN + SESSION_KEY_LEN);
+        session.as_mut_bytes()[..IV_LEN].copy_from_slice(data_iv);
+        session.as_mut_bytes()[IV_LEN..].copy_from_slice(session_key);
+        let encrypted_session =
+            backend::aes256_cbc_encrypt(self.keys.encryption_key(), master_iv, session.as_bytes())?;
+
+        let hmac = self
+            .keys
+            .hmac(&[master_iv, &encrypted_session, &ciphertext])?;
+
+        let mut result = Vec::with_capacity(CIPHERTEXT_OFFSET + ciphertext.len());
+        result.extend_from_slice(HEADER);
+        result.extend_from_slice(&hmac);
+        result.extend_from_slice(master_iv);
+        result.extend_from_slice(&encrypted_session);
+        result.extend_from_slice(&ciphertext);
+        Ok(result)
+    }
+}
+
+impl ObjectEncrypter for ObjectEncrypterV2 {
+    fn encrypt_object(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let session_key = self.session_key()?;
+        self.encrypt_with(plaintext, &random_iv()?, &random_iv()?, &session_key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_keys() -> MasterKeys {
+        MasterKeys::new(
+            [1; MASTER_KEY_LEN],
+            [2; MASTER_KEY_LEN],
+            Some([3; MASTER_KEY_LEN]),
+        )
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        let encrypter = ObjectEncrypterV2::new(test_keys());
+        let decrypter = ObjectDecrypterV2::new(test_keys());
+
+        for plaintext in [&b""[..], b"x", &[0xAB; 4097][..]].iter() {
+            let object = encrypter.encrypt_object(plaintext).ok().unwrap();
+            assert_eq!(&object[..4], b"ARQO");
+            assert_eq!(
+                decrypter.decrypt_object(&object).ok().as_deref(),
+                Some(*plaintext)
+            );
+        }
+    }
+
+    #[test]
+    fn v2_layout() {
+        let encrypter = ObjectEncrypterV2::new(test_keys());
+        let master_iv = [4; IV_LEN];
+        let data_iv = [5; IV_LEN];
+        let session_key = [6; SESSION_KEY_LEN];
+        let plaintext = b"sixteen bytes!!!";
+
+        let object = encrypter
+            .encrypt_with(plaintext, &master_iv, &data_iv, &session_key)
+            .ok()
+            .unwrap();
+
+        // A full block of plaintext gets a full block of padding
+        assert_eq!(object.len(), CIPHERTEXT_OFFSET + 32);
+        assert_eq!(&object[MASTER_IV_OFFSET..SESSION_OFFSET], &master_iv[..]);
+
+        let session = backend::aes256_cbc_decrypt(
+            &[1; MASTER_KEY_LEN],
+            &master_iv,
+            &object[SESSION_OFFSET..CIPHERTEXT_OFFSET],
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(&session[..IV_LEN], &data_iv[..]);
+        assert_eq!(&session[IV_LEN..], &session_key[..]);
+
+        let ciphertext = &object[CIPHERTEXT_OFFSET..];
+        assert_eq!(
+            backend::aes256_cbc_decrypt(&session_key, &data_iv, ciphertext)
+                .ok()
+                .unwrap(),
+            plaintext
+        );
+
+        let hmac = hmac_sha256(&[2; MASTER_KEY_LEN], &[&object[MASTER_IV_OFFSET..]]).ok();
+        assert_eq!(
+            hmac.as_deref(),
+            Some(&object[HMAC_OFFSET..MASTER_IV_OFFSET])
+        );
+    }
+
+    #[test]
+    fn session_keys_are_reused_but_ivs_are_not() {
+        let encrypter = ObjectEncrypterV2::new(test_keys());
+        let a = encrypter.encrypt_object(b"same").ok().unwrap();
+        let b = encrypter.encrypt_object(b"same").ok().unwrap();
+        assert_ne!(a, b);
+
+        let first = *encrypter.session_key().ok().unwrap();
+        for _ in 3..SESSION_KEY_USES {
+            assert_eq!(encrypter.session_key().ok().map(|k| *k), Some(first));
+        }
+        assert_ne!(encrypter.session_key().ok().map(|k| *k), Some(first));
+    }
+
+    /// Hands out its content a few bytes at a time, like a network stream
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(7);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn decrypt_stream(
+        decrypter: &dyn ObjectDecrypter,
+        object: &[u8],
+    ) -> Result<Vec<u8>, Option<std::io::Error>> {
+        let mut reader = decrypter
+            .decrypt_stream(Box::new(Trickle(object)))
+            .map_err(|_| None)?;
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext).map_err(Some)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn v2_streaming_matches_whole_object_decryption() {
+        let encrypter = ObjectEncrypterV2::new(test_keys());
+        let decrypter = ObjectDecrypterV2::new(test_keys());
+
+        let big: Vec<u8> = (0..200_000).map(|n| (n % 251) as u8).collect();
+        for plaintext in [&b""[..], b"sixteen bytes!!!", &big[..]].iter() {
+            let object = encrypter.encrypt_object(plaintext).ok().unwrap();
+            assert_eq!(
+                decrypt_stream(&decrypter, &object).ok().as_deref(),
+                Some(*plaintext)
+            );
+        }
+    }
+
+    #[test]
+    fn v2_streaming_detects_tampering_at_the_end() {
+        let encrypter = ObjectEncrypterV2::new(test_keys());
+        let decrypter = ObjectDecrypterV2::new(test_keys());
+        let mut object = encrypter.encrypt_object(&[0x5A; 100_000]).ok().unwrap();
+        object[CIPHERTEXT_OFFSET + 10] ^= 1;
+
+        let err = decrypt_stream(&decrypter, &object).err().unwrap().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(matches!(
+            err.get_ref().and_then(|e| e.downcast_ref::<CryptoError>()),
+            Some(CryptoError::HmacMismatch)
+        ));
+
+        assert!(matches!(decrypt_stream(&decrypter, b"ARQO"), Err(None)));
+    }
+
+    #[test]
+    fn tampered_objects_are_rejected() {
+        let encrypter = ObjectEncrypterV2::new(test_keys());
+        let decrypter = ObjectDecrypterV2::new(test_keys());
+        let mut object = encrypter.encrypt_object(b"payload").ok().unwrap();
+
+        let n = object.len() - 1;
+        object[n] ^= 1;
+        assert!(matches!(
+            decrypter.decrypt_object(&object),
+            Err(CryptoError::HmacMismatch)
+        ));
+
+        assert!(matches!(
+            decrypter.decrypt_object(b"ARQO"),
+            Err(CryptoError::MalformedData)
+        ));
+
+        let other = ObjectDecrypterV2::new(MasterKeys::new(
+            [1; MASTER_KEY_LEN],
+            [9; MASTER_KEY_LEN],
+            None,
+        ));
+        object[n] ^= 1;
+        assert!(matches!(
+            other.decrypt_object(&object),
+            Err(CryptoError::HmacMismatch)
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+use zeroize::Zeroizing;
+
+use crate::{
+    backend,
+    encrypted_object::{hmac_sha256, MASTER_KEY_LEN},
+    CryptoError, MasterKeys, SecretBytes,
+};
+
+// The encryption dat file is laid out as
+//
+//   header                  "ENCRYPTIONV2"
+//   salt                    8 bytes
+//   HMAC-SHA256             32 bytes
+//   IV                      16 bytes
+//   encrypted master keys   ...
+//
+// where the master keys are encrypted with a key derived from the user's
+// password. See doc/format.txt for the details.
+
+const HEADER: &[u8] = b"ENCRYPTIONV2";
+const SALT_LEN: usize = 8;
+const HMAC_LEN: usize = 32;
+const IV_LEN: usize = 16;
+const KEY_ROUNDS: usize = 200_000;
+const DERIVED_KEY_LEN: usize = 64;
+
+/**
+ * The parsed (but still encrypted) content of an `encryptionv2.dat` or
+ * `encryptionv3.dat` file.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionDat {
+    salt: [u8; SALT_LEN],
+    hmac: [u8; HMAC_LEN],
+    iv: [u8; IV_LEN],
+    encrypted_keys: Vec<u8>,
+}
+
+/// Derives the encryption and HMAC keys protecting the master keys from the
+/// user's password.
+fn derive_keys(
+    password: &str,
+    salt: &[u8],
+) -> Result<Zeroizing<[u8; DERIVED_KEY_LEN]>, CryptoError> {
+    let mut key = Zeroizing::new([0; DERIVED_KEY_LEN]);
+    backend::pbkdf2_sha1(password.as_bytes(), salt, KEY_ROUNDS, &mut key[..])?;
+    Ok(key)
+}
+
+impl EncryptionDat {
+    pub fn parse(data: &[u8]) -> Result<EncryptionDat, CryptoError> {
+        let min_len = HEADER.len() + SALT_LEN + HMAC_LEN + IV_LEN;
+        if data.len() <= min_len || &data[..HEADER.len()] != HEADER {
+            return Err(CryptoError::MalformedData);
+        }
+
+        let mut dat = EncryptionDat {
+            salt: [0; SALT_LEN],
+            hmac: [0; HMAC_LEN],
+            iv: [0; IV_LEN],
+            encrypted_keys: Vec::new(),
+        };
+
+        let (salt, rest) = data[HEADER.len()..].split_at(SALT_LEN);
+        let (hmac, rest) = rest.split_at(HMAC_LEN);
+        let (iv, encrypted_keys) = rest.split_at(IV_LEN);
+        dat.salt.copy_from_slice(salt);
+        dat.hmac.copy_from_slice(hmac);
+        dat.iv.copy_from_slice(iv);
+        dat.encrypted_keys = encrypted_keys.to_vec();
+
+        Ok(dat)
+    }
+
+    /// Encrypts a set of master keys with `password`, using a fresh salt and
+    /// IV.
+    pub fn seal(keys: &MasterKeys, password: &str) -> Result<EncryptionDat, CryptoError> {
+        let mut salt = [0; SALT_LEN];
+        let mut iv = [0; IV_LEN];
+        backend::random_bytes(&mut salt)?;
+        backend::random_bytes(&mut iv)?;
+
+        let derived = derive_keys(password, &salt)?;
+        let encrypted_keys =
+            backend::aes256_cbc_encrypt(&derived[..MASTER_KEY_LEN], &iv, keys.as_bytes())?;
+
+        let mut hmac = [0; HMAC_LEN];
+        hmac.copy_from_slice(&hmac_sha256(
+            &derived[MASTER_KEY_LEN..],
+            &[&iv, &encrypted_keys],
+        )?);
+
+        Ok(EncryptionDat {
+            salt,
+            hmac,
+            iv,
+            encrypted_keys,
+        })
+    }
+
+    /// Recovers the master keys. A wrong password shows up as
+    /// `CryptoError::HmacMismatch`.
+    pub fn open(&self, password: &str) -> Result<MasterKeys, CryptoError> {
+        let derived = derive_keys(password, &self.salt)?;
+
+        let hmac = hmac_sha256(
+            &derived[MASTER_KEY_LEN..],
+            &[&self.iv, &self.encrypted_keys],
+        )?;
+        if !backend::constant_time_eq(&hmac, &self.hmac) {
+            return Err(CryptoError::HmacMismatch);
+        }
+
+        // encryptionv2.dat files hold 2 keys, encryptionv3.dat files hold 3
+        backend::aes256_cbc_decrypt(&derived[..MASTER_KEY_LEN], &self.iv, &self.encrypted_keys)
+            .map(SecretBytes::new)
+            .and_then(MasterKeys::from_secret)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.extend_from_slice(HEADER);
+        result.extend_from_slice(&self.salt);
+        result.extend_from_slice(&self.hmac);
+        result.extend_from_slice(&self.iv);
+        result.extend_from_slice(&self.encrypted_keys);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_keys(salt: Option<[u8; MASTER_KEY_LEN]>) -> MasterKeys {
+        MasterKeys::new([1; MASTER_KEY_LEN], [2; MASTER_KEY_LEN], salt)
+    }
+
+    #[test]
+    fn round_trip() {
+        for keys in [test_keys(Some([3; MASTER_KEY_LEN])), test_keys(None)].iter() {
+            let bytes = EncryptionDat::seal(keys, "correct horse")
+                .ok()
+                .unwrap()
+                .to_bytes();
+            assert_eq!(&bytes[..12], b"ENCRYPTIONV2");
+
+            let dat = EncryptionDat::parse(&bytes).ok().unwrap();
+            assert_eq!(dat.to_bytes(), bytes);
+
+            let opened = dat.open("correct horse").ok().unwrap();
+            assert_eq!(opened.encryption_key(), keys.encryption_key());
+            assert_eq!(opened.hmac_key(), keys.hmac_key());
+            assert_eq!(opened.salt_key(), keys.salt_key());
+        }
+    }
+
+    #[test]
+    fn wrong_password_is_detected() {
+        let dat = EncryptionDat::seal(&test_keys(None), "correct horse")
+            .ok()
+            .unwrap();
+        assert!(matches!(
+            dat.open("battery staple"),
+            Err(CryptoError::HmacMismatch)
+        ));
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        assert!(matches!(
+            EncryptionDat::parse(b"ENCRYPTIONV2"),
+            Err(CryptoError::MalformedData)
+        ));
+        assert!(matches!(
+            EncryptionDat::parse(&[0; 128]),
+            Err(CryptoError::MalformedData)
+        ));
+    }
+}
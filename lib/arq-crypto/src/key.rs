@@ -5,62 +5,65 @@
 //     pbkdf2::{self, PBKDF2_HMAC_SHA1},
 // };
 
-use crate::CryptoError;
-use std::vec::Vec;
+use crate::{backend, CryptoError, SecretBytes};
+use std::{fmt, vec::Vec};
+use zeroize::Zeroizing;
 
 const KEY_LEN: usize = 48;
 const KEY_ITER: usize = 1000;
 
-use openssl::{
-    hash::MessageDigest,
-    pkcs5,
-    symm::{decrypt, encrypt, Cipher},
-};
-
-#[derive(Clone)]
 pub struct CryptoKey {
-    cipher: Cipher,
-    key: Vec<u8>,
-    iv: Option<Vec<u8>>,
+    key: SecretBytes,
+    iv: Option<SecretBytes>,
+}
+
+impl fmt::Debug for CryptoKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CryptoKey").finish_non_exhaustive()
+    }
 }
 
 impl CryptoKey {
     pub fn new(secret: &str, salt: &[u8]) -> Result<CryptoKey, CryptoError> {
-        let mut key_bytes: [u8; KEY_LEN] = [0; KEY_LEN];
+        // EVP_BytesToKey only takes an 8 byte salt
+        if salt.len() != 8 {
+            return Err(CryptoError::Unexpected);
+        }
 
-        pkcs5::pbkdf2_hmac(
-            secret.as_bytes(),
-            salt,
-            KEY_ITER,
-            MessageDigest::sha1(),
-            &mut key_bytes[..],
-        )
-        .map_err(CryptoError::LibraryError)?;
+        let mut key_bytes = Zeroizing::new([0u8; KEY_LEN]);
+        backend::pbkdf2_sha1(secret.as_bytes(), salt, KEY_ITER, &mut key_bytes[..])?;
 
-        let cipher = Cipher::aes_256_cbc();
-
-        pkcs5::bytes_to_key(
-            cipher,
-            MessageDigest::sha1(),
-            &key_bytes[..],
-            Some(salt),
-            KEY_ITER as i32,
-        )
-        .map_err(|_| CryptoError::Unexpected)
-        .map(|k| CryptoKey {
-            key: k.key,
-            iv: k.iv,
-            cipher,
+        let (key, iv) = backend::bytes_to_key_sha1(&key_bytes[..], salt, KEY_ITER)?;
+        Ok(CryptoKey {
+            key: SecretBytes::from_slice(&key[..]),
+            iv: Some(SecretBytes::from_slice(&iv[..])),
         })
     }
 
+    /// Rebuilds a key from the parts returned by `key_bytes` and `iv_bytes`
+    pub fn from_parts(key: SecretBytes, iv: Option<SecretBytes>) -> CryptoKey {
+        CryptoKey { key, iv }
+    }
+
+    pub fn key_bytes(&self) -> &[u8] {
+        self.key.as_bytes()
+    }
+
+    pub fn iv_bytes(&self) -> Option<&[u8]> {
+        self.iv.as_ref().map(SecretBytes::as_bytes)
+    }
+
+    // A missing IV means an all-zero one, as it does for OpenSSL
+    pub(crate) fn iv(&self) -> &[u8] {
+        const ZERO_IV: [u8; backend::AES_BLOCK_LEN] = [0; backend::AES_BLOCK_LEN];
+        self.iv_bytes().unwrap_or(&ZERO_IV)
+    }
+
     pub fn decrypt(&self, buf: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let iv = self.iv.as_deref();
-        decrypt(self.cipher, &self.key[..], iv, buf).map_err(|_| CryptoError::BadKey)
+        backend::aes256_cbc_decrypt(self.key.as_bytes(), self.iv(), buf)
     }
 
     pub fn encrypt(&self, buf: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let iv = self.iv.as_deref();
-        encrypt(self.cipher, &self.key[..], iv, buf).map_err(CryptoError::LibraryError)
+        backend::aes256_cbc_encrypt(self.key.as_bytes(), self.iv(), buf)
     }
 }
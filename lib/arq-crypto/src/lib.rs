@@ -1,16 +1,80 @@
+mod any_decrypter;
+mod backend;
+mod blob_hash;
+mod encrypted_object;
+mod encryption_dat;
 mod key;
 mod object_decrypter;
+mod object_encrypter;
+mod secret;
+mod stream;
 
+pub use any_decrypter::ObjectDecrypterAny;
+pub use backend::LibraryError;
+pub use blob_hash::BlobHasher;
+pub use encrypted_object::{
+    hmac_sha256, MasterKeys, ObjectDecrypterV2, ObjectEncrypterV2, MASTER_KEY_LEN,
+};
+pub use encryption_dat::EncryptionDat;
 pub use key::CryptoKey;
 pub use object_decrypter::ObjectDecrypterV1;
+pub use object_encrypter::ObjectEncrypterV1;
+pub use secret::{Password, SecretBytes};
 
+use std::{fmt, io};
+
+#[derive(Debug)]
 pub enum CryptoError {
     BadKey,
     MalformedData,
+    HmacMismatch,
     Unexpected,
-    LibraryError(openssl::error::ErrorStack),
+    LibraryError(LibraryError),
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::BadKey => f.write_str("bad key"),
+            CryptoError::MalformedData => f.write_str("malformed data"),
+            CryptoError::HmacMismatch => f.write_str("HMAC mismatch"),
+            CryptoError::Unexpected => f.write_str("unexpected error"),
+            CryptoError::LibraryError(e) => write!(f, "crypto library error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// Streaming decryption reports failures as `io::ErrorKind::InvalidData`
+/// errors wrapping the `CryptoError`.
+impl From<CryptoError> for io::Error {
+    fn from(err: CryptoError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
 }
 
 pub trait ObjectDecrypter {
     fn decrypt_object(&self, object_bytes: &[u8]) -> Result<Vec<u8>, CryptoError>;
+
+    /// Returns a reader that decrypts `object` as it is read, so that large
+    /// objects never need to be held in memory in both their encrypted and
+    /// decrypted forms. Integrity failures may only be reported at the end
+    /// of the stream. The default implementation reads the whole object and
+    /// hands it to `decrypt_object`.
+    fn decrypt_stream<'a>(
+        &'a self,
+        mut object: Box<dyn io::Read + 'a>,
+    ) -> Result<Box<dyn io::Read + 'a>, CryptoError> {
+        let mut object_bytes = Vec::new();
+        object
+            .read_to_end(&mut object_bytes)
+            .map_err(|_| CryptoError::MalformedData)?;
+        let plaintext = self.decrypt_object(&object_bytes)?;
+        Ok(Box::new(io::Cursor::new(plaintext)))
+    }
+}
+
+pub trait ObjectEncrypter {
+    fn encrypt_object(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError>;
 }
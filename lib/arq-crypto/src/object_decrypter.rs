@@ -1,6 +1,8 @@
-use crate::{CryptoError, CryptoKey, ObjectDecrypter};
+use std::io::Read;
 
-#[derive(Clone)]
+use crate::{backend, stream::DecryptingReader, CryptoError, CryptoKey, ObjectDecrypter};
+
+#[derive(Debug)]
 pub struct ObjectDecrypterV1 {
     key: CryptoKey,
 }
@@ -15,4 +17,12 @@ impl ObjectDecrypter for ObjectDecrypterV1 {
     fn decrypt_object(&self, object_bytes: &[u8]) -> Result<Vec<u8>, CryptoError> {
         self.key.decrypt(object_bytes)
     }
+
+    fn decrypt_stream<'a>(
+        &'a self,
+        object: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>, CryptoError> {
+        let cipher = backend::CbcDecrypter::new(self.key.key_bytes(), self.key.iv())?;
+        Ok(Box::new(DecryptingReader::new(object, cipher, None)))
+    }
 }
@@ -0,0 +1,55 @@
+use crate::{CryptoError, CryptoKey, ObjectEncrypter};
+
+/// Produces objects in the legacy (pre-`ARQO`) format, readable by an
+/// `ObjectDecrypterV1` created from the same key.
+#[derive(Debug)]
+pub struct ObjectEncrypterV1 {
+    key: CryptoKey,
+}
+
+impl ObjectEncrypterV1 {
+    pub fn new(key: CryptoKey) -> Self {
+        ObjectEncrypterV1 { key }
+    }
+}
+
+impl ObjectEncrypter for ObjectEncrypterV1 {
+    fn encrypt_object(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.key.encrypt(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ObjectDecrypter, ObjectDecrypterV1};
+
+    #[test]
+    fn v1_round_trip() {
+        let key = || CryptoKey::new("hunter2", b"saltsalt").ok().unwrap();
+        let encrypter = ObjectEncrypterV1::new(key());
+        let decrypter = ObjectDecrypterV1::new(key());
+
+        let plaintext = b"It was a bright cold day in April".to_vec();
+        let ciphertext = encrypter.encrypt_object(&plaintext).ok().unwrap();
+        assert_ne!(ciphertext, plaintext);
+
+        // The legacy format has a fixed IV, so encryption is deterministic
+        assert_eq!(
+            encrypter.encrypt_object(&plaintext).ok(),
+            Some(ciphertext.clone())
+        );
+        assert_eq!(
+            decrypter.decrypt_object(&ciphertext).ok(),
+            Some(plaintext.clone())
+        );
+
+        let mut stream = decrypter
+            .decrypt_stream(Box::new(&ciphertext[..]))
+            .ok()
+            .unwrap();
+        let mut streamed = Vec::new();
+        std::io::Read::read_to_end(&mut stream, &mut streamed).unwrap();
+        assert_eq!(streamed, plaintext);
+    }
+}
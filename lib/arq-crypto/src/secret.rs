@@ -0,0 +1,142 @@
+use std::fmt;
+
+use zeroize::Zeroize;
+
+/**
+ * A heap buffer holding key material. The buffer is wiped when it is
+ * dropped, never appears in `Debug` output and can't be cloned. With the
+ * `mlock` feature it is also locked into memory, so that it can't be
+ * written out to swap.
+ */
+pub struct SecretBytes(Box<[u8]>);
+
+impl SecretBytes {
+    /// Takes ownership of `bytes`. The content is copied into an exactly
+    /// sized buffer, and the original is wiped.
+    pub fn new(mut bytes: Vec<u8>) -> SecretBytes {
+        let secret = SecretBytes::from_slice(&bytes);
+        bytes.zeroize();
+        secret
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> SecretBytes {
+        let secret = SecretBytes(bytes.to_vec().into_boxed_slice());
+        lock(&secret.0);
+        secret
+    }
+
+    /// A zero-filled buffer, for filling in place
+    pub fn zeroed(len: usize) -> SecretBytes {
+        let secret = SecretBytes(vec![0; len].into_boxed_slice());
+        lock(&secret.0);
+        secret
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn as_mut_bytes(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        unlock(&self.0);
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes([REDACTED; {}])", self.0.len())
+    }
+}
+
+/**
+ * An encryption password, with the same protections as `SecretBytes`.
+ */
+pub struct Password(SecretBytes);
+
+impl Password {
+    pub fn new(password: String) -> Password {
+        Password(SecretBytes::new(password.into_bytes()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever constructed from a `String`
+        std::str::from_utf8(self.0.as_bytes()).unwrap()
+    }
+}
+
+impl From<String> for Password {
+    fn from(password: String) -> Password {
+        Password::new(password)
+    }
+}
+
+impl From<&str> for Password {
+    fn from(password: &str) -> Password {
+        Password(SecretBytes::from_slice(password.as_bytes()))
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Password([REDACTED])")
+    }
+}
+
+#[cfg(all(feature = "mlock", unix))]
+fn lock(buf: &[u8]) {
+    if buf.is_empty() {
+        return;
+    }
+
+    // Failure isn't fatal: unprivileged processes often have a very small
+    // RLIMIT_MEMLOCK
+    let result = unsafe { libc::mlock(buf.as_ptr() as *const libc::c_void, buf.len()) };
+    if result != 0 {
+        log::warn!(
+            "Locking key material into memory failed: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(all(feature = "mlock", unix))]
+fn unlock(buf: &[u8]) {
+    if !buf.is_empty() {
+        unsafe { libc::munlock(buf.as_ptr() as *const libc::c_void, buf.len()) };
+    }
+}
+
+#[cfg(not(all(feature = "mlock", unix)))]
+fn lock(_buf: &[u8]) {}
+
+#[cfg(not(all(feature = "mlock", unix)))]
+fn unlock(_buf: &[u8]) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn secrets_are_redacted() {
+        let password = Password::new("hunter2".to_string());
+        assert_eq!(password.as_str(), "hunter2");
+        assert!(!format!("{:?}", password).contains("hunter2"));
+
+        let bytes = SecretBytes::from_slice(b"\x01\x02\x03");
+        assert_eq!(format!("{:?}", bytes), "SecretBytes([REDACTED; 3])");
+    }
+}
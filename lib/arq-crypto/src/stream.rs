@@ -0,0 +1,86 @@
+use std::io::{self, Read};
+
+use crate::{backend, CryptoError};
+
+const CHUNK_LEN: usize = 64 * 1024;
+const HMAC_LEN: usize = 32;
+
+/**
+ * Decrypts an AES-256-CBC ciphertext as it is read, optionally checking an
+ * HMAC-SHA256 over it. The HMAC can only be checked once the whole
+ * ciphertext has been read, so a mismatch is reported by the final `read`
+ * rather than up front, and the data read before that must not be trusted
+ * until the reader has returned EOF.
+ */
+pub(crate) struct DecryptingReader<'a> {
+    input: Box<dyn Read + 'a>,
+    cipher: backend::CbcDecrypter,
+    hmac: Option<(backend::HmacSha256, [u8; HMAC_LEN])>,
+    chunk: Vec<u8>,
+    plaintext: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> DecryptingReader<'a> {
+    pub(crate) fn new(
+        input: Box<dyn Read + 'a>,
+        cipher: backend::CbcDecrypter,
+        hmac: Option<(backend::HmacSha256, [u8; HMAC_LEN])>,
+    ) -> Self {
+        DecryptingReader {
+            input,
+            cipher,
+            hmac,
+            chunk: vec![0; CHUNK_LEN],
+            plaintext: Vec::with_capacity(CHUNK_LEN + 16),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        self.plaintext.clear();
+        self.pos = 0;
+
+        // The cipher holds back the last block, so a read can produce nothing
+        while self.plaintext.is_empty() && !self.done {
+            let n = match self.input.read(&mut self.chunk) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            if n == 0 {
+                if let Some((hmac, expected)) = self.hmac.take() {
+                    if !backend::constant_time_eq(&hmac.finish(), &expected) {
+                        return Err(CryptoError::HmacMismatch.into());
+                    }
+                }
+                self.cipher.finish(&mut self.plaintext)?;
+                self.done = true;
+            } else {
+                let chunk = &self.chunk[..n];
+                if let Some((hmac, _)) = self.hmac.as_mut() {
+                    hmac.update(chunk);
+                }
+                self.cipher.update(chunk, &mut self.plaintext)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Read for DecryptingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.plaintext.len() {
+            self.fill()?;
+        }
+
+        let available = &self.plaintext[self.pos..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -1,26 +1,111 @@
-mod cache;
-
 use arq_storage::{Error as StorageError, Include, Key, ObjectInfo, Result as StorageResult};
 use log::{debug, error};
+use std::ops::Range;
 
 use rusoto_core::{
     credential::StaticProvider,
+    region::ParseRegionError,
     request::{HttpClient, TlsError},
     Region, RusotoError,
 };
 use rusoto_s3::{
-    CommonPrefix, GetObjectError, GetObjectRequest, ListObjectsV2Error, ListObjectsV2Request,
-    Object as S3Object, S3Client, S3,
+    CommonPrefix, DeleteObjectError, DeleteObjectRequest, GetObjectError, GetObjectRequest,
+    ListObjectsError, ListObjectsRequest, ListObjectsV2Error, ListObjectsV2Request,
+    Object as S3Object, PutObjectError, PutObjectRequest, S3Client, S3,
 };
-
-use cache::Cache;
+use std::str::FromStr;
 
 use trait_async::trait_async;
 
+/// The object storage services we know how to talk to via their
+/// S3-compatible APIs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Aws,
+
+    /// Backblaze B2. Authenticates with an application key ID and
+    /// application key in place of the AWS access key pair.
+    B2,
+
+    /// Google Cloud Storage via its XML API. Authenticates with an HMAC
+    /// interoperability key.
+    Gcs,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownProvider(pub String);
+
+impl FromStr for Provider {
+    type Err = UnknownProvider;
+
+    fn from_str(s: &str) -> Result<Provider, UnknownProvider> {
+        match s.to_ascii_lowercase().as_str() {
+            "aws" | "s3" => Ok(Provider::Aws),
+            "b2" | "backblaze" => Ok(Provider::B2),
+            "gcs" | "google" => Ok(Provider::Gcs),
+            _ => Err(UnknownProvider(s.to_owned())),
+        }
+    }
+}
+
+impl Provider {
+    /// Works out where to send requests for a bucket in the named region. An
+    /// explicit `endpoint` (e.g. a local emulator) overrides the provider's
+    /// public one.
+    pub fn region(&self, name: &str, endpoint: Option<&str>) -> Result<Region, ParseRegionError> {
+        if let Some(endpoint) = endpoint {
+            return Ok(Region::Custom {
+                name: name.to_owned(),
+                endpoint: endpoint.to_owned(),
+            });
+        }
+
+        match self {
+            Provider::Aws => Region::from_str(name),
+            Provider::B2 => Ok(Region::Custom {
+                name: name.to_owned(),
+                endpoint: format!("https://s3.{}.backblazeb2.com", name),
+            }),
+            Provider::Gcs => Ok(Region::Custom {
+                name: name.to_owned(),
+                endpoint: "https://storage.googleapis.com".to_owned(),
+            }),
+        }
+    }
+
+    /// Arq's B2 and GCS destination drivers spread the objects directory
+    /// across 256 subdirectories; its S3 driver does not.
+    pub fn shards_objects(&self) -> bool {
+        !matches!(self, Provider::Aws)
+    }
+
+    fn list_api(&self) -> ListApi {
+        match self {
+            // GCS's XML API only paginates the original ListObjects call
+            // reliably, using markers rather than continuation tokens.
+            Provider::Gcs => ListApi::V1,
+            _ => ListApi::V2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListApi {
+    V1,
+    V2,
+}
+
+/// Everything returned by a single page of a bucket listing
+struct ListingPage {
+    prefixes: Vec<CommonPrefix>,
+    objects: Vec<S3Object>,
+    next: Option<String>,
+}
+
 pub struct Store {
     bucket: String,
     s3: S3Client,
-    cache: Cache,
+    list_api: ListApi,
 }
 
 impl Store {
@@ -29,7 +114,16 @@ impl Store {
         key_id: &str,
         secret: &str,
         region: Region,
-        cache_dir: Option<std::path::PathBuf>,
+    ) -> Result<Store, TlsError> {
+        Store::for_provider(Provider::Aws, bucket, key_id, secret, region)
+    }
+
+    pub fn for_provider(
+        provider: Provider,
+        bucket: &str,
+        key_id: &str,
+        secret: &str,
+        region: Region,
     ) -> Result<Store, TlsError> {
         let creds = StaticProvider::new(key_id.to_string(), secret.to_string(), None, None);
         let dispatcher = HttpClient::new()?;
@@ -38,14 +132,112 @@ impl Store {
         let t = Store {
             bucket: bucket.to_string(),
             s3: client,
-            cache: Cache::new(cache_dir),
+            list_api: provider.list_api(),
         };
 
         Ok(t)
     }
+
+    async fn list_page(&self, prefix: &str, start: Option<String>) -> StorageResult<ListingPage> {
+        match self.list_api {
+            ListApi::V2 => {
+                let req = ListObjectsV2Request {
+                    bucket: self.bucket.clone(),
+                    continuation_token: start,
+                    delimiter: Some("/".to_string()),
+                    prefix: Some(prefix.to_string()),
+                    ..ListObjectsV2Request::default()
+                };
+
+                let response = self
+                    .s3
+                    .list_objects_v2(req)
+                    .await
+                    .map_err(translate_list_objects_v2_err)?;
+
+                let next = match response.is_truncated {
+                    Some(true) => response.next_continuation_token,
+                    _ => None,
+                };
+
+                Ok(ListingPage {
+                    prefixes: response.common_prefixes.unwrap_or_default(),
+                    objects: response.contents.unwrap_or_default(),
+                    next,
+                })
+            }
+
+            ListApi::V1 => {
+                let req = ListObjectsRequest {
+                    bucket: self.bucket.clone(),
+                    marker: start,
+                    delimiter: Some("/".to_string()),
+                    prefix: Some(prefix.to_string()),
+                    ..ListObjectsRequest::default()
+                };
+
+                let response = self
+                    .s3
+                    .list_objects(req)
+                    .await
+                    .map_err(translate_list_objects_err)?;
+
+                let prefixes = response.common_prefixes.unwrap_or_default();
+                let objects = response.contents.unwrap_or_default();
+                let next = match response.is_truncated {
+                    Some(true) => response
+                        .next_marker
+                        .or_else(|| last_listed_key(&prefixes, &objects)),
+                    _ => None,
+                };
+
+                Ok(ListingPage {
+                    prefixes,
+                    objects,
+                    next,
+                })
+            }
+        }
+    }
+
+    async fn fetch(&self, req: GetObjectRequest) -> StorageResult<Vec<u8>> {
+        let is_range = req.range.is_some();
+        let response = match self.s3.get_object(req).await {
+            Ok(response) => response,
+            Err(err) if is_range && is_unsatisfiable_range(&err) => return Ok(Vec::new()),
+            Err(err) => return Err(translate_get_object_err(err)),
+        };
+
+        match response.body {
+            None => Ok(Vec::new()),
+            Some(body) => read_all(body).await.map_err(|_| StorageError::NetworkError),
+        }
+    }
+}
+
+/// Servers are allowed to leave out `NextMarker` in a truncated V1 listing,
+/// in which case the next page starts after the greatest key or prefix
+/// returned so far.
+fn last_listed_key(prefixes: &[CommonPrefix], objects: &[S3Object]) -> Option<String> {
+    let prefixes = prefixes.iter().filter_map(|p| p.prefix.as_ref());
+    let keys = objects.iter().filter_map(|o| o.key.as_ref());
+    prefixes.chain(keys).max().cloned()
 }
 
-fn translate_list_objects_err(err: RusotoError<ListObjectsV2Error>) -> StorageError {
+fn translate_list_objects_err(err: RusotoError<ListObjectsError>) -> StorageError {
+    use ListObjectsError::NoSuchBucket;
+    use RusotoError::Service;
+
+    match err {
+        Service(NoSuchBucket(_)) => StorageError::NoSuchObject,
+        _ => {
+            error!("Unexpected error: {:?}", err);
+            StorageError::UnknownError
+        }
+    }
+}
+
+fn translate_list_objects_v2_err(err: RusotoError<ListObjectsV2Error>) -> StorageError {
     use ListObjectsV2Error::NoSuchBucket;
     use RusotoError::Service;
 
@@ -71,6 +263,24 @@ fn translate_get_object_err(err: RusotoError<GetObjectError>) -> StorageError {
     }
 }
 
+/// S3 rejects a range that starts at or past the end of an object, where a
+/// `Store` is expected to truncate it and return nothing
+fn is_unsatisfiable_range(err: &RusotoError<GetObjectError>) -> bool {
+    matches!(err, RusotoError::Unknown(response) if response.status.as_u16() == 416)
+}
+
+fn translate_write_err<E: std::fmt::Debug>(err: RusotoError<E>) -> StorageError {
+    match err {
+        RusotoError::Unknown(ref response) if response.status.as_u16() == 403 => {
+            StorageError::AccessDenied
+        }
+        _ => {
+            error!("Unexpected error: {:?}", err);
+            StorageError::UnknownError
+        }
+    }
+}
+
 async fn read_all(mut s: rusoto_core::ByteStream) -> Result<Vec<u8>, std::io::Error> {
     use futures::stream::TryStreamExt;
 
@@ -108,78 +318,159 @@ impl arq_storage::Store for Store {
             }
         }
 
-        let s3_client = self.s3.clone();
-        let bucket = self.bucket.clone();
-        let delimiter = '/'.to_string();
-        let search_prefix = prefix.to_string();
-
         let mut result = vec![];
-        let mut continuation_token = None;
+        let mut start = None;
         loop {
-            let req = ListObjectsV2Request {
-                bucket: bucket.clone(),
-                continuation_token,
-                delimiter: Some(delimiter.clone()),
-                prefix: Some(search_prefix.clone()),
-                ..ListObjectsV2Request::default()
-            };
-
-            let response = s3_client
-                .list_objects_v2(req)
-                .await
-                .map_err(translate_list_objects_err)?;
+            let page = self.list_page(prefix, start).await?;
 
             if flags.contains(Include::DIRS) {
-                if let Some(prefixes) = response.common_prefixes {
-                    result.extend(prefixes.into_iter().map(object_from_pfx));
-                }
+                result.extend(page.prefixes.into_iter().map(object_from_pfx));
             }
 
             if flags.contains(Include::FILES) {
-                if let Some(objects) = response.contents {
-                    result.extend(objects.into_iter().map(object_from_content));
-                }
+                result.extend(page.objects.into_iter().map(object_from_content));
             }
 
-            if !response.is_truncated.unwrap_or(false) {
-                break;
-            }
-
-            continuation_token = response.next_continuation_token;
+            start = match page.next {
+                Some(next) => Some(next),
+                None => break,
+            };
         }
 
-        return Ok(result);
+        Ok(result)
     }
 
     async fn get(&self, key: Key) -> StorageResult<Vec<u8>> {
-        if let Some(buf) = self.cache.read(&key) {
-            return Ok(buf);
+        let req = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            ..GetObjectRequest::default()
+        };
+
+        self.fetch(req).await
+    }
+
+    async fn get_range(&self, key: Key, range: Range<u64>) -> StorageResult<Vec<u8>> {
+        if range.end <= range.start {
+            return Ok(Vec::new());
         }
 
+        // HTTP byte ranges are inclusive at both ends
         let req = GetObjectRequest {
             bucket: self.bucket.clone(),
             key: key.to_string(),
+            range: Some(format!("bytes={}-{}", range.start, range.end - 1)),
             ..GetObjectRequest::default()
         };
 
-        let response = self
-            .s3
-            .get_object(req)
-            .await
-            .map_err(translate_get_object_err)?;
+        self.fetch(req).await
+    }
 
-        let content = match response.body {
-            None => Vec::new(),
-            Some(body) => read_all(body)
-                .await
-                .map_err(|_| StorageError::NetworkError)?,
+    fn supports_ranges(&self) -> bool {
+        true
+    }
+
+    async fn put(&self, key: Key, content: Vec<u8>) -> StorageResult<()> {
+        debug!("Writing {} bytes to {}", content.len(), key);
+        let req = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            content_length: Some(content.len() as i64),
+            body: Some(content.into()),
+            ..PutObjectRequest::default()
         };
 
-        self.cache.write(&key, &content);
+        self.s3
+            .put_object(req)
+            .await
+            .map(|_| ())
+            .map_err(translate_write_err::<PutObjectError>)
+    }
+
+    async fn delete(&self, key: Key) -> StorageResult<()> {
+        debug!("Deleting {}", key);
+        let req = DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            ..DeleteObjectRequest::default()
+        };
 
-        Ok(content)
+        self.s3
+            .delete_object(req)
+            .await
+            .map(|_| ())
+            .map_err(translate_write_err::<DeleteObjectError>)
     }
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+    use rusoto_core::request::BufferedHttpResponse;
+
+    #[test]
+    fn provider_endpoints() {
+        assert_eq!(
+            Provider::Aws.region("ap-southeast-2", None),
+            Ok(Region::ApSoutheast2)
+        );
+        assert_eq!(
+            Provider::B2.region("us-west-004", None),
+            Ok(Region::Custom {
+                name: "us-west-004".to_string(),
+                endpoint: "https://s3.us-west-004.backblazeb2.com".to_string(),
+            })
+        );
+        assert_eq!(
+            Provider::Gcs.region("auto", Some("http://localhost:4443")),
+            Ok(Region::Custom {
+                name: "auto".to_string(),
+                endpoint: "http://localhost:4443".to_string(),
+            })
+        );
+        assert!(Provider::Aws.region("nowhere-1", None).is_err());
+    }
+
+    #[test]
+    fn parse_provider() {
+        assert_eq!("b2".parse(), Ok(Provider::B2));
+        assert_eq!("GCS".parse(), Ok(Provider::Gcs));
+        assert_eq!("s3".parse(), Ok(Provider::Aws));
+        assert_eq!(
+            "azure".parse::<Provider>(),
+            Err(UnknownProvider("azure".to_string()))
+        );
+    }
+
+    #[test]
+    fn v1_listing_resumes_after_last_key() {
+        let prefixes = vec![CommonPrefix {
+            prefix: Some("C/objects/ff/".to_string()),
+        }];
+        let objects = vec![S3Object {
+            key: Some("C/objects/00".to_string()),
+            ..S3Object::default()
+        }];
+        assert_eq!(
+            last_listed_key(&prefixes, &objects),
+            Some("C/objects/ff/".to_string())
+        );
+        assert_eq!(last_listed_key(&[], &[]), None);
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_empty() {
+        let response = |status: u16| {
+            RusotoError::<GetObjectError>::Unknown(BufferedHttpResponse {
+                status: http::StatusCode::from_u16(status).unwrap(),
+                body: bytes::Bytes::new(),
+                headers: http::HeaderMap::default(),
+            })
+        };
+        assert!(is_unsatisfiable_range(&response(416)));
+        assert!(!is_unsatisfiable_range(&response(403)));
+        assert!(!is_unsatisfiable_range(&RusotoError::Service(
+            GetObjectError::NoSuchKey("nope".to_string())
+        )));
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    net::TcpStream,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use arq_storage::{Error as StorageError, Include, Key, ObjectInfo, Result as StorageResult};
+use log::{debug, error};
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, Session, Sftp};
+use trait_async::trait_async;
+
+// SFTP status codes, from draft-ietf-secsh-filexfer-02
+const SSH_FX_NO_SUCH_FILE: i32 = 2;
+const SSH_FX_PERMISSION_DENIED: i32 = 3;
+
+#[derive(Debug, Clone)]
+pub enum Auth {
+    Password(String),
+    PrivateKey {
+        path: PathBuf,
+        passphrase: Option<String>,
+    },
+    Agent,
+}
+
+/// Everything we need to know to connect to an SFTP server.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub auth: Auth,
+
+    /// The directory on the server that holds the backup set, i.e. the
+    /// parent of the computer UUID directories.
+    pub root: String,
+
+    /// The OpenSSH-format known hosts file used to verify the server's host
+    /// key. Defaults to `~/.ssh/known_hosts`.
+    pub known_hosts: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Ssh(ssh2::Error),
+    UnknownHost(String),
+    HostKeyMismatch(String),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<ssh2::Error> for Error {
+    fn from(e: ssh2::Error) -> Error {
+        Error::Ssh(e)
+    }
+}
+
+/**
+ * A store backed by a directory on an SFTP server.
+ *
+ * Everything goes through a single SFTP channel, which libssh2 can only use
+ * from one thread at a time, so requests are served one after another no
+ * matter how many are made at once. Fetching trees concurrently gains
+ * nothing with this store.
+ */
+pub struct Store {
+    root: String,
+    sftp: Arc<Mutex<Sftp>>,
+
+    // The SFTP channel is only valid while the session that owns it is
+    // alive, so we hold on to it here.
+    _session: Session,
+}
+
+fn default_known_hosts() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".ssh").join("known_hosts"))
+}
+
+fn verify_host_key(session: &Session, opts: &Options) -> Result<(), Error> {
+    let path = opts
+        .known_hosts
+        .clone()
+        .or_else(default_known_hosts)
+        .ok_or_else(|| Error::UnknownHost(opts.host.clone()))?;
+
+    let mut known_hosts = session.known_hosts()?;
+    known_hosts.read_file(&path, KnownHostFileKind::OpenSSH)?;
+
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| Error::UnknownHost(opts.host.clone()))?;
+
+    match known_hosts.check_port(&opts.host, opts.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(Error::HostKeyMismatch(opts.host.clone())),
+        CheckResult::NotFound | CheckResult::Failure => Err(Error::UnknownHost(opts.host.clone())),
+    }
+}
+
+impl Store {
+    /// Connects and authenticates to an SFTP server. This blocks until the
+    /// connection is established.
+    pub fn connect(opts: &Options) -> Result<Store, Error> {
+        debug!("Connecting to {}:{}", opts.host, opts.port);
+        let tcp = TcpStream::connect((opts.host.as_str(), opts.port))?;
+
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+
+        verify_host_key(&session, opts)?;
+
+        match &opts.auth {
+            Auth::Password(password) => session.userauth_password(&opts.user, password)?,
+            Auth::PrivateKey { path, passphrase } => {
+                session.userauth_pubkey_file(&opts.user, None, path, passphrase.as_deref())?
+            }
+            Auth::Agent => session.userauth_agent(&opts.user)?,
+        }
+
+        let sftp = session.sftp()?;
+
+        Ok(Store {
+            root: opts.root.trim_end_matches('/').to_owned(),
+            sftp: Arc::new(Mutex::new(sftp)),
+            _session: session,
+        })
+    }
+
+    fn remote_path(&self, path: &str) -> PathBuf {
+        remote_path(&self.root, path)
+    }
+
+    /// Runs a blocking SFTP operation on the blocking thread pool, so that
+    /// it doesn't stall the async runtime.
+    async fn run<T, F>(&self, f: F) -> StorageResult<T>
+    where
+        F: FnOnce(&Sftp) -> StorageResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let sftp = self.sftp.clone();
+        tokio::task::spawn_blocking(move || f(&sftp.lock().unwrap()))
+            .await
+            .map_err(|e| {
+                error!("SFTP task failed: {:?}", e);
+                StorageError::UnknownError
+            })?
+    }
+}
+
+fn remote_path(root: &str, path: &str) -> PathBuf {
+    if root.is_empty() && path.is_empty() {
+        PathBuf::from(".")
+    } else if root.is_empty() {
+        PathBuf::from(path)
+    } else {
+        PathBuf::from(format!("{}/{}", root, path))
+    }
+}
+
+/// Splits an S3-style listing prefix into the directory to list and the
+/// prefix that the names in that directory must start with.
+fn split_prefix(prefix: &str) -> (&str, &str) {
+    match prefix.rfind('/') {
+        Some(n) => (&prefix[..n + 1], &prefix[n + 1..]),
+        None => ("", prefix),
+    }
+}
+
+fn translate_err(err: ssh2::Error) -> StorageError {
+    match err.code() {
+        ErrorCode::SFTP(SSH_FX_NO_SUCH_FILE) => StorageError::NoSuchObject,
+        ErrorCode::SFTP(SSH_FX_PERMISSION_DENIED) => StorageError::AccessDenied,
+        _ => {
+            error!("Unexpected error: {:?}", err);
+            StorageError::NetworkError
+        }
+    }
+}
+
+fn translate_io_err(err: std::io::Error) -> StorageError {
+    error!("Unexpected error: {:?}", err);
+    StorageError::NetworkError
+}
+
+#[trait_async]
+impl arq_storage::Store for Store {
+    async fn list_contents(&self, prefix: &str, flags: Include) -> StorageResult<Vec<ObjectInfo>> {
+        debug!("Fetching listing for {}", prefix);
+        let (dir, name_prefix) = split_prefix(prefix);
+        let dir = dir.to_owned();
+        let name_prefix = name_prefix.to_owned();
+        let remote_dir = self.remote_path(&dir);
+
+        self.run(move |sftp| {
+            let entries = match sftp.readdir(&remote_dir) {
+                Ok(entries) => entries,
+                Err(e) => match translate_err(e) {
+                    // S3 semantics: listing a prefix that doesn't exist is
+                    // not an error, it's just empty
+                    StorageError::NoSuchObject => Vec::new(),
+                    e => return Err(e),
+                },
+            };
+
+            let mut result = Vec::new();
+            for (path, stat) in entries {
+                let name = match path.file_name().and_then(|n| n.to_str()) {
+                    Some(n) if n.starts_with(&name_prefix) => n,
+                    _ => continue,
+                };
+
+                if stat.is_dir() {
+                    if flags.contains(Include::DIRS) {
+                        result.push(ObjectInfo {
+                            key: Key::from(format!("{}{}/", dir, name)),
+                            size: 0,
+                        });
+                    }
+                } else if flags.contains(Include::FILES) {
+                    result.push(ObjectInfo {
+                        key: Key::from(format!("{}{}", dir, name)),
+                        size: stat.size.unwrap_or(0) as i64,
+                    });
+                }
+            }
+
+            result.sort_by(|a, b| a.key.cmp(&b.key));
+            Ok(result)
+        })
+        .await
+    }
+
+    async fn get(&self, key: Key) -> StorageResult<Vec<u8>> {
+        let path = self.remote_path(key.as_str());
+        self.run(move |sftp| {
+            let mut f = sftp.open(&path).map_err(translate_err)?;
+            let mut content = Vec::new();
+            f.read_to_end(&mut content).map_err(translate_io_err)?;
+            Ok(content)
+        })
+        .await
+    }
+
+    async fn get_range(&self, key: Key, range: Range<u64>) -> StorageResult<Vec<u8>> {
+        if range.end <= range.start {
+            return Ok(Vec::new());
+        }
+
+        let path = self.remote_path(key.as_str());
+        self.run(move |sftp| {
+            let mut f = sftp.open(&path).map_err(translate_err)?;
+            f.seek(SeekFrom::Start(range.start))
+                .map_err(translate_io_err)?;
+            let mut content = Vec::new();
+            f.take(range.end - range.start)
+                .read_to_end(&mut content)
+                .map_err(translate_io_err)?;
+            Ok(content)
+        })
+        .await
+    }
+
+    fn supports_ranges(&self) -> bool {
+        true
+    }
+
+    async fn put(&self, key: Key, content: Vec<u8>) -> StorageResult<()> {
+        let path = self.remote_path(key.as_str());
+        self.run(move |sftp| {
+            let mut f = sftp.create(&path).map_err(translate_err)?;
+            f.write_all(&content).map_err(translate_io_err)
+        })
+        .await
+    }
+
+    async fn delete(&self, key: Key) -> StorageResult<()> {
+        let path = self.remote_path(key.as_str());
+        self.run(move |sftp| sftp.unlink(&path).map_err(translate_err))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prefixes_are_split_on_the_last_delimiter() {
+        assert_eq!(split_prefix(""), ("", ""));
+        assert_eq!(split_prefix("C0FFEE/buckets/"), ("C0FFEE/buckets/", ""));
+        assert_eq!(
+            split_prefix("C0FFEE/packsets/AB"),
+            ("C0FFEE/packsets/", "AB")
+        );
+        assert_eq!(split_prefix("C0FFEE"), ("", "C0FFEE"));
+    }
+
+    #[test]
+    fn remote_paths_are_relative_to_root() {
+        assert_eq!(remote_path("", ""), PathBuf::from("."));
+        assert_eq!(remote_path("", "C0FFEE/salt"), PathBuf::from("C0FFEE/salt"));
+        assert_eq!(
+            remote_path("/backups/arq", "C0FFEE/salt"),
+            PathBuf::from("/backups/arq/C0FFEE/salt")
+        );
+        assert_eq!(
+            remote_path("/backups/arq", ""),
+            PathBuf::from("/backups/arq/")
+        );
+    }
+
+    // Runs against a real SFTP server, e.g. one started with
+    //
+    //   docker run -p 2222:2222 -e USER_NAME=larq -e USER_PASSWORD=larq \
+    //       -e PASSWORD_ACCESS=true linuxserver/openssh-server
+    //
+    // and configured with LARQ_SFTP_TEST_{HOST,PORT,USER,PASSWORD,ROOT}. The
+    // server's host key must be in ~/.ssh/known_hosts, and ROOT must hold a
+    // directory called `larq-test` containing a file `hello` with the content
+    // "hello, world".
+    #[tokio::test]
+    #[ignore]
+    async fn against_local_server() {
+        use arq_storage::Store as _;
+
+        let env = |name: &str| std::env::var(format!("LARQ_SFTP_TEST_{}", name)).unwrap();
+        let opts = Options {
+            host: env("HOST"),
+            port: env("PORT").parse().unwrap(),
+            user: env("USER"),
+            auth: Auth::Password(env("PASSWORD")),
+            root: env("ROOT"),
+            known_hosts: None,
+        };
+        let store = Store::connect(&opts).expect("Connection should succeed");
+
+        let dirs = store.list_contents("", Include::DIRS).await.unwrap();
+        assert!(dirs.iter().any(|o| o.key == Key::from("larq-test/")));
+
+        let files = store
+            .list_contents("larq-test/he", Include::FILES)
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].key, Key::from("larq-test/hello"));
+        assert_eq!(files[0].size, 12);
+
+        let content = store.get(Key::from("larq-test/hello")).await.unwrap();
+        assert_eq!(content, b"hello, world");
+
+        let content = store
+            .get_range(Key::from("larq-test/hello"), 7..100)
+            .await
+            .unwrap();
+        assert_eq!(content, b"world");
+
+        assert_eq!(
+            store.get(Key::from("larq-test/nope")).await,
+            Err(StorageError::NoSuchObject)
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::{ops::Range, path::PathBuf, sync::Arc};
+
+use trait_async::trait_async;
+
+use crate::{slice_range, Include, Key, Metrics, ObjectInfo, Result, Store};
+
+/**
+ * A store decorator that keeps a copy of every object fetched from the
+ * underlying store on the local disk, and serves subsequent requests for
+ * the same key from there. Listings are always passed through, as are ranged
+ * reads of objects that aren't already in the cache.
+ *
+ * Objects that Arq rewrites in place are never cached, since a copy of one
+ * would go stale. These are the key files, whose password can be changed,
+ * and anything under a `refs` directory, i.e. branch heads and reflogs.
+ */
+pub struct CachedStore {
+    inner: Arc<dyn Store>,
+    root: PathBuf,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl CachedStore {
+    pub fn new(inner: Arc<dyn Store>, root: PathBuf) -> CachedStore {
+        CachedStore {
+            inner,
+            root,
+            metrics: None,
+        }
+    }
+
+    /// Report cache hits and misses to the supplied metrics collection
+    pub fn with_metrics(self, metrics: Arc<Metrics>) -> CachedStore {
+        CachedStore {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+
+    fn read(&self, key: &Key) -> Option<Vec<u8>> {
+        use std::io::Read;
+
+        let path = self.root.join(key.as_str());
+
+        if let Ok(mut f) = std::fs::File::open(&path) {
+            let mut content = Vec::new();
+            if f.read_to_end(&mut content).is_ok() {
+                log::debug!("Found in cache");
+                return Some(content);
+            }
+        }
+
+        None
+    }
+
+    fn write(&self, key: &Key, data: &[u8]) {
+        use std::io::Write;
+
+        let path = self.root.join(key.as_str());
+        let tmp = path.with_extension(".tmp");
+
+        if let Some(parent_dir) = path.parent() {
+            let _ = std::fs::create_dir_all(parent_dir);
+        }
+
+        if let Ok(mut f) = std::fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&tmp)
+        {
+            let _ = f.write_all(data);
+            drop(f);
+
+            let _ = std::fs::rename(&tmp, &path);
+        }
+    }
+}
+
+/// Whether the object at `key` can change after it's written
+fn is_mutable(key: &Key) -> bool {
+    let key = key.as_str();
+    let name = key.rsplit('/').next().unwrap_or(key);
+    (name.starts_with("encryption") && name.ends_with(".dat"))
+        || key.split('/').any(|c| c == "refs")
+}
+
+#[trait_async]
+impl Store for CachedStore {
+    async fn list_contents(&self, path: &str, flags: Include) -> Result<Vec<ObjectInfo>> {
+        self.inner.list_contents(path, flags).await
+    }
+
+    async fn get(&self, key: Key) -> Result<Vec<u8>> {
+        if is_mutable(&key) {
+            return self.inner.get(key).await;
+        }
+
+        if let Some(buf) = self.read(&key) {
+            if let Some(m) = self.metrics.as_ref() {
+                m.record_cache_hit();
+            }
+            return Ok(buf);
+        }
+
+        if let Some(m) = self.metrics.as_ref() {
+            m.record_cache_miss();
+        }
+        let content = self.inner.get(key.clone()).await?;
+        self.write(&key, &content);
+
+        Ok(content)
+    }
+
+    async fn get_range(&self, key: Key, range: Range<u64>) -> Result<Vec<u8>> {
+        if is_mutable(&key) {
+            return self.inner.get_range(key, range).await;
+        }
+
+        if let Some(buf) = self.read(&key) {
+            if let Some(m) = self.metrics.as_ref() {
+                m.record_cache_hit();
+            }
+            return Ok(slice_range(&buf, range).to_vec());
+        }
+
+        if let Some(m) = self.metrics.as_ref() {
+            m.record_cache_miss();
+        }
+        self.inner.get_range(key, range).await
+    }
+
+    fn supports_ranges(&self) -> bool {
+        self.inner.supports_ranges()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rewritten_objects_are_mutable() {
+        let mutable = |key: &str| is_mutable(&Key::from(key));
+
+        assert!(mutable("C0FFEE/encryptionv3.dat"));
+        assert!(mutable("C0FFEE/encryptionv2.dat"));
+        assert!(mutable("C0FFEE/bucketdata/F00D/refs/heads/master"));
+        assert!(mutable("C0FFEE/bucketdata/F00D/refs/logs/master/1"));
+
+        assert!(!mutable("C0FFEE/computerinfo"));
+        assert!(!mutable("C0FFEE/objects/0123456789abcdef"));
+        assert!(!mutable("C0FFEE/packsets/F00D-trees/0123.pack"));
+    }
+}
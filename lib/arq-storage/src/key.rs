@@ -1,4 +1,5 @@
 use std::convert::From;
+use std::fmt;
 use std::ops::Div;
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone, Default)]
@@ -6,8 +7,7 @@ pub struct Key(String);
 
 impl Key {
     pub fn as_str(&self) -> &str {
-        let &Key(ref s) = self;
-        s.as_str()
+        self.0.as_str()
     }
 
     pub fn into_string(self) -> String {
@@ -15,15 +15,13 @@ impl Key {
     }
 
     pub fn ends_with(&self, suffix: &str) -> bool {
-        let &Key(ref s) = self;
-        s.ends_with(suffix)
+        self.0.ends_with(suffix)
     }
 }
 
-impl ToString for Key {
-    fn to_string(&self) -> String {
-        let &Key(ref s) = self;
-        s.clone()
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
     }
 }
 
@@ -0,0 +1,275 @@
+use std::{ops::Range, sync::Arc};
+
+use trait_async::trait_async;
+
+use crate::{Include, Key, ObjectInfo, Result, Store};
+
+/**
+ * Describes where a destination driver physically puts the objects of a
+ * backup set, as opposed to the logical `<computer>/...` paths the rest of
+ * the code uses.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyLayout {
+    /// The folder inside the bucket (or server) that holds the backup set,
+    /// if any.
+    pub prefix: String,
+
+    /// Some destinations spread `<computer>/objects/<sha1>` across 256
+    /// subdirectories named for the first two characters of the SHA1, i.e.
+    /// `<computer>/objects/<sha1[0..2]>/<sha1[2..]>`.
+    pub shard_objects: bool,
+}
+
+impl KeyLayout {
+    fn prefix(&self) -> String {
+        let p = self.prefix.trim_matches('/');
+        if p.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", p)
+        }
+    }
+
+    /// Maps a logical key onto its physical location
+    pub fn physical(&self, logical: &str) -> String {
+        let mut result = self.prefix();
+        match split_object_key(logical) {
+            Some((computer, sha)) if self.shard_objects && sha.len() > 2 => {
+                result += &format!("{}/objects/{}/{}", computer, &sha[..2], &sha[2..]);
+            }
+            _ => result += logical,
+        }
+        result
+    }
+
+    /// Maps a physical key back onto its logical name, or `None` if the key
+    /// lies outside of the backup set.
+    pub fn logical(&self, physical: &str) -> Option<String> {
+        let rest = physical.strip_prefix(&self.prefix())?;
+        if !self.shard_objects {
+            return Some(rest.to_owned());
+        }
+
+        let parts: Vec<&str> = rest.split('/').collect();
+        match parts.as_slice() {
+            [computer, "objects", shard, name] if shard.len() == 2 && !name.is_empty() => {
+                Some(format!("{}/objects/{}{}", computer, shard, name))
+            }
+            _ => Some(rest.to_owned()),
+        }
+    }
+}
+
+/// Splits `<computer>/objects/<sha1>` into its computer and SHA1 parts.
+fn split_object_key(key: &str) -> Option<(&str, &str)> {
+    let mut parts = key.splitn(3, '/');
+    let computer = parts.next()?;
+    match (parts.next(), parts.next()) {
+        (Some("objects"), Some(sha)) if !sha.contains('/') => Some((computer, sha)),
+        _ => None,
+    }
+}
+
+/**
+ * A store decorator that translates between logical keys and the physical
+ * layout of a particular destination type.
+ */
+pub struct LayoutStore {
+    inner: Arc<dyn Store>,
+    layout: KeyLayout,
+}
+
+impl LayoutStore {
+    pub fn new(inner: Arc<dyn Store>, layout: KeyLayout) -> LayoutStore {
+        LayoutStore { inner, layout }
+    }
+
+    async fn list_physical(&self, path: &str, flags: Include) -> Result<Vec<ObjectInfo>> {
+        let objects = self.inner.list_contents(path, flags).await?;
+        Ok(objects
+            .into_iter()
+            .filter_map(|obj| {
+                self.layout.logical(obj.key.as_str()).map(|key| ObjectInfo {
+                    key: Key::from(key),
+                    size: obj.size,
+                })
+            })
+            .collect())
+    }
+
+    /// Lists a prefix inside a sharded objects directory, which may mean
+    /// listing several of the shards.
+    async fn list_sharded(
+        &self,
+        computer: &str,
+        sha_prefix: &str,
+        flags: Include,
+    ) -> Result<Vec<ObjectInfo>> {
+        let objects_dir = format!("{}{}/objects/", self.layout.prefix(), computer);
+
+        if sha_prefix.len() >= 2 {
+            let path = format!("{}{}/{}", objects_dir, &sha_prefix[..2], &sha_prefix[2..]);
+            return self.list_physical(&path, flags & Include::FILES).await;
+        }
+
+        let shards = self
+            .inner
+            .list_contents(&format!("{}{}", objects_dir, sha_prefix), Include::DIRS)
+            .await?;
+
+        let mut result = Vec::new();
+        for shard in shards {
+            let mut objects = self
+                .list_physical(shard.key.as_str(), flags & Include::FILES)
+                .await?;
+            result.append(&mut objects);
+        }
+        Ok(result)
+    }
+}
+
+#[trait_async]
+impl Store for LayoutStore {
+    async fn list_contents(&self, path: &str, flags: Include) -> Result<Vec<ObjectInfo>> {
+        if self.layout.shard_objects {
+            if let Some((computer, sha_prefix)) = split_object_key(path) {
+                return self.list_sharded(computer, sha_prefix, flags).await;
+            }
+        }
+
+        self.list_physical(&self.layout.physical(path), flags).await
+    }
+
+    async fn get(&self, key: Key) -> Result<Vec<u8>> {
+        let physical = Key::from(self.layout.physical(key.as_str()));
+        self.inner.get(physical).await
+    }
+
+    async fn get_range(&self, key: Key, range: Range<u64>) -> Result<Vec<u8>> {
+        let physical = Key::from(self.layout.physical(key.as_str()));
+        self.inner.get_range(physical, range).await
+    }
+
+    fn supports_ranges(&self) -> bool {
+        self.inner.supports_ranges()
+    }
+
+    async fn put(&self, key: Key, content: Vec<u8>) -> Result<()> {
+        let physical = Key::from(self.layout.physical(key.as_str()));
+        self.inner.put(physical, content).await
+    }
+
+    async fn delete(&self, key: Key) -> Result<()> {
+        let physical = Key::from(self.layout.physical(key.as_str()));
+        self.inner.delete(physical).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SHA: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn flat_layout_only_adds_prefix() {
+        let layout = KeyLayout {
+            prefix: "/arq/".to_string(),
+            shard_objects: false,
+        };
+        let key = format!("C0FFEE/objects/{}", SHA);
+        assert_eq!(layout.physical(&key), format!("arq/{}", key));
+        assert_eq!(layout.logical(&format!("arq/{}", key)), Some(key));
+        assert_eq!(layout.logical("elsewhere/C0FFEE/salt"), None);
+    }
+
+    #[test]
+    fn sharded_layout_splits_object_names() {
+        let layout = KeyLayout {
+            prefix: String::new(),
+            shard_objects: true,
+        };
+        let logical = format!("C0FFEE/objects/{}", SHA);
+        let physical = format!("C0FFEE/objects/01/{}", &SHA[2..]);
+        assert_eq!(layout.physical(&logical), physical);
+        assert_eq!(layout.logical(&physical), Some(logical));
+
+        // Only objects are sharded
+        assert_eq!(layout.physical("C0FFEE/salt"), "C0FFEE/salt");
+        assert_eq!(
+            layout.physical("C0FFEE/packsets/A-trees/1.pack"),
+            "C0FFEE/packsets/A-trees/1.pack"
+        );
+    }
+
+    struct ShardedStore;
+
+    #[trait_async]
+    impl Store for ShardedStore {
+        async fn list_contents(&self, path: &str, flags: Include) -> Result<Vec<ObjectInfo>> {
+            let all = [
+                "pfx/C0FFEE/objects/01/23",
+                "pfx/C0FFEE/objects/01/45",
+                "pfx/C0FFEE/objects/ab/cd",
+            ];
+            let mut result = Vec::new();
+            for k in all.iter().filter(|k| k.starts_with(path)) {
+                let rest = &k[path.len()..];
+                match rest.find('/') {
+                    Some(n) if flags.contains(Include::DIRS) => {
+                        let key = Key::from(format!("{}{}", path, &rest[..n + 1]));
+                        if !result.iter().any(|o: &ObjectInfo| o.key == key) {
+                            result.push(ObjectInfo { key, size: 0 });
+                        }
+                    }
+                    None if flags.contains(Include::FILES) => result.push(ObjectInfo {
+                        key: Key::from(*k),
+                        size: 1,
+                    }),
+                    _ => {}
+                }
+            }
+            Ok(result)
+        }
+
+        async fn get(&self, key: Key) -> Result<Vec<u8>> {
+            Ok(key.as_str().as_bytes().to_vec())
+        }
+    }
+
+    #[tokio::test]
+    async fn sharded_objects_are_listed_as_if_flat() {
+        let layout = KeyLayout {
+            prefix: "pfx".to_string(),
+            shard_objects: true,
+        };
+        let store = LayoutStore::new(Arc::new(ShardedStore), layout);
+
+        let keys = |objects: Vec<ObjectInfo>| -> Vec<String> {
+            objects.into_iter().map(|o| o.key.into_string()).collect()
+        };
+
+        let all = store
+            .list_contents("C0FFEE/objects/", Include::FILES)
+            .await
+            .unwrap();
+        assert_eq!(
+            keys(all),
+            vec![
+                "C0FFEE/objects/0123",
+                "C0FFEE/objects/0145",
+                "C0FFEE/objects/abcd"
+            ]
+        );
+
+        let some = store
+            .list_contents("C0FFEE/objects/014", Include::FILES)
+            .await
+            .unwrap();
+        assert_eq!(keys(some), vec!["C0FFEE/objects/0145"]);
+
+        let data = store.get(Key::from("C0FFEE/objects/abcd")).await.unwrap();
+        assert_eq!(data, b"pfx/C0FFEE/objects/ab/cd");
+    }
+}
@@ -1,8 +1,18 @@
 // traits and types for abstracting away storage mechanisms
 
+mod cache;
 mod key;
+mod layout;
+mod metrics;
+mod rate_limit;
+mod record;
 mod store;
 
 pub use key::Key;
 
-pub use store::{Error, Include, ObjectInfo, Result, Store};
+pub use cache::CachedStore;
+pub use layout::{KeyLayout, LayoutStore};
+pub use metrics::{Metrics, MetricsStore, Operation, OperationStats};
+pub use rate_limit::{ByteRate, RateLimitedStore};
+pub use record::{RecordingStore, ReplayStore};
+pub use store::{slice_range, Error, Include, ObjectInfo, Result, Store};
@@ -0,0 +1,367 @@
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use trait_async::trait_async;
+
+use crate::{Include, Key, ObjectInfo, Result, Store};
+
+/// The kinds of request a store can be asked to service.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub enum Operation {
+    List,
+    Get,
+    GetRange,
+}
+
+impl Operation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Operation::List => "list",
+            Operation::Get => "get",
+            Operation::GetRange => "get_range",
+        }
+    }
+}
+
+/// Classifies a key by the area of the backup set it lives in, i.e. the
+/// path component immediately after the computer UUID.
+fn key_prefix(key: &str) -> &'static str {
+    match key.split('/').nth(1) {
+        Some("packsets") => "packsets",
+        Some("objects") => "objects",
+        Some("bucketdata") => "bucketdata",
+        Some("buckets") => "buckets",
+        _ => "other",
+    }
+}
+
+/// Accumulated statistics for a single operation on a single key prefix.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OperationStats {
+    pub requests: u64,
+    pub errors: u64,
+    pub bytes: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+/// Describes how one field of `OperationStats` is exported to Prometheus.
+struct Series {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&OperationStats) -> String,
+}
+
+const PROMETHEUS_SERIES: [Series; 5] = [
+    Series {
+        name: "larq_store_requests_total",
+        kind: "counter",
+        help: "Requests made to the store",
+        value: |s| s.requests.to_string(),
+    },
+    Series {
+        name: "larq_store_errors_total",
+        kind: "counter",
+        help: "Requests that failed",
+        value: |s| s.errors.to_string(),
+    },
+    Series {
+        name: "larq_store_bytes_total",
+        kind: "counter",
+        help: "Bytes received from the store",
+        value: |s| s.bytes.to_string(),
+    },
+    Series {
+        name: "larq_store_request_seconds_total",
+        kind: "counter",
+        help: "Total time spent waiting on the store",
+        value: |s| s.total_latency.as_secs_f64().to_string(),
+    },
+    Series {
+        name: "larq_store_request_seconds_max",
+        kind: "gauge",
+        help: "Longest single request to the store",
+        value: |s| s.max_latency.as_secs_f64().to_string(),
+    },
+];
+
+/**
+ * A collection of request statistics, shared between all of the stores that
+ * report into it.
+ */
+#[derive(Debug, Default)]
+pub struct Metrics {
+    operations: Mutex<BTreeMap<(Operation, &'static str), OperationStats>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    fn record(&self, op: Operation, key: &str, bytes: Option<usize>, latency: Duration) {
+        let mut operations = self.operations.lock().unwrap();
+        let stats = operations.entry((op, key_prefix(key))).or_default();
+        stats.requests += 1;
+        match bytes {
+            Some(n) => stats.bytes += n as u64,
+            None => stats.errors += 1,
+        }
+        stats.total_latency += latency;
+        stats.max_latency = stats.max_latency.max(latency);
+    }
+
+    pub fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits.load(Ordering::Relaxed)
+    }
+
+    pub fn cache_misses(&self) -> u64 {
+        self.cache_misses.load(Ordering::Relaxed)
+    }
+
+    /// Returns a snapshot of the stats for each operation and key prefix
+    /// seen so far, in a stable order.
+    pub fn operations(&self) -> Vec<(Operation, &'static str, OperationStats)> {
+        self.operations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((op, prefix), stats)| (*op, *prefix, stats.clone()))
+            .collect()
+    }
+
+    /// Writes the metrics out in the Prometheus text exposition format.
+    pub fn write_prometheus<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        let operations = self.operations();
+
+        for series in PROMETHEUS_SERIES.iter() {
+            writeln!(w, "# HELP {} {}", series.name, series.help)?;
+            writeln!(w, "# TYPE {} {}", series.name, series.kind)?;
+            for (op, prefix, stats) in operations.iter() {
+                writeln!(
+                    w,
+                    "{}{{op=\"{}\",prefix=\"{}\"}} {}",
+                    series.name,
+                    op.as_str(),
+                    prefix,
+                    (series.value)(stats)
+                )?;
+            }
+        }
+
+        writeln!(w, "# HELP larq_store_cache_requests_total Cache lookups")?;
+        writeln!(w, "# TYPE larq_store_cache_requests_total counter")?;
+        writeln!(
+            w,
+            "larq_store_cache_requests_total{{result=\"hit\"}} {}",
+            self.cache_hits()
+        )?;
+        writeln!(
+            w,
+            "larq_store_cache_requests_total{{result=\"miss\"}} {}",
+            self.cache_misses()
+        )
+    }
+}
+
+/// A human-readable summary table
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<9} {:<10} {:>9} {:>7} {:>14} {:>12} {:>12}",
+            "op", "prefix", "requests", "errors", "bytes", "avg latency", "max latency"
+        )?;
+        for (op, prefix, stats) in self.operations().iter() {
+            let avg_latency = if stats.requests == 0 {
+                Duration::from_secs(0)
+            } else {
+                stats.total_latency / stats.requests as u32
+            };
+            writeln!(
+                f,
+                "{:<9} {:<10} {:>9} {:>7} {:>14} {:>12.3?} {:>12.3?}",
+                op.as_str(),
+                prefix,
+                stats.requests,
+                stats.errors,
+                stats.bytes,
+                avg_latency,
+                stats.max_latency
+            )?;
+        }
+        writeln!(
+            f,
+            "cache: {} hits, {} misses",
+            self.cache_hits(),
+            self.cache_misses()
+        )
+    }
+}
+
+/**
+ * A store decorator that records the number, size and latency of the
+ * requests it passes through to the underlying store.
+ */
+pub struct MetricsStore {
+    inner: Arc<dyn Store>,
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsStore {
+    pub fn new(inner: Arc<dyn Store>, metrics: Arc<Metrics>) -> MetricsStore {
+        MetricsStore { inner, metrics }
+    }
+}
+
+#[trait_async]
+impl Store for MetricsStore {
+    async fn list_contents(&self, path: &str, flags: Include) -> Result<Vec<ObjectInfo>> {
+        let start = Instant::now();
+        let result = self.inner.list_contents(path, flags).await;
+        self.metrics.record(
+            Operation::List,
+            path,
+            result.as_ref().ok().map(|_| 0),
+            start.elapsed(),
+        );
+        result
+    }
+
+    async fn get(&self, key: Key) -> Result<Vec<u8>> {
+        let start = Instant::now();
+        let result = self.inner.get(key.clone()).await;
+        self.metrics.record(
+            Operation::Get,
+            key.as_str(),
+            result.as_ref().ok().map(Vec::len),
+            start.elapsed(),
+        );
+        result
+    }
+
+    async fn get_range(&self, key: Key, range: Range<u64>) -> Result<Vec<u8>> {
+        let start = Instant::now();
+        let result = self.inner.get_range(key.clone(), range).await;
+        self.metrics.record(
+            Operation::GetRange,
+            key.as_str(),
+            result.as_ref().ok().map(Vec::len),
+            start.elapsed(),
+        );
+        result
+    }
+
+    fn supports_ranges(&self) -> bool {
+        self.inner.supports_ranges()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Error;
+
+    struct TestStore;
+
+    #[trait_async]
+    impl Store for TestStore {
+        async fn list_contents(&self, _path: &str, _flags: Include) -> Result<Vec<ObjectInfo>> {
+            Ok(Vec::new())
+        }
+
+        async fn get(&self, key: Key) -> Result<Vec<u8>> {
+            if key.ends_with(".missing") {
+                Err(Error::NoSuchObject)
+            } else {
+                Ok(vec![0; 10])
+            }
+        }
+    }
+
+    #[test]
+    fn keys_are_classified_by_prefix() {
+        assert_eq!(key_prefix("C0FFEE/packsets/A-trees/0123.pack"), "packsets");
+        assert_eq!(key_prefix("C0FFEE/objects/0123"), "objects");
+        assert_eq!(
+            key_prefix("C0FFEE/bucketdata/A/refs/heads/master"),
+            "bucketdata"
+        );
+        assert_eq!(key_prefix("C0FFEE/computerinfo"), "other");
+        assert_eq!(key_prefix(""), "other");
+    }
+
+    #[tokio::test]
+    async fn requests_are_counted() {
+        let metrics = Arc::new(Metrics::new());
+        let store = MetricsStore::new(Arc::new(TestStore), metrics.clone());
+
+        store
+            .get(Key::from("C/packsets/A-trees/1.pack"))
+            .await
+            .unwrap();
+        store
+            .get(Key::from("C/packsets/A-trees/2.pack"))
+            .await
+            .unwrap();
+        store
+            .get(Key::from("C/objects/3.missing"))
+            .await
+            .unwrap_err();
+        store
+            .list_contents("C/packsets/", Include::FILES)
+            .await
+            .unwrap();
+
+        let ops = metrics.operations();
+        assert_eq!(ops.len(), 3);
+
+        let (op, prefix, stats) = &ops[0];
+        assert_eq!((*op, *prefix), (Operation::List, "packsets"));
+        assert_eq!(stats.requests, 1);
+
+        let (op, prefix, stats) = &ops[1];
+        assert_eq!((*op, *prefix), (Operation::Get, "objects"));
+        assert_eq!((stats.requests, stats.errors, stats.bytes), (1, 1, 0));
+
+        let (op, prefix, stats) = &ops[2];
+        assert_eq!((*op, *prefix), (Operation::Get, "packsets"));
+        assert_eq!((stats.requests, stats.errors, stats.bytes), (2, 0, 20));
+    }
+
+    #[tokio::test]
+    async fn prometheus_output() {
+        let metrics = Arc::new(Metrics::new());
+        let store = MetricsStore::new(Arc::new(TestStore), metrics.clone());
+        store.get(Key::from("C/objects/1")).await.unwrap();
+        metrics.record_cache_hit();
+
+        let mut buf = Vec::new();
+        metrics.write_prometheus(&mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+
+        assert!(text.contains("larq_store_requests_total{op=\"get\",prefix=\"objects\"} 1\n"));
+        assert!(text.contains("larq_store_bytes_total{op=\"get\",prefix=\"objects\"} 10\n"));
+        assert!(text.contains("larq_store_cache_requests_total{result=\"hit\"} 1\n"));
+        assert!(text.contains("larq_store_cache_requests_total{result=\"miss\"} 0\n"));
+    }
+}
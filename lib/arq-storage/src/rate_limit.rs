@@ -0,0 +1,407 @@
+use std::{
+    fmt,
+    ops::Range,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+use trait_async::trait_async;
+
+use crate::{slice_range, Include, Key, ObjectInfo, Result, Store};
+
+/// A transfer rate, in bytes per second.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct ByteRate(u64);
+
+impl ByteRate {
+    pub fn bytes_per_second(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for ByteRate {
+    fn from(n: u64) -> ByteRate {
+        ByteRate(n)
+    }
+}
+
+/// Parses a rate in the same style as curl's `--limit-rate`, i.e. a number
+/// of bytes per second with an optional `K`, `M` or `G` (binary) suffix.
+impl FromStr for ByteRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ByteRate, String> {
+        let s = s.trim();
+        let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&s[..s.len() - 1], 1 << 10),
+            Some('M') => (&s[..s.len() - 1], 1 << 20),
+            Some('G') => (&s[..s.len() - 1], 1 << 30),
+            _ => (s, 1),
+        };
+
+        let n: u64 = digits
+            .parse()
+            .map_err(|_| format!("Invalid transfer rate: {:?}", s))?;
+
+        match n.checked_mul(multiplier) {
+            Some(0) | None => Err(format!("Invalid transfer rate: {:?}", s)),
+            Some(rate) => Ok(ByteRate(rate)),
+        }
+    }
+}
+
+impl fmt::Display for ByteRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes/s", self.0)
+    }
+}
+
+/// A token bucket that is allowed to go into debt. Each transfer reserves
+/// the most it could pull before it starts, and then waits until any debt
+/// that leaves is paid off. Whatever the transfer didn't use is refunded
+/// when it finishes.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: ByteRate, now: Instant) -> TokenBucket {
+        let rate = rate.bytes_per_second() as f64;
+        TokenBucket {
+            rate,
+            capacity: rate,
+            tokens: rate,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Reserves `n` bytes, returning how long the transfer must wait before
+    /// it may start.
+    fn reserve(&mut self, n: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    fn refund(&mut self, n: u64, now: Instant) {
+        self.refill(now);
+        self.tokens = (self.tokens + n as f64).min(self.capacity);
+    }
+
+    /// The largest transfer worth making at once: a second's worth.
+    fn chunk_size(&self) -> u64 {
+        (self.capacity as u64).max(1)
+    }
+}
+
+/**
+ * A store decorator that limits the rate at which object data is pulled
+ * from the underlying store. The limit is shared between all concurrent
+ * requests made through the same `RateLimitedStore`.
+ *
+ * If the underlying store supports ranges, objects are pulled with
+ * `get_range`, a second's worth of data at a time, and each piece is paid
+ * for before it is requested, so no single large object can be pulled
+ * faster than the limit. Otherwise each object is pulled whole with a
+ * single `get`: a second's worth is paid for up front and the rest once it
+ * has arrived, which holds back the requests that follow it.
+ *
+ * Listings are not counted against the limit.
+ */
+pub struct RateLimitedStore {
+    inner: Arc<dyn Store>,
+    bucket: Mutex<TokenBucket>,
+}
+
+impl RateLimitedStore {
+    pub fn new(inner: Arc<dyn Store>, rate: ByteRate) -> RateLimitedStore {
+        RateLimitedStore {
+            inner,
+            bucket: Mutex::new(TokenBucket::new(rate, Instant::now())),
+        }
+    }
+
+    async fn reserve(&self, n: u64) {
+        let delay = self.bucket.lock().unwrap().reserve(n, Instant::now());
+        if delay > Duration::from_secs(0) {
+            log::debug!("Rate limit exceeded, waiting {:?}", delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn refund(&self, n: u64) {
+        self.bucket.lock().unwrap().refund(n, Instant::now());
+    }
+
+    /// Fetches a whole object with a single request, for stores that can't
+    /// fetch ranges
+    async fn fetch_whole(&self, key: Key) -> Result<Vec<u8>> {
+        let reserved = self.bucket.lock().unwrap().chunk_size();
+        self.reserve(reserved).await;
+
+        let content = match self.inner.get(key).await {
+            Ok(content) => content,
+            Err(e) => {
+                self.refund(reserved);
+                return Err(e);
+            }
+        };
+        let got = content.len() as u64;
+        if got < reserved {
+            self.refund(reserved - got);
+        } else {
+            // Too late to wait for this one, so the debt delays the next
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.reserve(got - reserved, Instant::now());
+        }
+        Ok(content)
+    }
+
+    /// Fetches `range` of an object piece by piece, stopping early at the
+    /// end of the object.
+    async fn fetch(&self, key: Key, range: Range<u64>) -> Result<Vec<u8>> {
+        let chunk_size = self.bucket.lock().unwrap().chunk_size();
+        let mut content = Vec::new();
+        let mut start = range.start;
+
+        while start < range.end {
+            let end = range.end.min(start.saturating_add(chunk_size));
+            let wanted = end - start;
+            self.reserve(wanted).await;
+
+            let chunk = match self.inner.get_range(key.clone(), start..end).await {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    self.refund(wanted);
+                    return Err(e);
+                }
+            };
+            let got = chunk.len() as u64;
+            self.refund(wanted.saturating_sub(got));
+            content.extend_from_slice(&chunk);
+
+            if got < wanted {
+                break;
+            }
+            start = end;
+        }
+
+        Ok(content)
+    }
+}
+
+#[trait_async]
+impl Store for RateLimitedStore {
+    async fn list_contents(&self, path: &str, flags: Include) -> Result<Vec<ObjectInfo>> {
+        self.inner.list_contents(path, flags).await
+    }
+
+    async fn get(&self, key: Key) -> Result<Vec<u8>> {
+        if !self.inner.supports_ranges() {
+            return self.fetch_whole(key).await;
+        }
+        self.fetch(key, 0..u64::MAX).await
+    }
+
+    async fn get_range(&self, key: Key, range: Range<u64>) -> Result<Vec<u8>> {
+        if !self.inner.supports_ranges() {
+            let content = self.fetch_whole(key).await?;
+            return Ok(slice_range(&content, range).to_vec());
+        }
+        self.fetch(key, range).await
+    }
+
+    fn supports_ranges(&self) -> bool {
+        self.inner.supports_ranges()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!("100".parse::<ByteRate>(), Ok(ByteRate(100)));
+        assert_eq!("5k".parse::<ByteRate>(), Ok(ByteRate(5 * 1024)));
+        assert_eq!("5M".parse::<ByteRate>(), Ok(ByteRate(5 * 1024 * 1024)));
+        assert_eq!(
+            "2G".parse::<ByteRate>(),
+            Ok(ByteRate(2 * 1024 * 1024 * 1024))
+        );
+        assert!("0".parse::<ByteRate>().is_err());
+        assert!("M".parse::<ByteRate>().is_err());
+        assert!("fast".parse::<ByteRate>().is_err());
+    }
+
+    #[test]
+    fn bucket_in_debt_delays_next_transfer() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(ByteRate(1000), start);
+        assert_eq!(bucket.reserve(1000, start), Duration::from_secs(0));
+
+        // 2000 more bytes against an empty 1000-byte bucket leaves us 2000
+        // bytes (i.e. 2 seconds) in debt
+        assert_eq!(bucket.reserve(2000, start), Duration::from_secs(2));
+
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(bucket.reserve(0, later), Duration::from_millis(500));
+
+        // Unused bytes are given back
+        bucket.refund(500, later);
+        assert_eq!(bucket.reserve(0, later), Duration::from_secs(0));
+
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(bucket.reserve(0, much_later), Duration::from_secs(0));
+        assert!(bucket.tokens <= bucket.capacity);
+    }
+
+    /// Serves objects of one size, and only whole
+    #[derive(Default)]
+    struct FixedSizeStore {
+        size: usize,
+        gets: AtomicUsize,
+    }
+
+    #[trait_async]
+    impl Store for FixedSizeStore {
+        async fn list_contents(&self, _path: &str, _flags: Include) -> Result<Vec<ObjectInfo>> {
+            Ok(Vec::new())
+        }
+
+        async fn get(&self, _key: Key) -> Result<Vec<u8>> {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![0; self.size])
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_gets_share_the_limit() {
+        tokio::time::pause();
+        let inner = FixedSizeStore {
+            size: 1000,
+            ..FixedSizeStore::default()
+        };
+        let store = RateLimitedStore::new(Arc::new(inner), ByteRate(1000));
+        let start = tokio::time::Instant::now();
+
+        let gets = (0..4).map(|_| store.get(Key::from("a")));
+        let results = futures::future::join_all(gets).await;
+        assert!(results.iter().all(|r| r.is_ok()));
+
+        // The first request is covered by the initial burst and the second
+        // starts with the bucket empty, leaving it in debt. Each subsequent
+        // request has to wait for the debt to be repaid, i.e. 1 second each.
+        // The paused clock means we're not really waiting here.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2), "Elapsed {:?}", elapsed);
+    }
+
+    /// Records the ranges fetched from it
+    #[derive(Default)]
+    struct RangeStore {
+        size: u64,
+        ranges: Mutex<Vec<(Range<u64>, Instant)>>,
+    }
+
+    #[trait_async]
+    impl Store for RangeStore {
+        async fn list_contents(&self, _path: &str, _flags: Include) -> Result<Vec<ObjectInfo>> {
+            Ok(Vec::new())
+        }
+
+        async fn get(&self, _key: Key) -> Result<Vec<u8>> {
+            panic!("Whole objects should never be fetched")
+        }
+
+        fn supports_ranges(&self) -> bool {
+            true
+        }
+
+        async fn get_range(&self, _key: Key, range: Range<u64>) -> Result<Vec<u8>> {
+            let end = range.end.min(self.size);
+            let start = range.start.min(end);
+            self.ranges
+                .lock()
+                .unwrap()
+                .push((range.clone(), Instant::now()));
+            Ok(vec![0; (end - start) as usize])
+        }
+    }
+
+    #[tokio::test]
+    async fn large_objects_are_fetched_a_second_at_a_time() {
+        tokio::time::pause();
+        let inner = Arc::new(RangeStore {
+            size: 4500,
+            ..RangeStore::default()
+        });
+        let store = RateLimitedStore::new(inner.clone(), ByteRate(1000));
+        let start = Instant::now();
+
+        assert_eq!(store.get(Key::from("a")).await.unwrap().len(), 4500);
+        assert_eq!(
+            store.get_range(Key::from("a"), 0..10).await.unwrap().len(),
+            10
+        );
+
+        // Each piece waits for the one before it to be paid for, so the
+        // rate holds even within a single object
+        let ranges = inner.ranges.lock().unwrap();
+        let requested: Vec<_> = ranges.iter().map(|(r, _)| r.clone()).collect();
+        assert_eq!(
+            requested,
+            vec![
+                0..1000,
+                1000..2000,
+                2000..3000,
+                3000..4000,
+                4000..5000,
+                0..10
+            ]
+        );
+        for (i, (_, at)) in ranges.iter().enumerate().take(5) {
+            assert!(*at >= start + Duration::from_secs(i as u64), "{:?}", ranges);
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_without_ranges_are_fetched_whole() {
+        tokio::time::pause();
+        let inner = Arc::new(FixedSizeStore {
+            size: 4500,
+            ..FixedSizeStore::default()
+        });
+        let store = RateLimitedStore::new(inner.clone(), ByteRate(1000));
+        let start = Instant::now();
+
+        assert_eq!(store.get(Key::from("a")).await.unwrap().len(), 4500);
+        assert_eq!(inner.gets.load(Ordering::SeqCst), 1);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // The next request waits until the first has been paid for
+        assert_eq!(
+            store.get_range(Key::from("a"), 0..10).await.unwrap().len(),
+            10
+        );
+        assert_eq!(inner.gets.load(Ordering::SeqCst), 2);
+        assert!(start.elapsed() >= Duration::from_millis(4500));
+    }
+}
@@ -0,0 +1,491 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    ops::Range,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use trait_async::trait_async;
+
+use crate::{Error, Include, Key, ObjectInfo, Result, Store};
+
+// The archive is a flat sequence of records, each describing the result of
+// a single request:
+//
+//   magic            "LARQREC1"
+//   record count     u64
+//   (
+//     tag            u8   ('L' for a listing, 'G' for a get, 'R' for a
+//                         ranged get, 'S' for whether ranges are supported)
+//     path/key       [String] (not for 'S')
+//     flags          u32  (listings only)
+//     range          u64 start, then u64 end (ranged gets only)
+//     status         u8   (0 for success, otherwise an encoded `Error`;
+//                         for 'S', 1 if ranges are supported)
+//     payload             (success only)
+//                         listings: u64 count, then ([String] key, i64 size)*
+//                         gets:     u64 length, then the object data
+//   ) * record count
+//
+// where a [String] is a u64 byte length followed by UTF-8 data, and all
+// integers are big-endian. Archives without an 'S' record were made from a
+// store without range support.
+
+const MAGIC: &[u8] = b"LARQREC1";
+const LISTING_TAG: u8 = b'L';
+const GET_TAG: u8 = b'G';
+const RANGE_TAG: u8 = b'R';
+const SUPPORTS_RANGES_TAG: u8 = b'S';
+
+type Listing = Result<Vec<ObjectInfo>>;
+
+/// The recorded results of every request made through a `RecordingStore`
+#[derive(Debug, Default)]
+struct Archive {
+    listings: BTreeMap<(String, u32), Listing>,
+    objects: BTreeMap<Key, Result<Vec<u8>>>,
+    ranges: BTreeMap<(Key, u64, u64), Result<Vec<u8>>>,
+    supports_ranges: bool,
+}
+
+fn encode_error(e: Error) -> u8 {
+    match e {
+        Error::NoSuchObject => 1,
+        Error::AccessDenied => 2,
+        Error::NetworkError => 3,
+        Error::UnknownError => 4,
+        Error::Unsupported => 5,
+    }
+}
+
+fn decode_error(n: u8) -> io::Result<Error> {
+    match n {
+        1 => Ok(Error::NoSuchObject),
+        2 => Ok(Error::AccessDenied),
+        3 => Ok(Error::NetworkError),
+        4 => Ok(Error::UnknownError),
+        5 => Ok(Error::Unsupported),
+        _ => Err(invalid_data("Invalid error code")),
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_u64<W: Write>(w: &mut W, n: u64) -> io::Result<()> {
+    w.write_all(&n.to_be_bytes())
+}
+
+fn write_bytes<W: Write>(w: &mut W, b: &[u8]) -> io::Result<()> {
+    write_u64(w, b.len() as u64)?;
+    w.write_all(b)
+}
+
+fn write_status<W: Write, T>(w: &mut W, r: &Result<T>) -> io::Result<()> {
+    match r {
+        Ok(_) => w.write_all(&[0]),
+        Err(e) => w.write_all(&[encode_error(*e)]),
+    }
+}
+
+fn read_array<R: Read, const N: usize>(r: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    read_array(r).map(u64::from_be_bytes)
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    let mut buf = Vec::new();
+    r.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    String::from_utf8(read_bytes(r)?).map_err(|_| invalid_data("Invalid UTF-8"))
+}
+
+impl Archive {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        let count = self.listings.len() + self.objects.len() + self.ranges.len() + 1;
+        write_u64(w, count as u64)?;
+        w.write_all(&[SUPPORTS_RANGES_TAG, self.supports_ranges as u8])?;
+
+        for ((path, flags), listing) in self.listings.iter() {
+            w.write_all(&[LISTING_TAG])?;
+            write_bytes(w, path.as_bytes())?;
+            w.write_all(&flags.to_be_bytes())?;
+            write_status(w, listing)?;
+            if let Ok(objects) = listing {
+                write_u64(w, objects.len() as u64)?;
+                for obj in objects.iter() {
+                    write_bytes(w, obj.key.as_str().as_bytes())?;
+                    w.write_all(&obj.size.to_be_bytes())?;
+                }
+            }
+        }
+
+        for (key, object) in self.objects.iter() {
+            w.write_all(&[GET_TAG])?;
+            write_bytes(w, key.as_str().as_bytes())?;
+            write_status(w, object)?;
+            if let Ok(data) = object {
+                write_bytes(w, data)?;
+            }
+        }
+
+        for ((key, start, end), object) in self.ranges.iter() {
+            w.write_all(&[RANGE_TAG])?;
+            write_bytes(w, key.as_str().as_bytes())?;
+            write_u64(w, *start)?;
+            write_u64(w, *end)?;
+            write_status(w, object)?;
+            if let Ok(data) = object {
+                write_bytes(w, data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Archive> {
+        let magic: [u8; 8] = read_array(r)?;
+        if magic != MAGIC {
+            return Err(invalid_data("Not a larq request archive"));
+        }
+
+        let mut archive = Archive::default();
+        for _ in 0..read_u64(r)? {
+            let [tag] = read_array(r)?;
+            if tag == SUPPORTS_RANGES_TAG {
+                let [supported] = read_array(r)?;
+                archive.supports_ranges = supported != 0;
+                continue;
+            }
+
+            let path = read_string(r)?;
+            match tag {
+                LISTING_TAG => {
+                    let flags = read_array(r).map(u32::from_be_bytes)?;
+                    let listing = match read_array(r)? {
+                        [0] => {
+                            let count = read_u64(r)?;
+                            let mut objects = Vec::new();
+                            for _ in 0..count {
+                                let key = Key::from(read_string(r)?);
+                                let size = read_array(r).map(i64::from_be_bytes)?;
+                                objects.push(ObjectInfo { key, size });
+                            }
+                            Ok(objects)
+                        }
+                        [e] => Err(decode_error(e)?),
+                    };
+                    archive.listings.insert((path, flags), listing);
+                }
+                GET_TAG => {
+                    let object = match read_array(r)? {
+                        [0] => Ok(read_bytes(r)?),
+                        [e] => Err(decode_error(e)?),
+                    };
+                    archive.objects.insert(Key::from(path), object);
+                }
+                RANGE_TAG => {
+                    let start = read_u64(r)?;
+                    let end = read_u64(r)?;
+                    let object = match read_array(r)? {
+                        [0] => Ok(read_bytes(r)?),
+                        [e] => Err(decode_error(e)?),
+                    };
+                    archive.ranges.insert((Key::from(path), start, end), object);
+                }
+                _ => return Err(invalid_data("Invalid record tag")),
+            }
+        }
+
+        Ok(archive)
+    }
+}
+
+fn clone_listing(listing: &Listing) -> Listing {
+    listing.as_ref().map_err(|e| *e).map(|objects| {
+        objects
+            .iter()
+            .map(|obj| ObjectInfo {
+                key: obj.key.clone(),
+                size: obj.size,
+            })
+            .collect()
+    })
+}
+
+/**
+ * A store decorator that records the result of every request passed through
+ * to the underlying store, so that they can be saved into a single archive
+ * file and served back later by a `ReplayStore`. This lets us reproduce a
+ * failure without needing access to the original backup set.
+ */
+pub struct RecordingStore {
+    inner: Arc<dyn Store>,
+    archive: Mutex<Archive>,
+}
+
+impl RecordingStore {
+    pub fn new(inner: Arc<dyn Store>) -> RecordingStore {
+        let archive = Archive {
+            supports_ranges: inner.supports_ranges(),
+            ..Archive::default()
+        };
+        RecordingStore {
+            inner,
+            archive: Mutex::new(archive),
+        }
+    }
+
+    /// Writes everything recorded so far to an archive file at `path`.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.archive.lock().unwrap().write(&mut w)?;
+        w.flush()
+    }
+}
+
+#[trait_async]
+impl Store for RecordingStore {
+    async fn list_contents(&self, path: &str, flags: Include) -> Result<Vec<ObjectInfo>> {
+        let result = self.inner.list_contents(path, flags).await;
+        self.archive
+            .lock()
+            .unwrap()
+            .listings
+            .insert((path.to_owned(), flags.bits()), clone_listing(&result));
+        result
+    }
+
+    async fn get(&self, key: Key) -> Result<Vec<u8>> {
+        let result = self.inner.get(key.clone()).await;
+        self.archive
+            .lock()
+            .unwrap()
+            .objects
+            .insert(key, result.clone());
+        result
+    }
+
+    async fn get_range(&self, key: Key, range: Range<u64>) -> Result<Vec<u8>> {
+        let result = self.inner.get_range(key.clone(), range.clone()).await;
+        self.archive
+            .lock()
+            .unwrap()
+            .ranges
+            .insert((key, range.start, range.end), result.clone());
+        result
+    }
+
+    fn supports_ranges(&self) -> bool {
+        self.inner.supports_ranges()
+    }
+}
+
+/**
+ * A store that serves requests from an archive created by a
+ * `RecordingStore`. Any request that was not recorded fails with
+ * `Error::UnknownError`.
+ */
+pub struct ReplayStore {
+    archive: Archive,
+}
+
+impl ReplayStore {
+    pub fn open(path: &Path) -> io::Result<ReplayStore> {
+        let mut r = BufReader::new(File::open(path)?);
+        Archive::read(&mut r).map(|archive| ReplayStore { archive })
+    }
+
+    pub fn from_bytes(mut data: &[u8]) -> io::Result<ReplayStore> {
+        Archive::read(&mut data).map(|archive| ReplayStore { archive })
+    }
+}
+
+#[trait_async]
+impl Store for ReplayStore {
+    async fn list_contents(&self, path: &str, flags: Include) -> Result<Vec<ObjectInfo>> {
+        match self.archive.listings.get(&(path.to_owned(), flags.bits())) {
+            Some(listing) => clone_listing(listing),
+            None => {
+                log::error!("Listing of {:?} not found in archive", path);
+                Err(Error::UnknownError)
+            }
+        }
+    }
+
+    async fn get(&self, key: Key) -> Result<Vec<u8>> {
+        match self.archive.objects.get(&key) {
+            Some(object) => object.clone(),
+            None => {
+                log::error!("Object {} not found in archive", key);
+                Err(Error::UnknownError)
+            }
+        }
+    }
+
+    async fn get_range(&self, key: Key, range: Range<u64>) -> Result<Vec<u8>> {
+        match self
+            .archive
+            .ranges
+            .get(&(key.clone(), range.start, range.end))
+        {
+            Some(object) => object.clone(),
+            None => {
+                log::error!("Range {:?} of {} not found in archive", range, key);
+                Err(Error::UnknownError)
+            }
+        }
+    }
+
+    fn supports_ranges(&self) -> bool {
+        self.archive.supports_ranges
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::slice_range;
+
+    struct TestStore;
+
+    #[trait_async]
+    impl Store for TestStore {
+        async fn list_contents(&self, path: &str, _flags: Include) -> Result<Vec<ObjectInfo>> {
+            Ok(vec![ObjectInfo {
+                key: Key::from(path) / "child",
+                size: 42,
+            }])
+        }
+
+        async fn get(&self, key: Key) -> Result<Vec<u8>> {
+            if key.ends_with(".missing") {
+                Err(Error::NoSuchObject)
+            } else {
+                Ok(key.as_str().as_bytes().to_vec())
+            }
+        }
+
+        async fn get_range(&self, key: Key, range: Range<u64>) -> Result<Vec<u8>> {
+            let content = self.get(key).await?;
+            Ok(slice_range(&content, range).to_vec())
+        }
+
+        fn supports_ranges(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn recorded_requests_are_replayed() {
+        let recorder = RecordingStore::new(Arc::new(TestStore));
+        recorder
+            .list_contents("a/b/", Include::FILES)
+            .await
+            .unwrap();
+        recorder.get(Key::from("a/b/c")).await.unwrap();
+        recorder.get(Key::from("a/b/d.missing")).await.unwrap_err();
+
+        let mut buf = Vec::new();
+        recorder.archive.lock().unwrap().write(&mut buf).unwrap();
+
+        let replay = ReplayStore::from_bytes(&buf).unwrap();
+
+        let listing = replay.list_contents("a/b/", Include::FILES).await.unwrap();
+        assert_eq!(listing.len(), 1);
+        assert_eq!(listing[0].key, Key::from("a/b/child"));
+        assert_eq!(listing[0].size, 42);
+
+        assert_eq!(replay.get(Key::from("a/b/c")).await, Ok(b"a/b/c".to_vec()));
+        assert_eq!(
+            replay.get(Key::from("a/b/d.missing")).await,
+            Err(Error::NoSuchObject)
+        );
+
+        // Anything not in the archive is an error, including the same path
+        // listed with different flags
+        assert_eq!(
+            replay
+                .list_contents("a/b/", Include::DIRS)
+                .await
+                .unwrap_err(),
+            Error::UnknownError
+        );
+        assert_eq!(
+            replay.get(Key::from("a/b/e")).await,
+            Err(Error::UnknownError)
+        );
+    }
+
+    #[tokio::test]
+    async fn recorded_ranges_are_replayed() {
+        let recorder = RecordingStore::new(Arc::new(TestStore));
+        let key = Key::from("a/b/c");
+        assert_eq!(
+            recorder.get_range(key.clone(), 2..4).await,
+            Ok(b"b/".to_vec())
+        );
+        assert_eq!(
+            recorder.get_range(key.clone(), 4..100).await,
+            Ok(b"c".to_vec())
+        );
+        recorder
+            .get_range(Key::from("a/b/d.missing"), 0..1)
+            .await
+            .unwrap_err();
+
+        let mut buf = Vec::new();
+        recorder.archive.lock().unwrap().write(&mut buf).unwrap();
+        let replay = ReplayStore::from_bytes(&buf).unwrap();
+
+        assert!(replay.supports_ranges());
+        assert_eq!(
+            replay.get_range(key.clone(), 2..4).await,
+            Ok(b"b/".to_vec())
+        );
+        assert_eq!(
+            replay.get_range(key.clone(), 4..100).await,
+            Ok(b"c".to_vec())
+        );
+        assert_eq!(
+            replay.get_range(Key::from("a/b/d.missing"), 0..1).await,
+            Err(Error::NoSuchObject)
+        );
+
+        // Neither the whole object nor other ranges of it were recorded
+        assert_eq!(replay.get(key.clone()).await, Err(Error::UnknownError));
+        assert_eq!(replay.get_range(key, 2..5).await, Err(Error::UnknownError));
+    }
+
+    #[test]
+    fn truncated_archive_is_rejected() {
+        let mut archive = Archive::default();
+        archive
+            .objects
+            .insert(Key::from("a"), Ok(vec![1, 2, 3, 4, 5]));
+
+        let mut buf = Vec::new();
+        archive.write(&mut buf).unwrap();
+
+        let replay = ReplayStore::from_bytes(&buf).unwrap();
+        assert!(!replay.supports_ranges());
+        assert!(ReplayStore::from_bytes(&buf[..buf.len() - 1]).is_err());
+        assert!(ReplayStore::from_bytes(b"NOTANARCHIVE").is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::key::Key;
 use bitflags::bitflags;
+use std::ops::Range;
 use trait_async::trait_async;
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -8,6 +9,7 @@ pub enum Error {
     AccessDenied,
     NetworkError,
     UnknownError,
+    Unsupported,
 }
 
 /**
@@ -29,12 +31,57 @@ bitflags! {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-// TODO: maybe allow some sort of ranged-get so that we can get only the parts of an object
-//       we need at any one time.
-
 #[trait_async]
 pub trait Store: Send + Sync {
     async fn list_contents(&self, path: &str, flags: Include) -> Result<Vec<ObjectInfo>>;
 
     async fn get(&self, key: Key) -> Result<Vec<u8>>;
+
+    /// Fetches the bytes of an object in the half-open range `range`. A range
+    /// that extends past the end of the object is truncated. The default
+    /// implementation fetches the whole object, so stores that can do better
+    /// should override it, along with `supports_ranges`.
+    async fn get_range(&self, key: Key, range: Range<u64>) -> Result<Vec<u8>> {
+        let content = self.get(key).await?;
+        Ok(slice_range(&content, range).to_vec())
+    }
+
+    /// Whether `get_range` fetches only the range asked for. Stores that
+    /// wrap another report what the one they wrap can do.
+    fn supports_ranges(&self) -> bool {
+        false
+    }
+
+    /// Creates or replaces an object. Stores are read-only unless they
+    /// override this.
+    async fn put(&self, _key: Key, _content: Vec<u8>) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+
+    /// Removes an object. Stores are read-only unless they override this.
+    async fn delete(&self, _key: Key) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+}
+
+/// Extracts `range` from a buffer, truncating it to the buffer's bounds.
+pub fn slice_range(content: &[u8], range: Range<u64>) -> &[u8] {
+    let len = content.len() as u64;
+    let end = range.end.min(len) as usize;
+    let start = range.start.min(end as u64) as usize;
+    &content[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::slice_range;
+
+    #[test]
+    fn ranges_are_truncated_to_content() {
+        let content = b"hello, world";
+        assert_eq!(slice_range(content, 0..5), b"hello");
+        assert_eq!(slice_range(content, 7..100), b"world");
+        assert_eq!(slice_range(content, 50..100), b"");
+        assert_eq!(slice_range(content, 5..5), b"");
+    }
 }
@@ -0,0 +1,309 @@
+use std::{collections::BTreeMap, path::PathBuf, vec};
+
+use futures::{future, stream, Stream};
+use serde::Serialize;
+
+use crate::{
+    tree::{BlobKey, Node},
+    CompressionType, RepoError,
+};
+
+use super::Commit;
+
+/// How an entry differs between two commits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Removed,
+
+    /// A file's content changed, or an entry changed between being a file
+    /// and a directory
+    Modified,
+
+    /// Only the entry's attributes changed, such as its permissions,
+    /// ownership, modification time, extended attributes or ACL
+    MetadataChanged,
+}
+
+/// An entry that differs between two commits, as found by `Commit::diff`
+#[derive(Debug)]
+pub struct DiffEntry {
+    /// The path relative to the root of the backup
+    pub path: PathBuf,
+
+    pub change: Change,
+
+    /// The entry in the older commit, unless it was added
+    pub old: Option<Node>,
+
+    /// The entry in the newer commit, unless it was removed
+    pub new: Option<Node>,
+}
+
+impl DiffEntry {
+    /// Whether the entry is a directory, in the newer commit if it's in
+    /// both
+    pub fn is_tree(&self) -> bool {
+        self.new
+            .as_ref()
+            .or(self.old.as_ref())
+            .is_some_and(|n| n.is_tree)
+    }
+}
+
+/// A directory whose contents differ, and which needs to be compared
+struct PendingDir {
+    path: PathBuf,
+    old: (Vec<BlobKey>, CompressionType),
+    new: (Vec<BlobKey>, CompressionType),
+}
+
+/// A pair of directories that have been loaded and are being compared
+struct Dir {
+    path: PathBuf,
+    pairs: vec::IntoIter<(Option<Node>, Option<Node>)>,
+}
+
+struct Diff {
+    pending: Option<PendingDir>,
+    dirs: Vec<Dir>,
+}
+
+fn same_metadata(old: &Node, new: &Node) -> bool {
+    let sha = |key: &Option<BlobKey>| key.as_ref().map(|k| k.sha.clone());
+
+    old.file_mode == new.file_mode
+        && old.user_id == new.user_id
+        && old.group_id == new.group_id
+        && old.flags == new.flags
+        && old.mod_time == new.mod_time
+        && sha(&old.xattrs_blob_key) == sha(&new.xattrs_blob_key)
+        && sha(&old.acl_blob_key) == sha(&new.acl_blob_key)
+}
+
+/// Lines the entries of two directories up by name
+fn pair_up(old: Vec<Node>, new: Vec<Node>) -> Vec<(Option<Node>, Option<Node>)> {
+    let mut pairs = BTreeMap::new();
+    for node in old {
+        pairs.insert(node.name.clone(), (Some(node), None));
+    }
+    for node in new {
+        let pair = pairs.entry(node.name.clone()).or_insert((None, None));
+        pair.1 = Some(node);
+    }
+    pairs.into_values().collect()
+}
+
+impl<'a> Commit<'a> {
+    /// Compares this commit with a later one, which may be from a different
+    /// folder. Entries come out depth first, with each directory before
+    /// its contents and the entries of a directory in name order. Anything
+    /// whose tree is the same in both commits is skipped without being
+    /// loaded, and a directory that was added or removed is reported as a
+    /// whole rather than file by file. An error ends the stream.
+    pub fn diff<'s>(
+        &'s self,
+        later: &'s Commit<'_>,
+    ) -> impl Stream<Item = Result<DiffEntry, RepoError>> + 's {
+        let root = PendingDir {
+            path: PathBuf::new(),
+            old: (vec![self.root_key()], self.record.compression_type),
+            new: (vec![later.root_key()], later.record.compression_type),
+        };
+        let diff = Diff {
+            pending: Some(root).filter(|_| self.record.tree_sha != later.record.tree_sha),
+            dirs: Vec::new(),
+        };
+
+        stream::try_unfold(diff, move |mut diff| async move {
+            loop {
+                if let Some(pending) = diff.pending.take() {
+                    let (old_keys, old_compression) = &pending.old;
+                    let (new_keys, new_compression) = &pending.new;
+                    let (old, new) = future::try_join(
+                        self.load_tree(old_keys, *old_compression, &pending.path),
+                        later.load_tree(new_keys, *new_compression, &pending.path),
+                    )
+                    .await?;
+                    diff.dirs.push(Dir {
+                        path: pending.path,
+                        pairs: pair_up(old.nodes, new.nodes).into_iter(),
+                    });
+                }
+
+                let dir = match diff.dirs.last_mut() {
+                    Some(dir) => dir,
+                    None => return Ok(None),
+                };
+                let (old, new) = match dir.pairs.next() {
+                    Some(pair) => pair,
+                    None => {
+                        diff.dirs.pop();
+                        continue;
+                    }
+                };
+
+                let (path, change) = match (&old, &new) {
+                    (Some(o), None) => (dir.path.join(&o.name), Some(Change::Removed)),
+                    (None, Some(n)) => (dir.path.join(&n.name), Some(Change::Added)),
+                    (Some(o), Some(n)) => {
+                        let path = dir.path.join(&n.name);
+                        let change = if o.is_tree != n.is_tree {
+                            Some(Change::Modified)
+                        } else if o.same_content(n) {
+                            Some(Change::MetadataChanged).filter(|_| !same_metadata(o, n))
+                        } else if o.is_tree {
+                            diff.pending = Some(PendingDir {
+                                path: path.clone(),
+                                old: (o.data_blob_keys.clone(), o.data_compression_type),
+                                new: (n.data_blob_keys.clone(), n.data_compression_type),
+                            });
+                            Some(Change::MetadataChanged).filter(|_| !same_metadata(o, n))
+                        } else {
+                            Some(Change::Modified)
+                        };
+                        (path, change)
+                    }
+                    (None, None) => unreachable!("pair_up pairs at least one node"),
+                };
+
+                if let Some(change) = change {
+                    let entry = DiffEntry {
+                        path,
+                        change,
+                        old,
+                        new,
+                    };
+                    return Ok(Some((entry, diff)));
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        mocks::{commit, node, tree_packset},
+        Packset,
+    };
+    use futures::TryStreamExt;
+
+    /// Two backups, before (tree 1) and after (tree 10):
+    ///
+    ///   docs/a.pdf       unchanged
+    ///   docs/old/b.pdf   removed along with docs/old
+    ///   src/main.rs      modified
+    ///   src/lib.rs       added
+    ///   readme.txt       made executable
+    ///   same/x.txt       in an identical directory, whose tree is missing
+    async fn packset() -> Packset {
+        let trees = [
+            (
+                1,
+                vec![
+                    node("docs", true, 2).with_mode(0o755),
+                    node("readme.txt", false, 0xF0),
+                    node("same", true, 5).with_mode(0o755),
+                    node("src", true, 4).with_mode(0o755),
+                ],
+            ),
+            (
+                2,
+                vec![
+                    node("a.pdf", false, 0xF1),
+                    node("old", true, 3).with_mode(0o755),
+                ],
+            ),
+            (3, vec![node("b.pdf", false, 0xF2)]),
+            (4, vec![node("main.rs", false, 0xF4)]),
+            (
+                10,
+                vec![
+                    node("docs", true, 12).with_mode(0o755),
+                    node("readme.txt", false, 0xF0).with_mode(0o755),
+                    node("same", true, 5).with_mode(0o755),
+                    node("src", true, 14).with_mode(0o700),
+                ],
+            ),
+            (12, vec![node("a.pdf", false, 0xF1)]),
+            (
+                14,
+                vec![node("lib.rs", false, 0xF5), node("main.rs", false, 0xF6)],
+            ),
+        ];
+
+        tree_packset(&trees).await.1
+    }
+
+    async fn diff(old: &Commit<'_>, new: &Commit<'_>) -> Vec<(String, Change)> {
+        old.diff(new)
+            .map_ok(|e| (e.path.to_string_lossy().into_owned(), e.change))
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    fn changes(changes: &[(&str, Change)]) -> Vec<(String, Change)> {
+        changes.iter().map(|(p, c)| (p.to_string(), *c)).collect()
+    }
+
+    #[tokio::test]
+    async fn changes_are_found() {
+        let packset = packset().await;
+        let before = commit(&packset, 0xC1, 1);
+        let after = commit(&packset, 0xC2, 10);
+
+        assert_eq!(
+            diff(&before, &after).await,
+            changes(&[
+                ("docs/old", Change::Removed),
+                ("readme.txt", Change::MetadataChanged),
+                ("src", Change::MetadataChanged),
+                ("src/lib.rs", Change::Added),
+                ("src/main.rs", Change::Modified),
+            ])
+        );
+
+        assert_eq!(
+            diff(&after, &before).await,
+            changes(&[
+                ("docs/old", Change::Added),
+                ("readme.txt", Change::MetadataChanged),
+                ("src", Change::MetadataChanged),
+                ("src/lib.rs", Change::Removed),
+                ("src/main.rs", Change::Modified),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn identical_commits_have_no_changes() {
+        let packset = packset().await;
+        let before = commit(&packset, 0xC1, 1);
+        let again = commit(&packset, 0xC3, 1);
+        assert_eq!(diff(&before, &again).await, vec![]);
+
+        // Not even the root tree is needed
+        let missing = commit(&packset, 0xC4, 0x99);
+        assert_eq!(diff(&missing, &missing).await, vec![]);
+    }
+
+    #[tokio::test]
+    async fn entries_carry_both_nodes() {
+        let packset = packset().await;
+        let before = commit(&packset, 0xC1, 1);
+        let after = commit(&packset, 0xC2, 10);
+
+        let entries: Vec<_> = before.diff(&after).try_collect().await.unwrap();
+        let readme = &entries[1];
+        assert_eq!(readme.old.as_ref().map(|n| n.file_mode), Some(0o644));
+        assert_eq!(readme.new.as_ref().map(|n| n.file_mode), Some(0o755));
+
+        let removed = &entries[0];
+        assert!(removed.old.as_ref().unwrap().is_tree);
+        assert!(removed.new.is_none());
+    }
+}
@@ -1,111 +1,267 @@
+mod diff;
 mod record;
+mod search;
+mod walk;
 
 use std::{
-    path::PathBuf,
+    convert::TryFrom,
+    io::{self, Read, Write},
+    path::Path,
     sync::Arc,
 };
 
 use chrono::prelude::*;
-use glob::Pattern;
-use log::{error, info};
+use log::{error, warn};
+use serde::Serialize;
 
 use crate::{
-    compression::decompress,
-    crypto::ObjectDecrypter,
+    compression::decompressing_reader,
+    crypto::{BlobHasher, CryptoError, ObjectDecrypter, SecretBytes},
     storage::Store,
-    tree::{self, BlobKey, StorageType},
-    CompressionType, Packset, RepoError,
+    tree::{self, BlobKey, StorageType, Tree},
+    CompressionType, Packset, RepoError, TreeCache, SHA1,
 };
 
 use record::CommitRecord;
+pub use diff::{Change, DiffEntry};
+pub use record::FileError;
+pub use search::{FileQuery, FileSearch};
+pub(crate) use walk::PathLookup;
+pub use walk::{PathFilter, WalkEntry};
+
+/// How many trees a walk fetches at once unless told otherwise
+const DEFAULT_CONCURRENCY: usize = 8;
 
 pub struct Commit<'a> {
+    id: SHA1,
     record: CommitRecord,
     packset: &'a Packset,
     store: Arc<dyn Store>,
     decrypter: Arc<dyn ObjectDecrypter>,
+    blob_salt: Option<Arc<SecretBytes>>,
+    tree_cache: Option<Arc<dyn TreeCache>>,
+    concurrency: usize,
 }
 
 impl<'a> Commit<'a> {
     pub fn parse(
+        id: SHA1,
         blob: &[u8],
         packset: &'a Packset,
         decrypter: &Arc<dyn ObjectDecrypter>,
     ) -> Result<Self, RepoError> {
         let record = record::parse(blob)?;
         Ok(Commit {
+            id,
             record,
             packset,
             store: packset.store().clone(),
             decrypter: decrypter.clone(),
+            blob_salt: None,
+            tree_cache: None,
+            concurrency: DEFAULT_CONCURRENCY,
         })
     }
 
-    pub async fn list_files(&self, pattern: &str) -> Result<(), RepoError> {
-        let commit = &self.record;
-        let _patterns = parse_pattern(pattern)?;
+    /// Verifies the trees of this commit as they are loaded
+    pub(crate) fn with_blob_salt(self, salt: Option<Arc<SecretBytes>>) -> Self {
+        Commit {
+            blob_salt: salt,
+            ..self
+        }
+    }
 
-        struct Child {
-            //pattern_index: isize,
-            keys: Vec<BlobKey>,
-            path: PathBuf,
-            compression_type: CompressionType,
-        };
+    /// Looks for trees in `cache` before fetching them, and keeps the ones
+    /// that are fetched there
+    pub(crate) fn with_tree_cache(self, cache: Option<Arc<dyn TreeCache>>) -> Self {
+        Commit {
+            tree_cache: cache,
+            ..self
+        }
+    }
 
-        let root = Child {
-            //pattern_index: -1,
-            path: PathBuf::new(),
-            keys: vec![BlobKey {
-                sha: commit.tree_sha.clone(),
-                stretch_key: commit.expand_key,
-                storage_type: StorageType::S3,
-                size: None,
-                upload_date: None,
-            }],
-            compression_type: commit.compression_type,
-        };
+    /// Sets how many trees a walk may fetch at once. Entries come out in
+    /// the same order whatever the limit.
+    pub fn with_concurrency(self, limit: usize) -> Self {
+        Commit {
+            concurrency: limit.max(1),
+            ..self
+        }
+    }
 
-        let mut pending_children = vec![root];
+    /// The key of the tree at the root of the backup
+    fn root_key(&self) -> BlobKey {
+        BlobKey {
+            sha: self.record.tree_sha.clone(),
+            stretch_key: self.record.expand_key,
+            storage_type: StorageType::S3,
+            size: None,
+            upload_date: None,
+        }
+    }
 
-        while let Some(j) = pending_children.pop() {
-            info!("Loading child {:?}", j.keys);
-            let t = load_blob(
-                &self.packset,
-                &j.keys,
-                self.decrypter.as_ref(),
-                j.compression_type,
-            )
-            .await
-            .and_then(|d| {
-                tree::parse(&d)
-            })
-            .map_err(|e| {
-                error!("Parsing tree failed: {:?}", e);
-                e
-            })?;
-
-            for n in t.nodes {
-                if n.is_tree {
-                    let child = Child {
-                        //pattern_index: 0, // not used yet
-                        path: j.path.join(n.name),
-                        keys: n.data_blob_keys,
-                        compression_type: n.data_compression_type,
-                    };
-                    pending_children.push(child);
-                    continue;
-                }
+    /// Loads the tree describing the directory at `path`, from the tree
+    /// cache if it's there. With blob verification on, a cached tree that
+    /// doesn't match its SHA1 is fetched again.
+    async fn load_tree(
+        &self,
+        keys: &[BlobKey],
+        compression_type: CompressionType,
+        path: &Path,
+    ) -> Result<Tree, RepoError> {
+        // Arq only ever stores a tree as a single blob, and that's all the
+        // cache knows how to key
+        let cache = match keys {
+            [key] => self.tree_cache.as_ref().map(|cache| (cache, &key.sha)),
+            _ => None,
+        };
 
-                println!("{:?}: {} bytes", j.path.join(n.name), n.data_size);
+        if let Some((cache, sha)) = cache {
+            if let Some(data) = cache.load(sha) {
+                if !blob_matches(&data, sha, self.blob_salt.as_deref()) {
+                    warn!("Ignoring cached tree {} that doesn't match its SHA1", sha);
+                } else {
+                    match tree::parse(&data) {
+                        Ok(tree) => return Ok(tree),
+                        Err(_) => warn!("Ignoring unreadable cached tree {}", sha),
+                    }
+                }
             }
         }
 
-        Ok(())
+        let loaded = load_blob(
+            self.packset,
+            keys,
+            self.decrypter.as_ref(),
+            compression_type,
+            self.blob_salt.as_deref(),
+            path,
+        )
+        .await
+        .and_then(|data| tree::parse(&data).map(|tree| (data, tree)));
+
+        match loaded {
+            Ok((data, tree)) => {
+                if let Some((cache, sha)) = cache {
+                    cache.save(sha, &data);
+                }
+                Ok(tree)
+            }
+            Err(e) => {
+                error!("Loading the tree for {:?} failed: {:?}", path, e);
+                Err(e)
+            }
+        }
     }
 
     pub fn timestamp(&self) -> &DateTime<Utc> {
         &self.record.timestamp
     }
+
+    /// The SHA1 this commit is stored under
+    pub fn id(&self) -> &SHA1 {
+        &self.id
+    }
+
+    /// The version of the commit format
+    pub fn version(&self) -> usize {
+        self.record.version
+    }
+
+    pub fn author(&self) -> Option<&str> {
+        self.record.author.as_deref()
+    }
+
+    pub fn comment(&self) -> Option<&str> {
+        self.record.comment.as_deref()
+    }
+
+    /// The commits this one follows on from; normally just the previous
+    /// backup of the folder
+    pub fn parents(&self) -> impl Iterator<Item = &SHA1> + '_ {
+        self.record.parents.iter().map(|p| &p.id)
+    }
+
+    /// The parents, along with whether each is encrypted with a stretched
+    /// key
+    pub(crate) fn parent_keys(&self) -> impl Iterator<Item = (&SHA1, bool)> + '_ {
+        self.record.parents.iter().map(|p| (&p.id, p.expand_key))
+    }
+
+    /// The location of the backed-up folder, as a `file://` URL
+    pub fn path(&self) -> Option<&str> {
+        self.record.path.as_deref()
+    }
+
+    /// The files Arq failed to back up
+    pub fn file_errors(&self) -> &[FileError] {
+        &self.record.file_errors
+    }
+
+    /// Whether Arq couldn't find some of the nodes it expected. `None` for
+    /// commits older than format version 8.
+    pub fn missing_nodes(&self) -> Option<bool> {
+        self.record.missing_nodes
+    }
+
+    /// Whether the backup ran to completion. `None` for commits older than
+    /// format version 9.
+    pub fn is_complete(&self) -> Option<bool> {
+        self.record.is_complete
+    }
+
+    /// The bucket plist of the folder at the time of the backup, if the
+    /// commit has one
+    pub fn bucket_plist(&self) -> Option<&[u8]> {
+        Some(&self.record.plist[..]).filter(|p| !p.is_empty())
+    }
+
+    /// The version of Arq that made the backup. Only recorded from commit
+    /// format version 12.
+    pub fn arq_version(&self) -> Option<&str> {
+        self.record.arq_version.as_deref()
+    }
+
+    pub fn summary(&self) -> CommitSummary {
+        CommitSummary::new(&self.id, &self.record)
+    }
+}
+
+/**
+ * The metadata of a commit, detached from the repository so that it can be
+ * kept around or serialised for reporting.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CommitSummary {
+    pub id: SHA1,
+    pub version: usize,
+    pub timestamp: DateTime<Utc>,
+    pub author: Option<String>,
+    pub comment: Option<String>,
+    pub parents: Vec<SHA1>,
+    pub path: Option<String>,
+    pub arq_version: Option<String>,
+    pub is_complete: Option<bool>,
+    pub missing_nodes: Option<bool>,
+    pub file_errors: Vec<FileError>,
+}
+
+impl CommitSummary {
+    fn new(id: &SHA1, record: &CommitRecord) -> CommitSummary {
+        CommitSummary {
+            id: id.clone(),
+            version: record.version,
+            timestamp: record.timestamp,
+            author: record.author.clone(),
+            comment: record.comment.clone(),
+            parents: record.parents.iter().map(|p| p.id.clone()).collect(),
+            path: record.path.clone(),
+            arq_version: record.arq_version.clone(),
+            is_complete: record.is_complete,
+            missing_nodes: record.missing_nodes,
+            file_errors: record.file_errors.clone(),
+        }
+    }
 }
 
 async fn load_blob(
@@ -113,10 +269,12 @@ async fn load_blob(
     keys: &[BlobKey],
     decrypter: &dyn ObjectDecrypter,
     compression_type: CompressionType,
+    blob_salt: Option<&SecretBytes>,
+    path: &Path,
 ) -> Result<Vec<u8>, RepoError> {
-    let fetch_tasks = keys
-        .iter()
-        .map(|k| load_blob_fragment(packset, k, decrypter, compression_type));
+    let fetch_tasks = keys.iter().map(|k| {
+        load_blob_fragment(packset, k, decrypter, compression_type, blob_salt, path)
+    });
     let blobs = futures::future::try_join_all(fetch_tasks).await?;
     let overall_len = blobs.iter().fold(0, |acc, x| acc + x.len());
     let mut result = Vec::with_capacity(overall_len);
@@ -132,27 +290,256 @@ async fn load_blob_fragment(
     key: &BlobKey,
     decrypter: &dyn ObjectDecrypter,
     compression_type: CompressionType,
+    blob_salt: Option<&SecretBytes>,
+    path: &Path,
 ) -> Result<Vec<u8>, RepoError> {
     let encrypted_object = packset.load(&key.sha).await?;
+    let mut result = Vec::new();
+    unpack_blob(
+        &encrypted_object.content,
+        key,
+        decrypter,
+        compression_type,
+        blob_salt,
+        path,
+        &mut result,
+    )?;
+    Ok(result)
+}
+
+/// Unpacks a blob into `out`. If a salt is given, also checks that the
+/// blob's data hashes to its SHA1. The data has been written by the time
+/// that is known, so a `RepoError::CorruptBlob` means discarding whatever
+/// was written.
+pub(crate) fn unpack_blob(
+    object: &[u8],
+    key: &BlobKey,
+    decrypter: &dyn ObjectDecrypter,
+    compression_type: CompressionType,
+    blob_salt: Option<&SecretBytes>,
+    path: &Path,
+    out: &mut dyn Write,
+) -> Result<u64, RepoError> {
+    let mut hasher = blob_salt.map(|salt| BlobHasher::new(salt.as_bytes()));
+    let written = unpack_object(object, decrypter, compression_type, hasher.as_mut(), out)?;
+
+    if let Some(hasher) = hasher {
+        let sha = SHA1::try_from(&hasher.finish()[..]).map_err(|_| RepoError::MalformedData)?;
+        if sha != key.sha {
+            error!("Blob {} of {:?} hashes to {}", key.sha, path, sha);
+            return Err(RepoError::CorruptBlob {
+                sha: key.sha.clone(),
+                path: path.to_owned(),
+            });
+        }
+    }
+    Ok(written)
+}
 
-    let decrypted_object = decrypter
-        .decrypt_object(&encrypted_object.content)
+/// Checks data that has already been unpacked, e.g. from a tree cache,
+/// against the SHA1 it is stored under. Without a salt there's nothing to
+/// check it with, and it's taken as it is.
+pub(crate) fn blob_matches(data: &[u8], sha: &SHA1, blob_salt: Option<&SecretBytes>) -> bool {
+    let salt = match blob_salt {
+        Some(salt) => salt,
+        None => return true,
+    };
+    let mut hasher = BlobHasher::new(salt.as_bytes());
+    hasher.update(data);
+    SHA1::try_from(&hasher.finish()[..]).ok().as_ref() == Some(sha)
+}
+
+/// Decrypts and decompresses a stored object straight into `out`, so that
+/// only the encrypted object is ever held in memory in full. Returns the
+/// number of bytes written.
+fn unpack_object(
+    object: &[u8],
+    decrypter: &dyn ObjectDecrypter,
+    compression_type: CompressionType,
+    mut hasher: Option<&mut BlobHasher>,
+    out: &mut dyn Write,
+) -> Result<u64, RepoError> {
+    let decrypted = decrypter
+        .decrypt_stream(Box::new(object))
         .map_err(|_| RepoError::CryptoError)?;
-    drop(encrypted_object);
+    let mut reader = decompressing_reader(decrypted, compression_type);
 
-    if compression_type == CompressionType::None {
-        Ok(decrypted_object)
+    // Can't use io::copy, as errors reading the object and errors writing
+    // the output mean very different things
+    let mut buf = vec![0; 64 * 1024];
+    let mut written = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(written),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(read_error(e, object, decrypter)),
+        };
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        out.write_all(&buf[..n])
+            .map_err(|e| RepoError::Io(e.kind()))?;
+        written += n as u64;
+    }
+}
+
+fn read_error(err: io::Error, object: &[u8], decrypter: &dyn ObjectDecrypter) -> RepoError {
+    if err.get_ref().is_some_and(|e| e.is::<CryptoError>()) {
+        return RepoError::CryptoError;
+    }
+
+    // The integrity of a stream is only checked once it has all been read,
+    // so a tampered object usually trips up the decompressor first. Finish
+    // decrypting it to tell the two apart.
+    let intact = decrypter
+        .decrypt_stream(Box::new(object))
+        .map(|mut r| io::copy(&mut r, &mut io::sink()).is_ok())
+        .unwrap_or(false);
+    if intact {
+        RepoError::MalformedData
     } else {
-        decompress(&decrypted_object, compression_type)
+        RepoError::CryptoError
     }
 }
 
-fn parse_pattern(pattern_text: &str) -> Result<Vec<glob::Pattern>, RepoError> {
-    let components = pattern_text.split_terminator(std::path::is_separator);
-    let mut result = Vec::new();
-    for component in components {
-        let p = Pattern::new(component).map_err(|_| RepoError::InputError)?;
-        result.push(p);
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        crypto::{ObjectDecrypterV2, ObjectEncrypter, ObjectEncrypterV2},
+        mocks::keys,
+    };
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    struct BrokenWriter;
+
+    impl Write for BrokenWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WriteZero.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn objects_are_unpacked_into_a_writer() {
+        let encrypter = ObjectEncrypterV2::new(keys());
+        let decrypter = ObjectDecrypterV2::new(keys());
+        let data: Vec<u8> = (0..300_000u32).map(|n| (n % 97) as u8).collect();
+
+        let object = encrypter.encrypt_object(&gzip(&data)).ok().unwrap();
+        let mut out = Vec::new();
+        let n = unpack_object(&object, &decrypter, CompressionType::GZip, None, &mut out);
+        assert_eq!(n, Ok(data.len() as u64));
+        assert_eq!(out, data);
+
+        let object = encrypter.encrypt_object(&data).ok().unwrap();
+        let mut out = Vec::new();
+        let n = unpack_object(&object, &decrypter, CompressionType::None, None, &mut out);
+        assert_eq!(n, Ok(data.len() as u64));
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn unpacking_errors_are_distinguished() {
+        let encrypter = ObjectEncrypterV2::new(keys());
+        let decrypter = ObjectDecrypterV2::new(keys());
+        let object = encrypter.encrypt_object(&gzip(b"some data")).ok().unwrap();
+
+        let mut tampered = object.clone();
+        let n = tampered.len() - 20;
+        tampered[n] ^= 1;
+        assert_eq!(
+            unpack_object(&tampered, &decrypter, CompressionType::GZip, None, &mut Vec::new()),
+            Err(RepoError::CryptoError)
+        );
+
+        let not_lz4 = encrypter.encrypt_object(b"nonsense").ok().unwrap();
+        assert_eq!(
+            unpack_object(&not_lz4, &decrypter, CompressionType::LZ4, None, &mut Vec::new()),
+            Err(RepoError::MalformedData)
+        );
+
+        assert_eq!(
+            unpack_object(&object, &decrypter, CompressionType::GZip, None, &mut BrokenWriter),
+            Err(RepoError::Io(io::ErrorKind::WriteZero))
+        );
+    }
+
+    fn blob_key(data: &[u8], salt: &[u8]) -> BlobKey {
+        let mut hasher = BlobHasher::new(salt);
+        hasher.update(data);
+        BlobKey {
+            sha: SHA1::try_from(&hasher.finish()[..]).unwrap(),
+            stretch_key: false,
+            storage_type: StorageType::S3,
+            size: None,
+            upload_date: None,
+        }
+    }
+
+    #[test]
+    fn blobs_are_checked_against_their_sha1() {
+        let encrypter = ObjectEncrypterV2::new(keys());
+        let decrypter = ObjectDecrypterV2::new(keys());
+        let salt = SecretBytes::from_slice(&[3; 32]);
+        let path = Path::new("Users/x/report.txt");
+
+        let data = b"the quarterly numbers".to_vec();
+        let object = encrypter.encrypt_object(&gzip(&data)).ok().unwrap();
+        let unpack = |key: &BlobKey, salt: Option<&SecretBytes>| {
+            let mut out = Vec::new();
+            unpack_blob(
+                &object,
+                key,
+                &decrypter,
+                CompressionType::GZip,
+                salt,
+                path,
+                &mut out,
+            )
+            .map(|_| out)
+        };
+
+        let key = blob_key(&data, salt.as_bytes());
+        assert_eq!(unpack(&key, Some(&salt)), Ok(data.clone()));
+
+        // Salted with something else, e.g. the computer UUID
+        let wrong_key = blob_key(&data, b"600150F6-70BB-47C6-A538-6F3A2258D524");
+        assert_eq!(
+            unpack(&wrong_key, Some(&salt)),
+            Err(RepoError::CorruptBlob {
+                sha: wrong_key.sha.clone(),
+                path: path.to_owned(),
+            })
+        );
+
+        // Without a salt, nothing is checked
+        assert_eq!(unpack(&wrong_key, None), Ok(data));
+    }
+
+    #[test]
+    fn summaries_serialise() {
+        let record = record::parse(include_bytes!("commit.blob")).unwrap();
+        let id = SHA1::try_from("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let summary = CommitSummary::new(&id, &record);
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["id"], "0123456789abcdef0123456789abcdef01234567");
+        assert_eq!(json["version"], 9);
+        assert_eq!(json["author"], "trent");
+        assert_eq!(json["parents"][0], "f5f17fbb8c5662f6682785c8c457ff0f5570e836");
+        assert_eq!(json["is_complete"], true);
+        assert_eq!(json["arq_version"], serde_json::Value::Null);
+        assert_eq!(json["file_errors"], serde_json::json!([]));
+        assert_eq!(json["timestamp"], "2015-10-31T04:46:50.000480Z");
     }
-    Ok(result)
 }
@@ -9,30 +9,33 @@ use nom::{
     IResult,
 };
 
+use serde::Serialize;
+
 use crate::{constructs::*, CompressionType, RepoError, SHA1};
 
-#[derive(Debug)]
-struct FileError {
-    filename: String,
-    error: String,
+/// A file that Arq failed to back up, and why
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileError {
+    pub filename: String,
+    pub error: String,
 }
 
 #[derive(Debug)]
 pub struct CommitRecord {
-    version: usize,
-    author: Option<String>,
-    comment: Option<String>,
-    parents: Vec<ParentKey>,
+    pub version: usize,
+    pub author: Option<String>,
+    pub comment: Option<String>,
+    pub parents: Vec<ParentKey>,
     pub tree_sha: SHA1,
     pub expand_key: bool,
     pub compression_type: CompressionType,
-    path: Option<String>,
+    pub path: Option<String>,
     pub timestamp: DateTime<Utc>,
-    file_errors: Vec<FileError>,
-    missing_nodes: Option<bool>,
-    is_complete: Option<bool>,
-    plist: Vec<u8>,
-    arq_version: Option<String>,
+    pub file_errors: Vec<FileError>,
+    pub missing_nodes: Option<bool>,
+    pub is_complete: Option<bool>,
+    pub plist: Vec<u8>,
+    pub arq_version: Option<String>,
 }
 
 fn file_error(i: &[u8]) -> IResult<&[u8], FileError> {
@@ -47,8 +50,8 @@ fn file_errors(i: &[u8]) -> IResult<&[u8], Vec<FileError>> {
 
 #[derive(Debug)]
 pub struct ParentKey {
-    id: SHA1,
-    expand_key: bool,
+    pub id: SHA1,
+    pub expand_key: bool,
 }
 
 fn parent_key(commit_version: usize) -> impl FnMut(&[u8]) -> IResult<&[u8], ParentKey> {
@@ -118,5 +121,16 @@ mod test {
     fn test_parse_v9() {
         let (_, c) = super::commit_record(COMMIT_V9).expect("Parsing commit should succeed");
         assert_eq!(c.version, 9);
+        assert_eq!(c.author.as_deref(), Some("trent"));
+        assert_eq!(c.comment.as_deref(), Some("complete Commit"));
+        assert_eq!(c.parents.len(), 1);
+        assert_eq!(
+            c.parents[0].id.to_string(),
+            "f5f17fbb8c5662f6682785c8c457ff0f5570e836"
+        );
+        assert_eq!(c.missing_nodes, Some(false));
+        assert_eq!(c.is_complete, Some(true));
+        assert!(c.file_errors.is_empty());
+        assert_eq!(c.arq_version, None);
     }
 }
@@ -0,0 +1,378 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc, vec};
+
+use chrono::{DateTime, Utc};
+use glob::Pattern;
+
+use crate::{tree::Node, RepoError, SHA1};
+
+use super::{Commit, WalkEntry};
+
+/**
+ * Describes the files a `FileSearch` is after. Directories never match.
+ * Every condition that has been set must hold, and a query with none set
+ * matches every file.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct FileQuery {
+    name: Option<Pattern>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_after: Option<DateTime<Utc>>,
+    modified_before: Option<DateTime<Utc>>,
+}
+
+impl FileQuery {
+    pub fn new() -> FileQuery {
+        FileQuery::default()
+    }
+
+    /// Only matches files whose name, without the directories above it,
+    /// matches the glob `pattern`, e.g. `*.xlsx`
+    pub fn with_name(self, pattern: &str) -> Result<FileQuery, RepoError> {
+        let name = Pattern::new(pattern).map_err(|_| RepoError::InputError)?;
+        Ok(FileQuery {
+            name: Some(name),
+            ..self
+        })
+    }
+
+    /// Only matches files of at least `min` and at most `max` bytes
+    pub fn with_size(self, min: Option<u64>, max: Option<u64>) -> FileQuery {
+        FileQuery {
+            min_size: min,
+            max_size: max,
+            ..self
+        }
+    }
+
+    /// Only matches files last modified at or after `after`, and before
+    /// `before`
+    pub fn with_mod_time(
+        self,
+        after: Option<DateTime<Utc>>,
+        before: Option<DateTime<Utc>>,
+    ) -> FileQuery {
+        FileQuery {
+            modified_after: after,
+            modified_before: before,
+            ..self
+        }
+    }
+
+    pub fn matches(&self, node: &Node) -> bool {
+        !node.is_tree
+            && self.name.iter().all(|p| p.matches(&node.name))
+            && self.min_size.iter().all(|&min| node.data_size >= min)
+            && self.max_size.iter().all(|&max| node.data_size <= max)
+            && self.modified_after.iter().all(|&t| node.mod_time >= t)
+            && self.modified_before.iter().all(|&t| node.mod_time < t)
+    }
+}
+
+/**
+ * Searches any number of commits, even from different folders, for the
+ * files a query describes. What was found below each tree is remembered,
+ * so a tree that turns up again in a later commit is answered without
+ * loading it or anything under it. Each match is kept once, in the tree
+ * holding it, however many trees above it are remembered.
+ */
+pub struct FileSearch {
+    query: FileQuery,
+
+    /// What was found in each tree searched so far, keyed by its SHA1
+    found: HashMap<SHA1, Found>,
+}
+
+impl FileSearch {
+    pub fn new(query: FileQuery) -> FileSearch {
+        FileSearch {
+            query,
+            found: HashMap::new(),
+        }
+    }
+}
+
+/// The matching files in a tree and the directories with matches below
+/// them, in the order Arq stored them
+type Found = Arc<Vec<Match>>;
+
+enum Match {
+    File(Box<Node>),
+    Dir(String, Found),
+}
+
+/// A directory that is being searched
+struct Dir {
+    name: String,
+
+    /// The SHA1 its matches will be remembered under, if it has a single one
+    sha: Option<SHA1>,
+    nodes: vec::IntoIter<Node>,
+    found: Vec<Match>,
+}
+
+fn tree_sha(node: &Node) -> Option<SHA1> {
+    match node.data_blob_keys.as_slice() {
+        [key] => Some(key.sha.clone()),
+        _ => None,
+    }
+}
+
+/// Lists the files in `found` and the directories below it, depth first
+fn entries(found: &Found) -> Vec<WalkEntry> {
+    let mut entries = Vec::new();
+    let mut path = PathBuf::new();
+    let mut dirs = vec![found.iter()];
+    while let Some(dir) = dirs.last_mut() {
+        match dir.next() {
+            Some(Match::File(node)) => entries.push(WalkEntry {
+                path: path.join(&node.name),
+                depth: dirs.len() - 1,
+                node: node.as_ref().clone(),
+            }),
+            Some(Match::Dir(name, found)) => {
+                path.push(name);
+                dirs.push(found.iter());
+            }
+            None => {
+                dirs.pop();
+                path.pop();
+            }
+        }
+    }
+    entries
+}
+
+impl<'a> Commit<'a> {
+    /// Finds every file in the commit that `search` is looking for, depth
+    /// first, with the entries of each directory in the order Arq stored
+    /// them. Trees that `search` has been through before aren't loaded
+    /// again.
+    pub async fn search(&self, search: &mut FileSearch) -> Result<Vec<WalkEntry>, RepoError> {
+        let found = match search.found.get(&self.record.tree_sha) {
+            Some(found) => found.clone(),
+            None => self.search_tree(search).await?,
+        };
+        Ok(entries(&found))
+    }
+
+    async fn search_tree(&self, search: &mut FileSearch) -> Result<Found, RepoError> {
+        let mut path = PathBuf::new();
+        let root = self
+            .load_tree(&[self.root_key()], self.record.compression_type, &path)
+            .await?;
+        let mut dirs = vec![Dir {
+            name: String::new(),
+            sha: Some(self.record.tree_sha.clone()),
+            nodes: root.nodes.into_iter(),
+            found: Vec::new(),
+        }];
+
+        loop {
+            let dir = dirs.last_mut().unwrap();
+            let node = match dir.nodes.next() {
+                Some(node) => node,
+                None => {
+                    let done = dirs.pop().unwrap();
+                    let found = Arc::new(done.found);
+                    if let Some(sha) = done.sha {
+                        search.found.insert(sha, found.clone());
+                    }
+                    match dirs.last_mut() {
+                        Some(parent) if !found.is_empty() => {
+                            parent.found.push(Match::Dir(done.name, found))
+                        }
+                        Some(_) => {}
+                        None => return Ok(found),
+                    }
+                    path.pop();
+                    continue;
+                }
+            };
+
+            if !node.is_tree {
+                if search.query.matches(&node) {
+                    dir.found.push(Match::File(Box::new(node)));
+                }
+                continue;
+            }
+
+            let sha = tree_sha(&node);
+            if let Some(found) = sha.as_ref().and_then(|sha| search.found.get(sha)) {
+                if !found.is_empty() {
+                    dir.found.push(Match::Dir(node.name, found.clone()));
+                }
+                continue;
+            }
+
+            path.push(&node.name);
+            let tree = self
+                .load_tree(&node.data_blob_keys, node.data_compression_type, &path)
+                .await?;
+            dirs.push(Dir {
+                name: node.name,
+                sha,
+                nodes: tree.nodes.into_iter(),
+                found: Vec::new(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        mocks::{commit, node, sha, tree_packset, MemoryStore},
+        Packset,
+    };
+    use chrono::prelude::*;
+    use std::sync::{atomic::Ordering, Arc};
+
+    /// Two backups, the second (tree 10) adding notes.pdf and otherwise
+    /// sharing the first's (tree 1) directories:
+    ///
+    ///   docs/a.pdf         1000 bytes
+    ///   docs/old/b.pdf     10 bytes
+    ///   docs/old/c.txt
+    ///   notes.pdf          100 bytes
+    ///   readme.txt
+    async fn packset() -> (Arc<MemoryStore>, Packset) {
+        let trees = [
+            (
+                1,
+                vec![
+                    node("docs", true, 2),
+                    node("readme.txt", false, 0xF0).with_size(5),
+                ],
+            ),
+            (
+                2,
+                vec![
+                    node("a.pdf", false, 0xF1).with_size(1000),
+                    node("old", true, 3),
+                ],
+            ),
+            (
+                3,
+                vec![
+                    node("b.pdf", false, 0xF2).with_size(10),
+                    node("c.txt", false, 0xF3).with_size(10),
+                ],
+            ),
+            (
+                10,
+                vec![
+                    node("docs", true, 2),
+                    node("notes.pdf", false, 0xF4).with_size(100),
+                    node("readme.txt", false, 0xF0).with_size(5),
+                ],
+            ),
+        ];
+
+        tree_packset(&trees).await
+    }
+
+    async fn paths(commit: &Commit<'_>, search: &mut FileSearch) -> Vec<String> {
+        commit
+            .search(search)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.path.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn files_are_found_by_name_and_size() {
+        let (_, packset) = packset().await;
+        let before = commit(&packset, 0xC1, 1);
+        let after = commit(&packset, 0xC2, 10);
+
+        let query = FileQuery::new().with_name("*.pdf").unwrap();
+        let mut search = FileSearch::new(query.clone());
+        assert_eq!(
+            paths(&before, &mut search).await,
+            vec!["docs/a.pdf", "docs/old/b.pdf"]
+        );
+        assert_eq!(
+            paths(&after, &mut search).await,
+            vec!["docs/a.pdf", "docs/old/b.pdf", "notes.pdf"]
+        );
+
+        let mut search = FileSearch::new(query.with_size(Some(10), Some(100)));
+        assert_eq!(
+            paths(&after, &mut search).await,
+            vec!["docs/old/b.pdf", "notes.pdf"]
+        );
+
+        let entries = after.search(&mut search).await.unwrap();
+        assert_eq!(entries[0].depth, 2);
+        assert_eq!(entries[1].depth, 0);
+
+        assert_eq!(
+            FileQuery::new().with_name("[").unwrap_err(),
+            RepoError::InputError
+        );
+    }
+
+    #[tokio::test]
+    async fn files_are_found_by_mod_time() {
+        let (_, packset) = packset().await;
+        let before = commit(&packset, 0xC1, 1);
+
+        // The test trees are all modified at the epoch
+        let epoch = Utc.timestamp(0, 0);
+        let later = Utc.timestamp(1, 0);
+        let mut search = FileSearch::new(FileQuery::new().with_mod_time(Some(epoch), Some(later)));
+        assert_eq!(paths(&before, &mut search).await.len(), 4);
+
+        let mut search = FileSearch::new(FileQuery::new().with_mod_time(Some(later), None));
+        assert_eq!(paths(&before, &mut search).await.len(), 0);
+        let mut search = FileSearch::new(FileQuery::new().with_mod_time(None, Some(epoch)));
+        assert_eq!(paths(&before, &mut search).await.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn shared_trees_are_searched_once() {
+        let (store, packset) = packset().await;
+        let before = commit(&packset, 0xC1, 1);
+        let after = commit(&packset, 0xC2, 10);
+        let again = commit(&packset, 0xC3, 1);
+        let mut search = FileSearch::new(FileQuery::new());
+
+        let gets = || store.gets.load(Ordering::SeqCst);
+        let start = gets();
+        paths(&before, &mut search).await;
+        assert_eq!(gets() - start, 3);
+
+        // Only the new root tree is needed, then nothing at all
+        let start = gets();
+        assert_eq!(paths(&after, &mut search).await.len(), 5);
+        assert_eq!(gets() - start, 1);
+
+        let start = gets();
+        assert_eq!(
+            paths(&again, &mut search).await,
+            vec![
+                "docs/a.pdf",
+                "docs/old/b.pdf",
+                "docs/old/c.txt",
+                "readme.txt"
+            ]
+        );
+        assert_eq!(gets() - start, 0);
+
+        // Both roots hold the docs tree's matches rather than copies
+        let docs = &search.found[&sha(2)];
+        for root in [sha(1), sha(10)].iter() {
+            match search.found[root].first() {
+                Some(Match::Dir(name, found)) => {
+                    assert_eq!(name, "docs");
+                    assert!(Arc::ptr_eq(found, docs));
+                }
+                _ => panic!("{} doesn't start with docs", root),
+            }
+        }
+    }
+}
@@ -0,0 +1,821 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{self, is_separator, Path, PathBuf},
+    str::FromStr,
+    vec,
+};
+
+use futures::{
+    future::{FutureExt, LocalBoxFuture},
+    stream::{self, FuturesUnordered, StreamExt},
+    Stream,
+};
+use glob::Pattern;
+use log::debug;
+
+use crate::{
+    storage,
+    tree::{BlobKey, Node, Tree},
+    CompressionType, RepoError, SHA1,
+};
+
+use super::Commit;
+
+#[derive(Debug, Clone)]
+enum Component {
+    /// `**`, which matches any number of path components, including none
+    AnyDepth,
+
+    /// A glob matched against a single file name
+    Name(Pattern),
+}
+
+/**
+ * Matches paths one component at a time, so that a walk can tell when
+ * nothing below a directory could match and skip loading it altogether.
+ * `Documents/**/*.pdf` matches every PDF anywhere under `Documents`, and an
+ * empty pattern matches everything.
+ */
+#[derive(Debug, Clone)]
+pub struct PathFilter {
+    components: Vec<Component>,
+}
+
+/// The positions in a filter's components that the path so far has reached,
+/// in ascending order
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MatchState(Vec<usize>);
+
+impl PathFilter {
+    pub fn new(pattern: &str) -> Result<PathFilter, RepoError> {
+        let components = pattern
+            .split(is_separator)
+            .filter(|c| !c.is_empty())
+            .map(|c| match c {
+                "**" => Ok(Component::AnyDepth),
+                _ => Pattern::new(c)
+                    .map(Component::Name)
+                    .map_err(|_| RepoError::InputError),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if components.is_empty() {
+            return Ok(PathFilter::all());
+        }
+        Ok(PathFilter { components })
+    }
+
+    pub fn all() -> PathFilter {
+        PathFilter {
+            components: vec![Component::AnyDepth],
+        }
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        let mut state = self.start();
+        for component in path.iter() {
+            state = self.step(&state, &component.to_string_lossy());
+        }
+        self.is_match(&state)
+    }
+
+    fn start(&self) -> MatchState {
+        self.closure(vec![0])
+    }
+
+    /// Adds the positions reachable by letting a `**` match nothing
+    fn closure(&self, mut positions: Vec<usize>) -> MatchState {
+        let mut i = 0;
+        while i < positions.len() {
+            let p = positions[i];
+            if let Some(Component::AnyDepth) = self.components.get(p) {
+                positions.push(p + 1);
+            }
+            i += 1;
+        }
+        positions.sort_unstable();
+        positions.dedup();
+        MatchState(positions)
+    }
+
+    /// Moves on past one more path component
+    fn step(&self, state: &MatchState, name: &str) -> MatchState {
+        let next = state
+            .0
+            .iter()
+            .filter_map(|&p| match self.components.get(p) {
+                Some(Component::AnyDepth) => Some(p),
+                Some(Component::Name(pattern)) if pattern.matches(name) => Some(p + 1),
+                _ => None,
+            })
+            .collect();
+        self.closure(next)
+    }
+
+    fn is_match(&self, state: &MatchState) -> bool {
+        state.0.last() == Some(&self.components.len())
+    }
+
+    /// Whether a path that carries on from here could ever match
+    fn can_descend(&self, state: &MatchState) -> bool {
+        state
+            .0
+            .first()
+            .is_some_and(|&p| p < self.components.len())
+    }
+}
+
+impl Default for PathFilter {
+    fn default() -> Self {
+        PathFilter::all()
+    }
+}
+
+impl FromStr for PathFilter {
+    type Err = RepoError;
+
+    fn from_str(s: &str) -> Result<PathFilter, RepoError> {
+        PathFilter::new(s)
+    }
+}
+
+/// A file or directory found by `Commit::walk` or `Commit::search`
+#[derive(Debug)]
+pub struct WalkEntry {
+    /// The path relative to the root of the backup
+    pub path: PathBuf,
+
+    pub node: Node,
+
+    /// The number of directories between the root and the entry, so 0 for
+    /// the entries at the top level
+    pub depth: usize,
+}
+
+/// A directory that has been loaded and is being worked through, along with
+/// where each of its entries leaves the filter
+struct Dir {
+    path: PathBuf,
+    depth: usize,
+    nodes: vec::IntoIter<(Node, MatchState)>,
+}
+
+/// A directory that needs to be loaded before the walk can go on
+struct PendingDir {
+    path: PathBuf,
+    depth: usize,
+    state: MatchState,
+    keys: Vec<BlobKey>,
+    compression_type: CompressionType,
+
+    /// Identifies the tree's fetch once it has been started
+    fetch: Option<usize>,
+}
+
+type TreeFetch<'c> = LocalBoxFuture<'c, (usize, Result<Tree, RepoError>)>;
+
+struct Walk<'c> {
+    filter: PathFilter,
+    pending: Option<PendingDir>,
+    dirs: Vec<Dir>,
+
+    /// The directories the walk will descend into, in the order it will
+    /// reach them. Fetches are started from the front, so that at most
+    /// `limit` trees are being fetched or waiting to be used at any time.
+    upcoming: VecDeque<PendingDir>,
+    limit: usize,
+    fetches: FuturesUnordered<TreeFetch<'c>>,
+    fetched: HashMap<usize, Result<Tree, RepoError>>,
+    next_fetch: usize,
+}
+
+impl<'c> Walk<'c> {
+    fn start_fetch(&mut self, commit: &'c Commit<'_>, dir: &mut PendingDir) {
+        let id = self.next_fetch;
+        self.next_fetch += 1;
+        dir.fetch = Some(id);
+
+        let keys = dir.keys.clone();
+        let compression_type = dir.compression_type;
+        let path = dir.path.clone();
+        self.fetches.push(
+            async move { (id, commit.load_tree(&keys, compression_type, &path).await) }
+                .boxed_local(),
+        );
+    }
+
+    /// Starts fetching upcoming trees, nearest first, while there is room
+    fn prefetch(&mut self, commit: &'c Commit<'_>) {
+        let mut upcoming = std::mem::take(&mut self.upcoming);
+        for dir in upcoming.iter_mut() {
+            if self.fetches.len() + self.fetched.len() >= self.limit {
+                break;
+            }
+            if dir.fetch.is_none() {
+                self.start_fetch(commit, dir);
+            }
+        }
+        self.upcoming = upcoming;
+    }
+
+    /// Waits for the tree of a directory, fetching it now if it hasn't
+    /// been started yet
+    async fn load(
+        &mut self,
+        commit: &'c Commit<'_>,
+        dir: &mut PendingDir,
+    ) -> Result<Tree, RepoError> {
+        if dir.fetch.is_none() {
+            self.start_fetch(commit, dir);
+        }
+        let id = dir.fetch.unwrap();
+
+        loop {
+            if let Some(result) = self.fetched.remove(&id) {
+                return result;
+            }
+            match self.fetches.next().await {
+                Some((done, result)) => {
+                    self.fetched.insert(done, result);
+                }
+                None => unreachable!("tree fetch {} was lost", id),
+            }
+        }
+    }
+
+    /// Moves into a freshly loaded directory, queueing up the directories
+    /// inside it that the walk will need ahead of everything else
+    fn enter(&mut self, commit: &'c Commit<'_>, dir: PendingDir, tree: Tree) {
+        let nodes: Vec<_> = tree
+            .nodes
+            .into_iter()
+            .map(|node| {
+                let state = self.filter.step(&dir.state, &node.name);
+                (node, state)
+            })
+            .collect();
+
+        let children = nodes
+            .iter()
+            .filter(|(node, state)| node.is_tree && self.filter.can_descend(state))
+            .map(|(node, state)| PendingDir {
+                path: dir.path.join(&node.name),
+                depth: dir.depth + 1,
+                state: state.clone(),
+                keys: node.data_blob_keys.clone(),
+                compression_type: node.data_compression_type,
+                fetch: None,
+            })
+            .collect::<Vec<_>>();
+        for child in children.into_iter().rev() {
+            self.upcoming.push_front(child);
+        }
+
+        self.dirs.push(Dir {
+            path: dir.path,
+            depth: dir.depth,
+            nodes: nodes.into_iter(),
+        });
+        self.prefetch(commit);
+    }
+}
+
+impl<'a> Commit<'a> {
+    /// Walks the backed-up files and directories whose paths match
+    /// `filter`, depth first, with each directory before its contents and
+    /// the entries of a directory in the order Arq stored them. Trees are
+    /// only loaded when something inside them could match, and up to the
+    /// commit's concurrency limit of them are fetched ahead of the walk. An
+    /// error ends the stream.
+    pub fn walk(
+        &self,
+        filter: PathFilter,
+    ) -> impl Stream<Item = Result<WalkEntry, RepoError>> + '_ {
+        let root = PendingDir {
+            path: PathBuf::new(),
+            depth: 0,
+            state: filter.start(),
+            keys: vec![self.root_key()],
+            compression_type: self.record.compression_type,
+            fetch: None,
+        };
+        let walk = Walk {
+            filter,
+            pending: Some(root),
+            dirs: Vec::new(),
+            upcoming: VecDeque::new(),
+            limit: self.concurrency,
+            fetches: FuturesUnordered::new(),
+            fetched: HashMap::new(),
+            next_fetch: 0,
+        };
+
+        stream::try_unfold(walk, move |mut walk| async move {
+            loop {
+                if let Some(mut pending) = walk.pending.take() {
+                    let tree = walk.load(self, &mut pending).await?;
+                    walk.enter(self, pending, tree);
+                }
+
+                let dir = match walk.dirs.last_mut() {
+                    Some(dir) => dir,
+                    None => return Ok(None),
+                };
+                let (node, state) = match dir.nodes.next() {
+                    Some(next) => next,
+                    None => {
+                        walk.dirs.pop();
+                        continue;
+                    }
+                };
+
+                let path = dir.path.join(&node.name);
+                let depth = dir.depth;
+
+                if node.is_tree && walk.filter.can_descend(&state) {
+                    // This is always the next of the upcoming directories
+                    walk.pending = walk.upcoming.pop_front();
+                }
+
+                if walk.filter.is_match(&state) {
+                    let entry = WalkEntry { path, node, depth };
+                    return Ok(Some((entry, walk)));
+                }
+            }
+        })
+    }
+
+    /// Finds the file or directory at `path`, relative to the root of the
+    /// backup, loading only the trees along the way. A path that doesn't
+    /// exist, or that runs through a file, is `NoSuchObject`.
+    ///
+    /// A leading `/` is ignored, so a path as it was on the backed-up
+    /// machine won't be found; `Folder::relative_path` turns one into a
+    /// path this understands.
+    pub async fn lookup(&self, path: &Path) -> Result<Node, RepoError> {
+        let mut lookup = PathLookup::new(path)?;
+        self.find(&mut lookup)
+            .await?
+            .ok_or(RepoError::Storage(storage::Error::NoSuchObject))
+    }
+
+    /// Finds the node `lookup` is after, or `None` if the commit doesn't
+    /// have it. Stops as soon as it reaches a tree that an earlier lookup
+    /// has been through, since what's below it must be the same.
+    pub(crate) async fn find(&self, lookup: &mut PathLookup) -> Result<Option<Node>, RepoError> {
+        let mut keys = vec![self.root_key()];
+        let mut compression_type = self.record.compression_type;
+        let mut dir = PathBuf::new();
+        let mut visited = Vec::new();
+
+        let found = loop {
+            let depth = visited.len();
+            let tree_sha = match keys.as_slice() {
+                [key] => Some(key.sha.clone()),
+                _ => None,
+            };
+            if let Some(found) = tree_sha
+                .as_ref()
+                .and_then(|sha| lookup.found.get(&(depth, sha.clone())))
+            {
+                break found.clone();
+            }
+            visited.extend(tree_sha.map(|sha| (depth, sha)));
+
+            let name = &lookup.names[depth];
+            let tree = self.load_tree(&keys, compression_type, &dir).await?;
+            let node = match tree.nodes.into_iter().find(|n| &n.name == name) {
+                Some(node) => node,
+                None => {
+                    debug!("No {:?} in {:?}", name, dir);
+                    break None;
+                }
+            };
+
+            if depth + 1 == lookup.names.len() {
+                break Some(node);
+            }
+            if !node.is_tree {
+                debug!("{:?} is not a directory", dir.join(name));
+                break None;
+            }
+
+            dir.push(name);
+            keys = node.data_blob_keys;
+            compression_type = node.data_compression_type;
+        };
+
+        for tree in visited {
+            lookup.found.insert(tree, found.clone());
+        }
+        Ok(found)
+    }
+}
+
+/**
+ * Looks the same path up in any number of commits. What was found below
+ * each tree is remembered, so commits that share the trees along the path
+ * don't need them loaded again.
+ */
+pub(crate) struct PathLookup {
+    names: Vec<String>,
+
+    /// What lies at the end of the path below a tree, keyed by how deep the
+    /// tree is and its SHA1
+    found: HashMap<(usize, SHA1), Option<Node>>,
+}
+
+impl PathLookup {
+    pub(crate) fn new(path: &Path) -> Result<PathLookup, RepoError> {
+        let names = path
+            .components()
+            .filter_map(|c| match c {
+                path::Component::Normal(name) => Some(
+                    name.to_str()
+                        .map(str::to_owned)
+                        .ok_or(RepoError::InputError),
+                ),
+                path::Component::CurDir | path::Component::RootDir | path::Component::Prefix(_) => {
+                    None
+                }
+                path::Component::ParentDir => Some(Err(RepoError::InputError)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // The root of the backup has no node of its own
+        if names.is_empty() {
+            return Err(RepoError::InputError);
+        }
+
+        Ok(PathLookup {
+            names,
+            found: HashMap::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        crypto::{
+            BlobHasher, ObjectDecrypter, ObjectDecrypterV2, ObjectEncrypter, ObjectEncrypterV2,
+            SecretBytes,
+        },
+        mocks::{
+            commit, commit_record, keys, node, open_packset, sha, tree_objects, tree_record,
+            write_packset, MemoryStore, MemoryTreeCache, TREES,
+        },
+        storage::{self, Include, Key, ObjectInfo, Store},
+        Packset, SHA1,
+    };
+    use async_trait::async_trait;
+    use chrono::prelude::*;
+    use futures::TryStreamExt;
+    use std::{
+        convert::TryFrom,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    #[test]
+    fn filters_match_whole_paths() {
+        let matches =
+            |pattern: &str, path: &str| PathFilter::new(pattern).unwrap().matches(Path::new(path));
+
+        assert!(matches("", "a/b/c"));
+        assert!(matches("**", "a/b/c"));
+        assert!(matches("a/*", "a/b"));
+        assert!(!matches("a/*", "a/b/c"));
+        assert!(!matches("a/*", "a"));
+        assert!(matches("/a//b/", "a/b"));
+
+        assert!(matches("a/**/c", "a/c"));
+        assert!(matches("a/**/c", "a/b/c"));
+        assert!(matches("a/**/c", "a/b/b/c"));
+        assert!(!matches("a/**/c", "a/b/c/d"));
+        assert!(matches("**/*.pdf", "x.pdf"));
+        assert!(matches("**/*.pdf", "a/b/x.pdf"));
+        assert!(!matches("**/*.pdf", "a/b/x.txt"));
+        assert!(matches("a/**", "a/b/c"));
+        assert!(matches("a/**", "a"));
+
+        assert!(PathFilter::new("a/[").is_err());
+    }
+
+    #[test]
+    fn filters_know_when_to_stop() {
+        let filter = PathFilter::new("a/*/c").unwrap();
+        let state = filter.step(&filter.start(), "b");
+        assert!(!filter.can_descend(&state));
+
+        let state = filter.step(&filter.start(), "a");
+        assert!(filter.can_descend(&state));
+        let state = filter.step(&state, "x");
+        let state = filter.step(&state, "c");
+        assert!(filter.is_match(&state));
+        assert!(!filter.can_descend(&state));
+
+        let filter = PathFilter::new("a/**").unwrap();
+        let state = filter.step(&filter.start(), "a");
+        assert!(filter.is_match(&state));
+        assert!(filter.can_descend(&state));
+    }
+
+    /// A backup of
+    ///
+    ///   docs/a.pdf
+    ///   docs/old/b.pdf
+    ///   docs/old/c.txt
+    ///   src/main.rs
+    ///   readme.txt
+    ///
+    /// leaving out the trees listed in `missing`
+    fn backup(missing: &[u8]) -> Vec<(SHA1, Vec<u8>)> {
+        let trees = [
+            (
+                1,
+                vec![
+                    node("docs", true, 2),
+                    node("src", true, 4),
+                    node("readme.txt", false, 0xF0),
+                ],
+            ),
+            (2, vec![node("a.pdf", false, 0xF1), node("old", true, 3)]),
+            (
+                3,
+                vec![node("b.pdf", false, 0xF2), node("c.txt", false, 0xF3)],
+            ),
+            (4, vec![node("main.rs", false, 0xF4)]),
+        ];
+
+        let trees: Vec<_> = trees
+            .iter()
+            .filter(|(id, _)| !missing.contains(id))
+            .cloned()
+            .collect();
+        tree_objects(&trees)
+    }
+
+    /// The trees in a single pack
+    fn tree_store(missing: &[u8]) -> MemoryStore {
+        let store = MemoryStore::default();
+        write_packset(&store, TREES, &backup(missing));
+        store
+    }
+
+    /// The trees in a pack each
+    fn split_tree_store() -> MemoryStore {
+        let store = MemoryStore::default();
+        for object in backup(&[]) {
+            write_packset(&store, TREES, &[object]);
+        }
+        store
+    }
+
+    async fn packset(missing: &[u8]) -> Packset {
+        open_packset(Arc::new(tree_store(missing))).await
+    }
+
+    async fn walk(packset: &Packset, pattern: &str) -> Result<Vec<(String, usize)>, RepoError> {
+        let commit = commit(packset, 0xCC, 1);
+
+        commit
+            .walk(PathFilter::new(pattern)?)
+            .map_ok(|e| (e.path.to_string_lossy().into_owned(), e.depth))
+            .try_collect()
+            .await
+    }
+
+    fn entries(paths: &[(&str, usize)]) -> Result<Vec<(String, usize)>, RepoError> {
+        Ok(paths.iter().map(|(p, d)| (p.to_string(), *d)).collect())
+    }
+
+    #[tokio::test]
+    async fn everything_is_walked_in_order() {
+        let packset = packset(&[]).await;
+        assert_eq!(
+            walk(&packset, "").await,
+            entries(&[
+                ("docs", 0),
+                ("docs/a.pdf", 1),
+                ("docs/old", 1),
+                ("docs/old/b.pdf", 2),
+                ("docs/old/c.txt", 2),
+                ("src", 0),
+                ("src/main.rs", 1),
+                ("readme.txt", 0),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn walks_are_filtered() {
+        let packset = packset(&[]).await;
+        assert_eq!(
+            walk(&packset, "**/*.pdf").await,
+            entries(&[("docs/a.pdf", 1), ("docs/old/b.pdf", 2)])
+        );
+        assert_eq!(
+            walk(&packset, "*/*.rs").await,
+            entries(&[("src/main.rs", 1)])
+        );
+        assert_eq!(walk(&packset, "docs").await, entries(&[("docs", 0)]));
+    }
+
+    async fn lookup(packset: &Packset, path: &str) -> Result<(String, bool), RepoError> {
+        let commit = commit(packset, 0xCC, 1);
+
+        let node = commit.lookup(Path::new(path)).await?;
+        Ok((node.name, node.is_tree))
+    }
+
+    #[tokio::test]
+    async fn paths_are_looked_up() {
+        let packset = packset(&[]).await;
+        assert_eq!(
+            lookup(&packset, "docs/old/b.pdf").await,
+            Ok(("b.pdf".to_string(), false))
+        );
+        assert_eq!(
+            lookup(&packset, "/docs/./old").await,
+            Ok(("old".to_string(), true))
+        );
+        assert_eq!(
+            lookup(&packset, "readme.txt").await,
+            Ok(("readme.txt".to_string(), false))
+        );
+
+        let not_found = Err(RepoError::Storage(crate::storage::Error::NoSuchObject));
+        assert_eq!(lookup(&packset, "docs/new/b.pdf").await, not_found);
+        assert_eq!(lookup(&packset, "readme.txt/x").await, not_found);
+        assert_eq!(lookup(&packset, "/Users/larq/readme.txt").await, not_found);
+        assert_eq!(lookup(&packset, "").await, Err(RepoError::InputError));
+        assert_eq!(
+            lookup(&packset, "docs/../src").await,
+            Err(RepoError::InputError)
+        );
+    }
+
+    #[tokio::test]
+    async fn lookups_only_load_trees_along_the_path() {
+        let packset = packset(&[3, 4]).await;
+        assert_eq!(
+            lookup(&packset, "docs/a.pdf").await,
+            Ok(("a.pdf".to_string(), false))
+        );
+        assert_eq!(
+            lookup(&packset, "docs/old").await,
+            Ok(("old".to_string(), true))
+        );
+    }
+
+    /// Counts how many fetches are in flight at once
+    #[derive(Default)]
+    struct SlowStore {
+        inner: MemoryStore,
+        current: AtomicUsize,
+        most: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Store for SlowStore {
+        async fn list_contents(
+            &self,
+            path: &str,
+            flags: Include,
+        ) -> storage::Result<Vec<ObjectInfo>> {
+            self.inner.list_contents(path, flags).await
+        }
+
+        async fn get(&self, key: Key) -> storage::Result<Vec<u8>> {
+            let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most.fetch_max(current, Ordering::SeqCst);
+            for _ in 0..10 {
+                let () = tokio::task::yield_now().await;
+            }
+            self.current.fetch_sub(1, Ordering::SeqCst);
+            self.inner.get(key).await
+        }
+    }
+
+    #[tokio::test]
+    async fn trees_are_fetched_concurrently_in_order() {
+        let expected = walk(&packset(&[]).await, "").await.unwrap();
+
+        for limit in [1, 2, 8].iter() {
+            let store = Arc::new(SlowStore {
+                inner: split_tree_store(),
+                ..SlowStore::default()
+            });
+            let packset = open_packset(store.clone()).await;
+            store.most.store(0, Ordering::SeqCst);
+
+            let entries: Vec<_> = commit(&packset, 0xCC, 1)
+                .with_concurrency(*limit)
+                .walk(PathFilter::all())
+                .map_ok(|e| (e.path.to_string_lossy().into_owned(), e.depth))
+                .try_collect()
+                .await
+                .unwrap();
+
+            assert_eq!(entries, expected);
+            let most = store.most.load(Ordering::SeqCst);
+            assert!(most <= *limit);
+            assert!(most > 1 || *limit == 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn trees_in_one_pack_share_its_fetch() {
+        let store = Arc::new(SlowStore {
+            inner: tree_store(&[]),
+            ..SlowStore::default()
+        });
+        let packset = open_packset(store.clone()).await;
+        let start = store.inner.gets.load(Ordering::SeqCst);
+
+        let entries = commit(&packset, 0xCC, 1)
+            .with_concurrency(8)
+            .walk(PathFilter::all())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 8);
+
+        // docs and src are fetched together, then old on its own
+        assert_eq!(store.most.load(Ordering::SeqCst), 1);
+        assert_eq!(store.inner.gets.load(Ordering::SeqCst) - start, 3);
+    }
+
+    #[tokio::test]
+    async fn cached_trees_are_not_fetched() {
+        let cache = Arc::new(MemoryTreeCache::default());
+        let full = packset(&[]).await;
+        let expected = commit(&full, 0xCC, 1)
+            .with_tree_cache(Some(cache.clone()))
+            .walk(PathFilter::all())
+            .map_ok(|e| e.path)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(cache.entries.lock().unwrap().len(), 4);
+
+        // None of the trees are in storage any more
+        let empty = packset(&[1, 2, 3, 4]).await;
+        let entries = commit(&empty, 0xCC, 1)
+            .with_tree_cache(Some(cache.clone()))
+            .walk(PathFilter::all())
+            .map_ok(|e| e.path)
+            .try_collect::<Vec<_>>()
+            .await;
+        assert_eq!(entries, Ok(expected));
+    }
+
+    #[tokio::test]
+    async fn cached_trees_are_checked_when_verifying() {
+        let data = tree_record(&[node("readme.txt", false, 0xF0)]);
+        let mut hasher = BlobHasher::new(b"salt");
+        hasher.update(&data);
+        let root = SHA1::try_from(&hasher.finish()[..]).unwrap();
+
+        let store = MemoryStore::default();
+        let object = ObjectEncrypterV2::new(keys()).encrypt_object(&data).ok().unwrap();
+        write_packset(&store, TREES, &[(root.clone(), object)]);
+        let packset = open_packset(Arc::new(store)).await;
+
+        // Someone has swapped the cached tree for one of their own
+        let cache = Arc::new(MemoryTreeCache::default());
+        let forged = tree_record(&[node("forged.txt", false, 0xF1)]);
+        cache.entries.lock().unwrap().insert(root.clone(), forged);
+
+        let decrypter: Arc<dyn ObjectDecrypter> = Arc::new(ObjectDecrypterV2::new(keys()));
+        let record = commit_record(&[], &root, &Utc.timestamp(1_600_000_000, 0));
+        let paths = Commit::parse(sha(0xCC), &record, &packset, &decrypter)
+            .unwrap()
+            .with_blob_salt(Some(Arc::new(SecretBytes::from_slice(b"salt"))))
+            .with_tree_cache(Some(cache.clone()))
+            .walk(PathFilter::all())
+            .map_ok(|e| e.path)
+            .try_collect::<Vec<_>>()
+            .await;
+        assert_eq!(paths, Ok(vec![PathBuf::from("readme.txt")]));
+        assert_eq!(cache.entries.lock().unwrap()[&root], data);
+    }
+
+    #[tokio::test]
+    async fn unmatched_subtrees_are_not_loaded() {
+        // Nothing under src can need the tree for docs/old
+        let packset = packset(&[3]).await;
+        assert_eq!(
+            walk(&packset, "src/*").await,
+            entries(&[("src/main.rs", 1)])
+        );
+        assert_eq!(
+            walk(&packset, "docs/old/*").await,
+            Err(RepoError::MalformedData)
+        );
+    }
+}
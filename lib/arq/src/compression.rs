@@ -1,20 +1,289 @@
-use crate::{CompressionType, RepoError};
+use std::io::{self, Read};
 
-pub fn decompress(input: &[u8], compression_type: CompressionType) -> Result<Vec<u8>, RepoError> {
-    use std::io::Write;
+use crate::CompressionType;
 
+/// Wraps a reader so that it yields decompressed data, without ever holding
+/// the whole of either the compressed or the decompressed data in memory.
+pub fn decompressing_reader<'a>(
+    input: Box<dyn Read + 'a>,
+    compression_type: CompressionType,
+) -> Box<dyn Read + 'a> {
     match compression_type {
-        CompressionType::GZip => {
-            let writer = Vec::new();
-            let mut decoder = flate2::write::GzDecoder::new(writer);
-            decoder
-                .write_all(input)
-                .and_then(|_| decoder.finish())
-                .map_err(|_| RepoError::MalformedData)
+        CompressionType::None => input,
+        CompressionType::GZip => Box::new(flate2::read::GzDecoder::new(input)),
+        CompressionType::LZ4 => Box::new(Lz4Reader::new(input)),
+    }
+}
+
+// Arq stores LZ4-compressed data as the big-endian length of the original
+// data, followed by a single LZ4 block. The block format is a sequence of
+//
+//   token             high nibble: literal count, low nibble: match length
+//   literal count     (only if the nibble is 15) bytes added until one < 255
+//   literals          ...
+//   match offset      little-endian u16, distance back into the output
+//   match length      (only if the nibble is 15) as for the literal count
+//
+// where the final sequence stops after its literals. Matches only reach 64K
+// back, so that is all the output we need to keep around.
+
+const LZ4_WINDOW: usize = 64 * 1024;
+const LZ4_MIN_MATCH: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+enum Lz4State {
+    Header,
+    Token,
+    Literals { remaining: usize, match_nibble: u8 },
+    Match { offset: usize, remaining: usize },
+    Done,
+}
+
+struct Lz4Reader<R: Read> {
+    input: io::BufReader<R>,
+    state: Lz4State,
+    expected_len: u64,
+    produced: u64,
+
+    /// Output that has been decoded, of which everything before `pos` has
+    /// already been returned. At most `LZ4_WINDOW` bytes are kept before
+    /// `pos`.
+    window: Vec<u8>,
+    pos: usize,
+}
+
+fn malformed(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<R: Read> Lz4Reader<R> {
+    fn new(input: R) -> Self {
+        Lz4Reader {
+            input: io::BufReader::new(input),
+            state: Lz4State::Header,
+            expected_len: 0,
+            produced: 0,
+            window: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut b = [0; 1];
+        self.input.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    fn read_length(&mut self, nibble: u8) -> io::Result<usize> {
+        let mut len = nibble as usize;
+        if nibble == 15 {
+            loop {
+                let b = self.read_u8()?;
+                len += b as usize;
+                if b != 255 {
+                    break;
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    /// Decodes some more output into the window
+    fn decode(&mut self) -> io::Result<()> {
+        let start = self.window.len();
+        while self.window.len() == start {
+            self.state = match self.state {
+                Lz4State::Header => {
+                    let mut len = [0; 4];
+                    self.input.read_exact(&mut len)?;
+                    self.expected_len = u32::from_be_bytes(len) as u64;
+                    if self.expected_len == 0 {
+                        Lz4State::Done
+                    } else {
+                        Lz4State::Token
+                    }
+                }
+
+                Lz4State::Token => {
+                    let token = self.read_u8()?;
+                    Lz4State::Literals {
+                        remaining: self.read_length(token >> 4)?,
+                        match_nibble: token & 0x0F,
+                    }
+                }
+
+                Lz4State::Literals {
+                    remaining,
+                    match_nibble,
+                } if remaining > 0 => {
+                    let n = remaining.min(LZ4_WINDOW);
+                    let end = self.window.len();
+                    self.window.resize(end + n, 0);
+                    self.input.read_exact(&mut self.window[end..])?;
+                    Lz4State::Literals {
+                        remaining: remaining - n,
+                        match_nibble,
+                    }
+                }
+
+                Lz4State::Literals { match_nibble, .. } => {
+                    let decoded = self.produced + (self.window.len() - self.pos) as u64;
+                    if decoded >= self.expected_len {
+                        Lz4State::Done
+                    } else {
+                        let mut offset = [0; 2];
+                        self.input.read_exact(&mut offset)?;
+                        let offset = u16::from_le_bytes(offset) as usize;
+                        if offset == 0 || offset > self.window.len() {
+                            return Err(malformed("LZ4 match offset out of range"));
+                        }
+                        Lz4State::Match {
+                            offset,
+                            remaining: self.read_length(match_nibble)? + LZ4_MIN_MATCH,
+                        }
+                    }
+                }
+
+                Lz4State::Match { offset, remaining } => {
+                    // Matches may overlap their own output, so copy bytewise
+                    let n = remaining.min(LZ4_WINDOW);
+                    for _ in 0..n {
+                        let b = self.window[self.window.len() - offset];
+                        self.window.push(b);
+                    }
+                    if remaining > n {
+                        Lz4State::Match {
+                            offset,
+                            remaining: remaining - n,
+                        }
+                    } else {
+                        Lz4State::Token
+                    }
+                }
+
+                Lz4State::Done => return Ok(()),
+            };
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Lz4Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.window.len() {
+            // Only the last LZ4_WINDOW bytes can be referred to again
+            if self.pos > 2 * LZ4_WINDOW {
+                self.window.drain(..self.pos - LZ4_WINDOW);
+                self.pos = LZ4_WINDOW;
+            }
+
+            self.decode().map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => malformed("truncated LZ4 data"),
+                _ => e,
+            })?;
+
+            let decoded = self.produced + (self.window.len() - self.pos) as u64;
+            if decoded > self.expected_len {
+                return Err(malformed("LZ4 data is longer than its header says"));
+            }
+            if let Lz4State::Done = self.state {
+                if decoded != self.expected_len {
+                    return Err(malformed("LZ4 data is shorter than its header says"));
+                }
+            }
+        }
+
+        let available = &self.window[self.pos..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n;
+        self.produced += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::RepoError;
+    use std::io::Write;
+
+    fn decompress(input: &[u8], compression_type: CompressionType) -> Result<Vec<u8>, RepoError> {
+        let mut result = Vec::new();
+        decompressing_reader(Box::new(input), compression_type)
+            .read_to_end(&mut result)
+            .map_err(|_| RepoError::MalformedData)?;
+        Ok(result)
+    }
+
+    fn arq_lz4(data: &[u8]) -> Vec<u8> {
+        let mut result = (data.len() as u32).to_be_bytes().to_vec();
+        result.extend_from_slice(&lz4_flex::block::compress(data));
+        result
+    }
+
+    fn sample_data() -> Vec<u8> {
+        // Repetitive enough to compress, with matches both near and far
+        let mut data = Vec::new();
+        for n in 0..50_000u32 {
+            data.extend_from_slice(format!("line {} of {}\n", n % 700, n % 13).as_bytes());
         }
-        _ => {
-            log::error!("Compression type not supported yet: {:?}", compression_type);
+        data
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let data = sample_data();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(decompress(&compressed, CompressionType::GZip), Ok(data));
+        assert_eq!(
+            decompress(&compressed[..100], CompressionType::GZip),
             Err(RepoError::MalformedData)
+        );
+    }
+
+    #[test]
+    fn lz4_round_trip() {
+        let incompressible: Vec<u8> = (0..300_000u32)
+            .map(|n| (n.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+
+        for data in [Vec::new(), b"a".to_vec(), sample_data(), incompressible].iter() {
+            let compressed = arq_lz4(data);
+
+            let mut reader = decompressing_reader(Box::new(&compressed[..]), CompressionType::LZ4);
+            let mut output = Vec::new();
+            let mut buf = [0; 1000];
+            loop {
+                let n = reader.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                output.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(&output, data);
         }
     }
+
+    #[test]
+    fn lz4_rejects_bad_data() {
+        let data = sample_data();
+        let compressed = arq_lz4(&data);
+
+        let truncated = &compressed[..compressed.len() / 2];
+        assert_eq!(
+            decompress(truncated, CompressionType::LZ4),
+            Err(RepoError::MalformedData)
+        );
+
+        let mut wrong_length = compressed.clone();
+        wrong_length[..4].copy_from_slice(&(data.len() as u32 + 1).to_be_bytes());
+        assert_eq!(
+            decompress(&wrong_length, CompressionType::LZ4),
+            Err(RepoError::MalformedData)
+        );
+    }
 }
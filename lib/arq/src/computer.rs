@@ -1,11 +1,11 @@
-use arq_crypto::ObjectDecrypter;
+use arq_crypto::{ObjectDecrypter, SecretBytes};
 use arq_storage::{Include, Store};
 use futures::{future, TryFutureExt};
 use log::{debug, error, info};
 use serde::Deserialize;
 use std::{fmt, sync::Arc};
 
-use crate::{storage::Key as StorageKey, Folder, FolderInfo, RepoError};
+use crate::{storage::Key as StorageKey, Folder, FolderInfo, RepoError, TreeCache};
 
 #[derive(Deserialize, Debug)]
 pub struct ComputerInfo {
@@ -24,6 +24,8 @@ pub struct Computer {
     store: Arc<dyn Store>,
     decrypter: Arc<dyn ObjectDecrypter>,
     bucket_decrypter: Arc<dyn ObjectDecrypter>,
+    blob_salt: Option<Arc<SecretBytes>>,
+    tree_cache: Option<Arc<dyn TreeCache>>,
 }
 
 impl fmt::Debug for Computer {
@@ -46,6 +48,26 @@ impl Computer {
             store: store.clone(),
             decrypter: decrypter.clone(),
             bucket_decrypter: bucket_decrypter.clone(),
+            blob_salt: None,
+            tree_cache: None,
+        }
+    }
+
+    /// Verifies the blobs of this computer's folders, using `salt` to
+    /// calculate their SHA1s.
+    pub(crate) fn with_blob_salt(self, salt: Arc<SecretBytes>) -> Computer {
+        Computer {
+            blob_salt: Some(salt),
+            ..self
+        }
+    }
+
+    /// Looks for the trees and commits of this computer's folders in
+    /// `cache` before fetching them.
+    pub(crate) fn with_tree_cache(self, cache: Option<Arc<dyn TreeCache>>) -> Computer {
+        Computer {
+            tree_cache: cache,
+            ..self
         }
     }
 
@@ -61,7 +83,14 @@ impl Computer {
         debug!("Building task list");
         let tasks: Vec<_> = folder_buckets
             .into_iter()
-            .map(|obj| fetch_folder(self.store.as_ref(), obj.key, self.bucket_decrypter.as_ref()))
+            .map(|obj| {
+                fetch_folder(
+                    self.store.as_ref(),
+                    obj.key,
+                    self.bucket_decrypter.as_ref(),
+                    self.decrypter.as_ref(),
+                )
+            })
             .collect();
 
         debug!("Spawning {} subtasks", tasks.len());
@@ -86,34 +115,164 @@ impl Computer {
             self.store.as_ref(),
             key.clone(),
             self.bucket_decrypter.as_ref(),
+            self.decrypter.as_ref(),
         )
         .and_then(|info| Folder::new(&self.info.id, info, &self.store, &self.decrypter))
         .await
+        .map(|folder| {
+            folder
+                .with_blob_salt(self.blob_salt.clone())
+                .with_tree_cache(self.tree_cache.clone())
+        })
     }
 }
 
-const V1_HEADER: &[u8] = "encrypted".as_bytes();
+const V1_HEADER: &[u8] = b"encrypted";
+const OBJECT_HEADER: &[u8] = b"ARQO";
+
+/// The ways Arq stores plists, such as bucket configurations and reflog
+/// entries
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PlistEnvelope<'a> {
+    /// `encrypted`, followed by a legacy object. Bucket plists are encrypted
+    /// with the password and the fixed `BucketPL` salt.
+    Legacy(&'a [u8]),
+
+    /// An `ARQO` EncryptedObject under the computer's master keys, with or
+    /// without the `encrypted` prefix
+    Object(&'a [u8]),
+
+    /// An unencrypted plist, as written by very old versions of Arq
+    Plain(&'a [u8]),
+}
+
+impl<'a> PlistEnvelope<'a> {
+    pub(crate) fn detect(bytes: &'a [u8]) -> PlistEnvelope<'a> {
+        match bytes.strip_prefix(V1_HEADER) {
+            Some(rest) if rest.starts_with(OBJECT_HEADER) => PlistEnvelope::Object(rest),
+            Some(rest) => PlistEnvelope::Legacy(rest),
+            None if bytes.starts_with(OBJECT_HEADER) => PlistEnvelope::Object(bytes),
+            None => PlistEnvelope::Plain(bytes),
+        }
+    }
+}
 
-async fn fetch_folder(
+pub(crate) async fn fetch_folder(
     store: &dyn Store,
     key: StorageKey,
+    bucket_decrypter: &dyn ObjectDecrypter,
     decrypter: &dyn ObjectDecrypter,
 ) -> Result<FolderInfo, RepoError> {
     debug!("Fetching {:?}", key);
-    // TODO - examine how to do a streaming decrypt, rather than a one-hit
-    // buffered decrypt
-    let encrypted_object = store.get(key).await.map_err(RepoError::Storage)?;
+    let object = store.get(key).await.map_err(RepoError::Storage)?;
+    decode_folder(&object, bucket_decrypter, decrypter)
+}
+
+/// Unwraps a bucket plist, picking the decrypter that matches its envelope.
+/// `bucket_decrypter` handles the legacy envelope and `decrypter`, the
+/// computer's own, handles `ARQO` objects.
+pub(crate) fn decode_folder(
+    object: &[u8],
+    bucket_decrypter: &dyn ObjectDecrypter,
+    decrypter: &dyn ObjectDecrypter,
+) -> Result<FolderInfo, RepoError> {
+    debug!("decoding {}-byte bucket plist", object.len());
+    let plist = match PlistEnvelope::detect(object) {
+        PlistEnvelope::Legacy(obj) => bucket_decrypter.decrypt_object(obj),
+        PlistEnvelope::Object(obj) => decrypter.decrypt_object(obj),
+        PlistEnvelope::Plain(plist) => {
+            debug!("Bucket plist is not encrypted");
+            return plist::from_bytes(plist).map_err(|_| RepoError::MalformedData);
+        }
+    }
+    .map_err(|_| RepoError::CryptoError)?;
+
+    plist::from_bytes(&plist[..]).map_err(|_| RepoError::MalformedData)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mocks::keys;
+    use arq_crypto::{
+        CryptoKey, MasterKeys, ObjectDecrypterV1, ObjectDecrypterV2, ObjectEncrypter,
+        ObjectEncrypterV2,
+    };
+
+    const PLIST: &[u8] = br#"<?xml version="1.0" encoding="UTF-8"?>
+        <plist version="1.0">
+        <dict>
+            <key>BucketUUID</key>
+            <string>408E376B-ECF7-4688-902A-1E7671BC5B9A</string>
+            <key>BucketName</key>
+            <string>company</string>
+            <key>LocalPath</key>
+            <string>/Users/stefan/src/company</string>
+        </dict>
+        </plist>"#;
+
+    fn decrypters() -> (ObjectDecrypterV1, ObjectDecrypterV2) {
+        let bucket_key = CryptoKey::new("pw", b"BucketPL").ok().unwrap();
+        (
+            ObjectDecrypterV1::new(bucket_key),
+            ObjectDecrypterV2::new(keys()),
+        )
+    }
 
-    if encrypted_object.len() < V1_HEADER.len() || &encrypted_object[..9] != V1_HEADER {
-        return Err(RepoError::MalformedData);
+    fn decode(object: &[u8]) -> Result<(), RepoError> {
+        let (bucket_decrypter, decrypter) = decrypters();
+        decode_folder(object, &bucket_decrypter, &decrypter).map(|_| ())
     }
 
-    debug!("decrypting {}-byte object", encrypted_object.len());
-    let obj = decrypter
-        .decrypt_object(&encrypted_object[V1_HEADER.len()..])
-        .map_err(|_| RepoError::CryptoError)?;
+    #[test]
+    fn envelopes_are_detected() {
+        assert_eq!(
+            PlistEnvelope::detect(b"encryptedABC"),
+            PlistEnvelope::Legacy(b"ABC")
+        );
+        assert_eq!(
+            PlistEnvelope::detect(b"encryptedARQO..."),
+            PlistEnvelope::Object(b"ARQO...")
+        );
+        assert_eq!(
+            PlistEnvelope::detect(b"ARQO..."),
+            PlistEnvelope::Object(b"ARQO...")
+        );
+        assert_eq!(
+            PlistEnvelope::detect(b"<?xml"),
+            PlistEnvelope::Plain(b"<?xml")
+        );
+    }
+
+    #[test]
+    fn every_envelope_decodes() {
+        let key = CryptoKey::new("pw", b"BucketPL").ok().unwrap();
+        let mut legacy = V1_HEADER.to_vec();
+        legacy.extend(key.encrypt(PLIST).ok().unwrap());
+        assert_eq!(decode(&legacy), Ok(()));
+
+        let arqo = ObjectEncrypterV2::new(keys())
+            .encrypt_object(PLIST)
+            .ok()
+            .unwrap();
+        assert_eq!(decode(&arqo), Ok(()));
 
-    drop(encrypted_object);
+        let mut prefixed = V1_HEADER.to_vec();
+        prefixed.extend_from_slice(&arqo);
+        assert_eq!(decode(&prefixed), Ok(()));
 
-    plist::from_bytes(&obj[..]).map_err(|_| RepoError::MalformedData)
+        assert_eq!(decode(PLIST), Ok(()));
+    }
+
+    #[test]
+    fn bad_bucket_plists_are_rejected() {
+        let other_keys = MasterKeys::new([4; 32], [5; 32], None);
+        let arqo = ObjectEncrypterV2::new(other_keys)
+            .encrypt_object(PLIST)
+            .ok()
+            .unwrap();
+        assert_eq!(decode(&arqo), Err(RepoError::CryptoError));
+
+        assert_eq!(decode(b"not a plist"), Err(RepoError::MalformedData));
+    }
 }
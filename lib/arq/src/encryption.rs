@@ -0,0 +1,618 @@
+use std::sync::Arc;
+
+use arq_crypto::{
+    hmac_sha256, CryptoError, CryptoKey, EncryptionDat, MasterKeys, ObjectDecrypter,
+    ObjectDecrypterAny, ObjectDecrypterV1, ObjectDecrypterV2, SecretBytes,
+};
+use arq_storage::{Error as StorageError, Include, Key as StorageKey, Store};
+use log::{debug, error, info, warn};
+use zeroize::Zeroizing;
+
+use crate::{
+    computer::{decode_folder, PlistEnvelope},
+    RepoError,
+};
+
+/// The names a computer's key file can have, newest format first
+const ENCRYPTION_DAT_NAMES: [&str; 2] = ["encryptionv3.dat", "encryptionv2.dat"];
+
+/// Fetches the key file for a computer, along with its key. Backup sets
+/// created by the oldest versions of Arq have no key file.
+pub(crate) async fn fetch_encryption_dat(
+    store: &dyn Store,
+    computer_id: &str,
+) -> Result<Option<(StorageKey, Vec<u8>)>, RepoError> {
+    for name in ENCRYPTION_DAT_NAMES.iter() {
+        let key = StorageKey::from(computer_id) / *name;
+        match store.get(key.clone()).await {
+            Ok(content) => return Ok(Some((key, content))),
+            Err(StorageError::NoSuchObject) => continue,
+            Err(e) => return Err(RepoError::Storage(e)),
+        }
+    }
+    Ok(None)
+}
+
+fn open_encryption_dat(content: &[u8], password: &str) -> Result<MasterKeys, RepoError> {
+    EncryptionDat::parse(content)
+        .and_then(|dat| dat.open(password))
+        .map_err(|e| match e {
+            CryptoError::MalformedData => RepoError::MalformedData,
+            _ => RepoError::CryptoError,
+        })
+}
+
+/// The keys protecting a computer's backup objects
+pub(crate) enum Keyset {
+    /// A key derived from the password and the computer's salt, used by
+    /// sets that predate key files
+    Legacy(CryptoKey),
+
+    /// The master keys from the computer's key file
+    Master(MasterKeys),
+
+    /// The master keys from the computer's key file, along with the legacy
+    /// key, for sets that were written by older versions of Arq before
+    /// they gained a key file
+    Migrated(MasterKeys, CryptoKey),
+}
+
+const LEGACY_TAG: u8 = 1;
+const MASTER_TAG: u8 = 2;
+const MIGRATED_TAG: u8 = 3;
+
+fn put_legacy_key(buf: &mut Vec<u8>, key: &CryptoKey) {
+    let iv = key.iv_bytes().unwrap_or(&[]);
+    buf.push(key.key_bytes().len() as u8);
+    buf.extend_from_slice(key.key_bytes());
+    buf.push(iv.len() as u8);
+    buf.extend_from_slice(iv);
+}
+
+/// Reads a key written by `put_legacy_key`, returning whatever follows it
+fn take_legacy_key(bytes: &[u8]) -> Option<(CryptoKey, &[u8])> {
+    let (&key_len, rest) = bytes.split_first()?;
+    let key = rest.get(..key_len as usize)?;
+    let (&iv_len, rest) = rest.get(key_len as usize..)?.split_first()?;
+    let iv = rest.get(..iv_len as usize)?;
+    let iv = if iv.is_empty() {
+        None
+    } else {
+        Some(SecretBytes::from_slice(iv))
+    };
+    let key = CryptoKey::from_parts(SecretBytes::from_slice(key), iv);
+    Some((key, &rest[iv_len as usize..]))
+}
+
+impl Keyset {
+    /// Serialises the keys, e.g. for a `KeyCache`
+    pub(crate) fn to_secret(&self) -> SecretBytes {
+        let mut buf = Zeroizing::new(Vec::new());
+        match self {
+            Keyset::Legacy(key) => {
+                buf.push(LEGACY_TAG);
+                put_legacy_key(&mut buf, key);
+            }
+            Keyset::Master(keys) => {
+                buf.push(MASTER_TAG);
+                buf.extend_from_slice(keys.as_bytes());
+            }
+            Keyset::Migrated(keys, key) => {
+                buf.push(MIGRATED_TAG);
+                put_legacy_key(&mut buf, key);
+                buf.extend_from_slice(keys.as_bytes());
+            }
+        }
+        SecretBytes::from_slice(&buf)
+    }
+
+    pub(crate) fn from_secret(bytes: &[u8]) -> Option<Keyset> {
+        let master_keys = |keys: &[u8]| MasterKeys::from_secret(SecretBytes::from_slice(keys)).ok();
+
+        match bytes.split_first()? {
+            (&LEGACY_TAG, rest) => match take_legacy_key(rest)? {
+                (key, []) => Some(Keyset::Legacy(key)),
+                _ => None,
+            },
+            (&MASTER_TAG, keys) => master_keys(keys).map(Keyset::Master),
+            (&MIGRATED_TAG, rest) => {
+                let (key, keys) = take_legacy_key(rest)?;
+                Some(Keyset::Migrated(master_keys(keys)?, key))
+            }
+            _ => None,
+        }
+    }
+
+    /// The salt Arq prepends to a blob's data when calculating its SHA1:
+    /// the third master key, or the computer UUID if there are only two.
+    /// The naming of blobs in legacy sets isn't documented, so they can't be
+    /// verified.
+    pub(crate) fn blob_salt(&self, computer_id: &str) -> Option<SecretBytes> {
+        match self {
+            Keyset::Master(keys) | Keyset::Migrated(keys, _) => {
+                let salt = keys.salt_key().unwrap_or(computer_id.as_bytes());
+                Some(SecretBytes::from_slice(salt))
+            }
+            Keyset::Legacy(_) => None,
+        }
+    }
+
+    /// Master keys for encrypting a local cache of this computer's trees
+    /// and commits, derived from its own keys so that they can't be had
+    /// without the password
+    pub(crate) fn tree_cache_keys(&self) -> Option<SecretBytes> {
+        let root = match self {
+            Keyset::Master(keys) | Keyset::Migrated(keys, _) => keys.encryption_key(),
+            Keyset::Legacy(key) => key.key_bytes(),
+        };
+        let mut keys = Zeroizing::new(hmac_sha256(root, &[b"tree-cache", b"encryption"]).ok()?);
+        keys.extend_from_slice(&hmac_sha256(root, &[b"tree-cache", b"hmac"]).ok()?);
+        Some(SecretBytes::from_slice(&keys))
+    }
+
+    /// A decrypter that picks the key for each object by its header
+    pub(crate) fn into_decrypter(self) -> Arc<dyn ObjectDecrypter> {
+        let (v1, v2) = match self {
+            Keyset::Legacy(key) => (Some(ObjectDecrypterV1::new(key)), None),
+            Keyset::Master(keys) => (None, Some(ObjectDecrypterV2::new(keys))),
+            Keyset::Migrated(keys, key) => (
+                Some(ObjectDecrypterV1::new(key)),
+                Some(ObjectDecrypterV2::new(keys)),
+            ),
+        };
+        Arc::new(ObjectDecrypterAny::new(v1, v2))
+    }
+}
+
+/// Derives the legacy key from the password and the computer's salt, if it
+/// has one
+async fn legacy_key(
+    store: &dyn Store,
+    computer_id: &str,
+    password: &str,
+) -> Result<Option<CryptoKey>, RepoError> {
+    let salt = match store.get(StorageKey::from(computer_id) / "salt").await {
+        Ok(salt) => salt,
+        Err(StorageError::NoSuchObject) => return Ok(None),
+        Err(e) => return Err(RepoError::Storage(e)),
+    };
+
+    CryptoKey::new(password, &salt[..])
+        .map(Some)
+        .map_err(|_| RepoError::CryptoError)
+}
+
+/// Derives the keys for a computer's backup objects from the password and
+/// the computer's key file (as fetched by `fetch_encryption_dat`) and salt.
+/// Sets without a key file only have the legacy key, and sets without a
+/// salt only have master keys.
+pub(crate) async fn derive_keyset(
+    store: &dyn Store,
+    computer_id: &str,
+    password: &str,
+    key_file: Option<&[u8]>,
+) -> Result<Keyset, RepoError> {
+    let content = match key_file {
+        Some(content) => content,
+        None => {
+            return legacy_key(store, computer_id, password)
+                .await?
+                .map(Keyset::Legacy)
+                .ok_or(RepoError::Storage(StorageError::NoSuchObject))
+        }
+    };
+
+    debug!("Loading master keys for {}", computer_id);
+    let keys = open_encryption_dat(content, password)?;
+    match legacy_key(store, computer_id, password).await? {
+        Some(key) => {
+            debug!(
+                "{} also has a salt for objects from before its key file",
+                computer_id
+            );
+            Ok(Keyset::Migrated(keys, key))
+        }
+        None => Ok(Keyset::Master(keys)),
+    }
+}
+
+/// The decrypter for the bucket plists of legacy backup sets
+pub(crate) fn bucket_decrypter(password: &str) -> Result<Arc<dyn ObjectDecrypter>, RepoError> {
+    CryptoKey::new(password, "BucketPL".as_bytes())
+        .map(|k| Arc::new(ObjectDecrypterV1::new(k)) as Arc<dyn ObjectDecrypter>)
+        .map_err(|_| RepoError::CryptoError)
+}
+
+/// Checks `password` against a computer's key file HMAC or, for sets that
+/// predate key files, by decrypting one of its bucket plists.
+pub(crate) async fn verify_password(
+    store: &dyn Store,
+    computer_id: &str,
+    password: &str,
+) -> Result<bool, RepoError> {
+    if let Some((_, content)) = fetch_encryption_dat(store, computer_id).await? {
+        return match open_encryption_dat(&content, password) {
+            Ok(_) => Ok(true),
+            Err(RepoError::CryptoError) => Ok(false),
+            Err(e) => Err(e),
+        };
+    }
+
+    let buckets = store
+        .list_contents(&format!("{}/buckets/", computer_id), Include::FILES)
+        .await
+        .map_err(RepoError::Storage)?;
+
+    // Only the legacy envelope is encrypted with the password alone, so
+    // unencrypted plists can't tell us anything
+    let decrypter = bucket_decrypter(password)?;
+    for bucket in buckets {
+        let object = store.get(bucket.key).await.map_err(RepoError::Storage)?;
+        if let PlistEnvelope::Legacy(_) = PlistEnvelope::detect(&object) {
+            return match decode_folder(&object, decrypter.as_ref(), decrypter.as_ref()) {
+                Ok(_) => Ok(true),
+                // A wrong key almost always fails the padding check; the rare
+                // exception decrypts to garbage that won't parse
+                Err(RepoError::CryptoError) | Err(RepoError::MalformedData) => Ok(false),
+                Err(e) => Err(e),
+            };
+        }
+    }
+    Err(RepoError::Storage(StorageError::NoSuchObject))
+}
+
+fn same_keys(a: &MasterKeys, b: &MasterKeys) -> bool {
+    a.encryption_key() == b.encryption_key()
+        && a.hmac_key() == b.hmac_key()
+        && a.salt_key() == b.salt_key()
+}
+
+/// Writes `content` to `key` and checks that it reads back as the expected
+/// set of keys.
+async fn write_and_verify(
+    store: &dyn Store,
+    key: &StorageKey,
+    content: &[u8],
+    password: &str,
+    expected: &MasterKeys,
+) -> Result<(), RepoError> {
+    store
+        .put(key.clone(), content.to_vec())
+        .await
+        .map_err(RepoError::Storage)?;
+
+    let written = store.get(key.clone()).await.map_err(RepoError::Storage)?;
+    let keys = open_encryption_dat(&written, password)?;
+    if same_keys(&keys, expected) {
+        Ok(())
+    } else {
+        Err(RepoError::MalformedData)
+    }
+}
+
+/// Re-encrypts a computer's master keys with a new password. The new key
+/// file is written alongside the old one and checked before it replaces the
+/// original, and the original is put back if the replacement can't be read.
+pub(crate) async fn change_password(
+    store: &dyn Store,
+    computer_id: &str,
+    old_password: &str,
+    new_password: &str,
+) -> Result<(), RepoError> {
+    let (key, original) = fetch_encryption_dat(store, computer_id)
+        .await?
+        .ok_or(RepoError::Storage(StorageError::NoSuchObject))?;
+
+    let keys = open_encryption_dat(&original, old_password)?;
+    let replacement = EncryptionDat::seal(&keys, new_password)
+        .map_err(|_| RepoError::CryptoError)?
+        .to_bytes();
+
+    let staging_key = StorageKey::from(format!("{}.new", key));
+    info!("Writing new key file to {}", staging_key);
+    write_and_verify(store, &staging_key, &replacement, new_password, &keys).await?;
+
+    info!("Replacing {}", key);
+    if let Err(e) = write_and_verify(store, &key, &replacement, new_password, &keys).await {
+        error!("Replacing {} failed, restoring the original", key);
+        store
+            .put(key.clone(), original)
+            .await
+            .map_err(RepoError::Storage)?;
+        return Err(e);
+    }
+
+    if let Err(e) = store.delete(staging_key.clone()).await {
+        warn!("Removing {} failed: {:?}", staging_key, e);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mocks::MemoryStore;
+    use arq_crypto::{ObjectEncrypter, ObjectEncrypterV2, MASTER_KEY_LEN};
+    use arq_storage::{ObjectInfo, Result as StorageResult};
+    use async_trait::async_trait;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    const COMPUTER: &str = "C0FFEE";
+
+    fn test_keys() -> MasterKeys {
+        MasterKeys::new(
+            [1; MASTER_KEY_LEN],
+            [2; MASTER_KEY_LEN],
+            Some([3; MASTER_KEY_LEN]),
+        )
+    }
+
+    fn store_with_keys(password: &str) -> MemoryStore {
+        let store = MemoryStore::default();
+        let dat = EncryptionDat::seal(&test_keys(), password).ok().unwrap();
+        store
+            .objects
+            .lock()
+            .unwrap()
+            .insert(StorageKey::from("C0FFEE/encryptionv3.dat"), dat.to_bytes());
+        store
+    }
+
+    fn stored_keys(store: &MemoryStore, password: &str) -> Result<MasterKeys, RepoError> {
+        let objects = store.objects.lock().unwrap();
+        let dat = objects
+            .get(&StorageKey::from("C0FFEE/encryptionv3.dat"))
+            .unwrap();
+        open_encryption_dat(dat, password)
+    }
+
+    #[tokio::test]
+    async fn password_is_changed() {
+        let store = store_with_keys("old");
+        change_password(&store, COMPUTER, "old", "new")
+            .await
+            .unwrap();
+
+        assert!(same_keys(
+            &stored_keys(&store, "new").unwrap(),
+            &test_keys()
+        ));
+        assert_eq!(
+            stored_keys(&store, "old").err(),
+            Some(RepoError::CryptoError)
+        );
+
+        // the staging file is cleaned up
+        assert_eq!(store.objects.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn wrong_password_changes_nothing() {
+        let store = store_with_keys("old");
+        assert_eq!(
+            change_password(&store, COMPUTER, "wrong", "new").await,
+            Err(RepoError::CryptoError)
+        );
+        assert!(same_keys(
+            &stored_keys(&store, "old").unwrap(),
+            &test_keys()
+        ));
+        assert_eq!(store.objects.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn password_is_verified_against_key_file() {
+        let store = store_with_keys("right");
+        assert_eq!(verify_password(&store, COMPUTER, "right").await, Ok(true));
+        assert_eq!(verify_password(&store, COMPUTER, "wrong").await, Ok(false));
+    }
+
+    #[tokio::test]
+    async fn legacy_password_is_verified_against_bucket_plist() {
+        let plist = br#"<?xml version="1.0" encoding="UTF-8"?>
+            <plist version="1.0">
+            <dict>
+                <key>BucketUUID</key>
+                <string>408E376B-ECF7-4688-902A-1E7671BC5B9A</string>
+                <key>BucketName</key>
+                <string>company</string>
+                <key>LocalPath</key>
+                <string>/Users/stefan/src/company</string>
+            </dict>
+            </plist>"#;
+
+        let key = CryptoKey::new("right", b"BucketPL").ok().unwrap();
+        let mut object = b"encrypted".to_vec();
+        object.extend(key.encrypt(plist).ok().unwrap());
+
+        let store = MemoryStore::default();
+        store.objects.lock().unwrap().insert(
+            StorageKey::from("C0FFEE/buckets/408E376B-ECF7-4688-902A-1E7671BC5B9A"),
+            object,
+        );
+
+        assert_eq!(verify_password(&store, COMPUTER, "right").await, Ok(true));
+        assert_eq!(verify_password(&store, COMPUTER, "wrong").await, Ok(false));
+
+        let empty = MemoryStore::default();
+        assert_eq!(
+            verify_password(&empty, COMPUTER, "right").await,
+            Err(RepoError::Storage(StorageError::NoSuchObject))
+        );
+
+        let unencrypted = MemoryStore::default();
+        unencrypted.objects.lock().unwrap().insert(
+            StorageKey::from("C0FFEE/buckets/408E376B-ECF7-4688-902A-1E7671BC5B9A"),
+            plist.to_vec(),
+        );
+        assert_eq!(
+            verify_password(&unencrypted, COMPUTER, "right").await,
+            Err(RepoError::Storage(StorageError::NoSuchObject))
+        );
+    }
+
+    #[test]
+    fn blob_salts_follow_the_key_file_version() {
+        let computer_id = "600150F6-70BB-47C6-A538-6F3A2258D524";
+
+        let v3 = Keyset::Master(test_keys());
+        assert_eq!(
+            v3.blob_salt(computer_id)
+                .as_ref()
+                .map(SecretBytes::as_bytes),
+            Some(&[3; 32][..])
+        );
+
+        let v2 = Keyset::Master(MasterKeys::new([1; 32], [2; 32], None));
+        assert_eq!(
+            v2.blob_salt(computer_id)
+                .as_ref()
+                .map(SecretBytes::as_bytes),
+            Some(computer_id.as_bytes())
+        );
+
+        let legacy = Keyset::Legacy(CryptoKey::new("hunter2", b"saltsalt").ok().unwrap());
+        assert!(legacy.blob_salt(computer_id).is_none());
+    }
+
+    #[test]
+    fn tree_cache_keys_are_derived_from_the_computer_keys() {
+        let keys = Keyset::Master(test_keys()).tree_cache_keys().unwrap();
+        assert_eq!(keys.len(), 64);
+        assert!(!test_keys()
+            .as_bytes()
+            .windows(32)
+            .any(|w| keys.as_bytes().windows(32).any(|k| k == w)));
+
+        let again = Keyset::Master(test_keys()).tree_cache_keys().unwrap();
+        assert_eq!(keys.as_bytes(), again.as_bytes());
+
+        let other = Keyset::Master(MasterKeys::new([4; 32], [2; 32], None));
+        let other = other.tree_cache_keys().unwrap();
+        assert_ne!(keys.as_bytes(), other.as_bytes());
+
+        let legacy = Keyset::Legacy(CryptoKey::new("hunter2", b"saltsalt").ok().unwrap());
+        assert_eq!(legacy.tree_cache_keys().map(|k| k.len()), Some(64));
+    }
+
+    #[test]
+    fn keysets_survive_serialisation() {
+        let legacy = CryptoKey::new("pw", b"saltsalt").ok().unwrap();
+        let ciphertext = legacy.encrypt(b"legacy").ok().unwrap();
+        let bytes = Keyset::Legacy(legacy).to_secret();
+        let decrypter = Keyset::from_secret(bytes.as_bytes())
+            .unwrap()
+            .into_decrypter();
+        assert_eq!(
+            decrypter.decrypt_object(&ciphertext).ok(),
+            Some(b"legacy".to_vec())
+        );
+
+        let bytes = Keyset::Master(test_keys()).to_secret();
+        match Keyset::from_secret(bytes.as_bytes()) {
+            Some(Keyset::Master(keys)) => assert!(same_keys(&keys, &test_keys())),
+            _ => panic!("Expected master keys"),
+        }
+
+        let legacy = CryptoKey::new("pw", b"saltsalt").ok().unwrap();
+        let bytes = Keyset::Migrated(test_keys(), legacy).to_secret();
+        match Keyset::from_secret(bytes.as_bytes()) {
+            Some(Keyset::Migrated(keys, _)) => assert!(same_keys(&keys, &test_keys())),
+            _ => panic!("Expected migrated keys"),
+        }
+
+        assert!(Keyset::from_secret(&[]).is_none());
+        assert!(Keyset::from_secret(&[LEGACY_TAG, 32, 0]).is_none());
+        assert!(Keyset::from_secret(&[LEGACY_TAG, 1, 0, 0, 0]).is_none());
+        assert!(Keyset::from_secret(&[MASTER_TAG, 0]).is_none());
+        assert!(Keyset::from_secret(&[MIGRATED_TAG, 1, 0, 0, 0]).is_none());
+    }
+
+    #[tokio::test]
+    async fn migrated_sets_decrypt_objects_of_both_kinds() {
+        let store = store_with_keys("pw");
+        store
+            .objects
+            .lock()
+            .unwrap()
+            .insert(StorageKey::from("C0FFEE/salt"), b"saltsalt".to_vec());
+        let key_file = fetch_encryption_dat(&store, COMPUTER)
+            .await
+            .unwrap()
+            .map(|(_, content)| content);
+
+        let keyset = derive_keyset(&store, COMPUTER, "pw", key_file.as_deref())
+            .await
+            .unwrap();
+        assert!(matches!(keyset, Keyset::Migrated(..)));
+        let decrypter = keyset.into_decrypter();
+
+        let legacy = CryptoKey::new("pw", b"saltsalt").ok().unwrap();
+        let old = [&b"encrypted"[..], &legacy.encrypt(b"old").ok().unwrap()].concat();
+        let new = ObjectEncrypterV2::new(test_keys())
+            .encrypt_object(b"new")
+            .ok()
+            .unwrap();
+        assert_eq!(decrypter.decrypt_object(&old).ok(), Some(b"old".to_vec()));
+        assert_eq!(decrypter.decrypt_object(&new).ok(), Some(b"new".to_vec()));
+
+        // Without a salt, only the master keys are needed
+        let store = store_with_keys("pw");
+        let keyset = derive_keyset(&store, COMPUTER, "pw", key_file.as_deref())
+            .await
+            .unwrap();
+        assert!(matches!(keyset, Keyset::Master(_)));
+    }
+
+    /// Mangles the first write to the live key file
+    struct CorruptingStore {
+        inner: MemoryStore,
+        corrupted: AtomicBool,
+    }
+
+    #[async_trait]
+    impl Store for CorruptingStore {
+        async fn list_contents(
+            &self,
+            path: &str,
+            flags: Include,
+        ) -> StorageResult<Vec<ObjectInfo>> {
+            self.inner.list_contents(path, flags).await
+        }
+
+        async fn get(&self, key: StorageKey) -> StorageResult<Vec<u8>> {
+            self.inner.get(key).await
+        }
+
+        async fn put(&self, key: StorageKey, mut content: Vec<u8>) -> StorageResult<()> {
+            if key.ends_with(".dat") && !self.corrupted.swap(true, Ordering::SeqCst) {
+                content.truncate(content.len() / 2);
+            }
+            self.inner.put(key, content).await
+        }
+
+        async fn delete(&self, key: StorageKey) -> StorageResult<()> {
+            self.inner.delete(key).await
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_replacement_is_rolled_back() {
+        let store = Arc::new(CorruptingStore {
+            inner: store_with_keys("old"),
+            corrupted: AtomicBool::new(false),
+        });
+
+        assert!(change_password(store.as_ref(), COMPUTER, "old", "new")
+            .await
+            .is_err());
+        assert!(same_keys(
+            &stored_keys(&store.inner, "old").unwrap(),
+            &test_keys()
+        ));
+    }
+}
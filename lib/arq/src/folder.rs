@@ -1,19 +1,24 @@
 use std::{
+    collections::{HashSet, VecDeque},
     convert::TryInto,
+    io::Write,
     path::{Path, PathBuf},
     sync::Arc,
 };
 
 use crate::{
-    commit::Commit,
-    crypto::ObjectDecrypter,
+    commit::{blob_matches, unpack_blob, Commit, CommitSummary, PathLookup},
+    crypto::{ObjectDecrypter, SecretBytes},
     format_uuid,
     packset::Packset,
+    reflog::{self, ReflogEntry},
+    selector::CommitSelector,
     storage::{self, Store},
-    RepoError, SHA1,
+    Node, RepoError, TreeCache, SHA1,
 };
 
-use log::info;
+use futures::{future, lock::Mutex, pin_mut, stream, Stream, TryFutureExt, TryStreamExt};
+use log::{error, info, warn};
 use serde::Deserialize;
 use uuid::Uuid;
 
@@ -29,11 +34,54 @@ pub struct FolderInfo {
     local_path: PathBuf,
 }
 
+impl FolderInfo {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn local_path(&self) -> &Path {
+        &self.local_path
+    }
+}
+
+/// One version of a file or directory, as found by `Folder::path_history`
+#[derive(Debug)]
+pub struct PathVersion {
+    /// The node as it is in the newest of the commits. Its content and
+    /// modification time are the same in all of them.
+    pub node: Node,
+
+    /// The commits the version appears in, newest first
+    pub commits: Vec<CommitSummary>,
+}
+
+/// The commits `Folder::orphaned_commits` found
+pub struct Orphans<'a> {
+    pub commits: Vec<Commit<'a>>,
+
+    /// Commits that were written before Arq stretched keys, and so can't be
+    /// read
+    pub unsupported: Vec<SHA1>,
+}
+
 pub struct Folder {
     pub info: FolderInfo,
     packset: Packset,
     decrypter: Arc<dyn ObjectDecrypter>,
     computer_id: String,
+
+    /// The packset holding small file blobs, loaded the first time it's
+    /// needed
+    blobs: Mutex<Option<Arc<Packset>>>,
+
+    /// Blobs are verified if this is set
+    blob_salt: Option<Arc<SecretBytes>>,
+
+    tree_cache: Option<Arc<dyn TreeCache>>,
 }
 
 impl Folder {
@@ -49,11 +97,34 @@ impl Folder {
             packset,
             decrypter: decrypter.clone(),
             computer_id: computer_id.to_owned(),
+            blobs: Mutex::new(None),
+            blob_salt: None,
+            tree_cache: None,
         };
         Ok(f)
     }
 
+    pub(crate) fn with_blob_salt(self, salt: Option<Arc<SecretBytes>>) -> Folder {
+        Folder {
+            blob_salt: salt,
+            ..self
+        }
+    }
+
+    pub(crate) fn with_tree_cache(self, cache: Option<Arc<dyn TreeCache>>) -> Folder {
+        Folder {
+            tree_cache: cache,
+            ..self
+        }
+    }
+
     pub async fn get_latest_commit(&'_ self) -> Result<Commit<'_>, RepoError> {
+        let commit_sha = self.head_id().await?;
+        self.get_commit(commit_sha).await
+    }
+
+    /// The SHA1 of the folder's most recent commit
+    async fn head_id(&self) -> Result<SHA1, RepoError> {
         let key = storage::Key::from(format!(
             "{}/bucketdata/{}/refs/heads/master",
             self.computer_id,
@@ -66,28 +137,396 @@ impl Folder {
             .await
             .map_err(RepoError::Storage)?;
 
-        let commit_sha = String::from_utf8(content)
+        String::from_utf8(content)
             .ok()
             .and_then(|s| hex::decode(&s[..s.len() - 1]).ok())
             .and_then(|v| v.try_into().ok())
-            .ok_or(RepoError::MalformedData)?;
-
-        self.get_commit(commit_sha).await
+            .ok_or(RepoError::MalformedData)
     }
 
+    /// Loads a commit, from the tree cache if it's there. With blob
+    /// verification on, the commit has to match its SHA1, and a cached one
+    /// that doesn't is fetched again.
     pub async fn get_commit(&'_ self, commit_id: SHA1) -> Result<Commit<'_>, RepoError> {
+        let salt = self.blob_salt.as_deref();
+        let cached = self
+            .tree_cache
+            .as_ref()
+            .and_then(|cache| cache.load(&commit_id));
+        if let Some(data) = cached {
+            if !blob_matches(&data, &commit_id, salt) {
+                warn!(
+                    "Ignoring cached commit {} that doesn't match its SHA1",
+                    commit_id
+                );
+            } else {
+                match Commit::parse(commit_id.clone(), &data, &self.packset, &self.decrypter) {
+                    Ok(commit) => return Ok(self.prepare_commit(commit)),
+                    Err(_) => warn!("Ignoring unreadable cached commit {}", commit_id),
+                }
+            }
+        }
+
         log::info!("Loading commit {}", commit_id);
-        self.packset.load(&commit_id).await.and_then(|blob| {
-            self.decrypter
-                .decrypt_object(&blob.content)
-                .map_err(|_e| RepoError::CryptoError)
-                .and_then(|d| Commit::parse(&d, &self.packset, &self.decrypter))
+        let blob = self.packset.load(&commit_id).await?;
+        let data = self
+            .decrypter
+            .decrypt_object(&blob.content)
+            .map_err(|_e| RepoError::CryptoError)?;
+        if !blob_matches(&data, &commit_id, salt) {
+            error!("Commit {} doesn't match its SHA1", commit_id);
+            return Err(RepoError::CorruptBlob {
+                sha: commit_id,
+                path: PathBuf::new(),
+            });
+        }
+        let commit = Commit::parse(commit_id.clone(), &data, &self.packset, &self.decrypter)?;
+        if let Some(cache) = self.tree_cache.as_ref() {
+            cache.save(&commit_id, &data);
+        }
+        Ok(self.prepare_commit(commit))
+    }
+
+    fn prepare_commit<'a>(&self, commit: Commit<'a>) -> Commit<'a> {
+        commit
+            .with_blob_salt(self.blob_salt.clone())
+            .with_tree_cache(self.tree_cache.clone())
+    }
+
+    /// Loads the commit that `selector` picks out. Prefixes and times are
+    /// resolved against the history, so they can't select orphaned commits.
+    pub async fn select_commit(
+        &'_ self,
+        selector: &CommitSelector,
+    ) -> Result<Commit<'_>, RepoError> {
+        let not_found = || RepoError::Storage(storage::Error::NoSuchObject);
+
+        match selector {
+            CommitSelector::Sha(sha) => self.get_commit(sha.clone()).await,
+
+            CommitSelector::Head(n) => {
+                let mut commit = self.get_latest_commit().await?;
+                for _ in 0..*n {
+                    let (parent, stretched) = commit
+                        .parent_keys()
+                        .next()
+                        .map(|(id, stretched)| (id.clone(), stretched))
+                        .ok_or_else(not_found)?;
+                    commit = self.get_parent_commit(parent, stretched).await?;
+                }
+                Ok(commit)
+            }
+
+            CommitSelector::Prefix(prefix) => {
+                let mut matches: Vec<_> = self
+                    .history()
+                    .try_filter(|c| future::ready(c.id().as_string().starts_with(prefix)))
+                    .try_collect()
+                    .await?;
+                if matches.len() > 1 {
+                    error!("{} commits start with {}", matches.len(), prefix);
+                    return Err(RepoError::InputError);
+                }
+                matches.pop().ok_or_else(not_found)
+            }
+
+            // Parents aren't always older than their children, e.g. after
+            // the clock was put back, so the whole history has to be seen
+            CommitSelector::AsOf(time) => self
+                .history()
+                .try_filter(|c| future::ready(c.timestamp() <= time))
+                .try_fold(None, |newest: Option<Commit<'_>>, c| async move {
+                    Ok(match newest {
+                        Some(newest) if newest.timestamp() >= c.timestamp() => Some(newest),
+                        _ => Some(c),
+                    })
+                })
+                .await?
+                .ok_or_else(not_found),
+        }
+    }
+
+    /// Walks the folder's backup records from the latest commit back through
+    /// their parents, newest first. Each commit is only visited once, and a
+    /// parent that no longer exists (e.g. because Arq has thinned out old
+    /// backups) or that predates key stretching ends that line of history.
+    /// Any other error ends the stream.
+    pub fn history(&self) -> impl Stream<Item = Result<Commit<'_>, RepoError>> + '_ {
+        self.walk_history(None)
+    }
+
+    /// As `history`, but starting from `start` instead of the head
+    pub fn history_from<'a>(
+        &'a self,
+        start: Commit<'a>,
+    ) -> impl Stream<Item = Result<Commit<'a>, RepoError>> + 'a {
+        self.walk_history(Some(start))
+    }
+
+    fn walk_history<'a>(
+        &'a self,
+        start: Option<Commit<'a>>,
+    ) -> impl Stream<Item = Result<Commit<'a>, RepoError>> + 'a {
+        struct Walk<'a> {
+            /// The first commit, with `None` for the head, until it's visited
+            start: Option<Option<Commit<'a>>>,
+            pending: VecDeque<(SHA1, bool)>,
+            seen: HashSet<SHA1>,
+        }
+
+        let walk = Walk {
+            start: Some(start),
+            pending: VecDeque::new(),
+            seen: HashSet::new(),
+        };
+
+        stream::try_unfold(walk, move |mut walk| async move {
+            loop {
+                let commit = if let Some(start) = walk.start.take() {
+                    let first = match start {
+                        Some(commit) => commit,
+                        None => self.get_latest_commit().await?,
+                    };
+                    walk.seen.insert(first.id().clone());
+                    first
+                } else {
+                    let (id, stretched) = match walk.pending.pop_front() {
+                        Some(next) => next,
+                        None => return Ok(None),
+                    };
+                    if !walk.seen.insert(id.clone()) {
+                        continue;
+                    }
+                    match self.get_parent_commit(id, stretched).await {
+                        Err(RepoError::Storage(storage::Error::NoSuchObject)) => {
+                            info!("Reached the end of the retained history");
+                            continue;
+                        }
+                        Err(RepoError::Unsupported) => continue,
+                        result => result?,
+                    }
+                };
+
+                for (parent, stretched) in commit.parent_keys() {
+                    walk.pending.push_back((parent.clone(), stretched));
+                }
+                return Ok(Some((commit, walk)));
+            }
         })
     }
 
+    /// Finds each distinct version of the file or directory at `path`,
+    /// relative to the root of the backup or under the folder's local path
+    /// (see `relative_path`), in the history of the commit
+    /// `from` selects. Versions are told apart by their content and
+    /// modification time, so a file changed back to what it was is a new
+    /// version, and come newest first. Only the trees along the path are
+    /// loaded, and only until they match those of a commit that has
+    /// already been looked at.
+    pub async fn path_history(
+        &self,
+        path: &Path,
+        from: &CommitSelector,
+    ) -> Result<Vec<PathVersion>, RepoError> {
+        let mut lookup = PathLookup::new(self.relative_path(path))?;
+        let mut versions: Vec<PathVersion> = Vec::new();
+
+        let start = self.select_commit(from).await?;
+        let history = self.history_from(start);
+        pin_mut!(history);
+        while let Some(commit) = history.try_next().await? {
+            let node = match commit.find(&mut lookup).await? {
+                Some(node) => node,
+                None => continue,
+            };
+            let same = |v: &&mut PathVersion| {
+                v.node.same_content(&node) && v.node.mod_time == node.mod_time
+            };
+            match versions.iter_mut().find(same) {
+                Some(version) => version.commits.push(commit.summary()),
+                None => versions.push(PathVersion {
+                    node,
+                    commits: vec![commit.summary()],
+                }),
+            }
+        }
+        Ok(versions)
+    }
+
+    /// Reads the folder's reflog, oldest entry first. Entries that can't be
+    /// read are skipped with a warning.
+    pub async fn reflog(&self) -> Result<Vec<ReflogEntry>, RepoError> {
+        let path = format!(
+            "{}/bucketdata/{}/refs/logs/master/",
+            self.computer_id,
+            format_uuid(&self.info.id)
+        );
+        let store = self.packset.store();
+        let objects = store
+            .list_contents(&path, storage::Include::FILES)
+            .await
+            .map_err(RepoError::Storage)?;
+
+        let fetch_tasks = objects.into_iter().map(|o| {
+            store
+                .get(o.key.clone())
+                .map_ok(move |content| (o.key, content))
+        });
+        let contents = throttled::try_join_all(5, fetch_tasks)
+            .await
+            .map_err(RepoError::Storage)?;
+
+        let mut entries: Vec<_> = contents
+            .iter()
+            .filter_map(|(key, content)| {
+                let id = key.as_str().rsplit('/').next().unwrap_or_default();
+                reflog::decode_entry(id, content, self.decrypter.as_ref())
+                    .map_err(|e| warn!("Skipping reflog entry {}: {:?}", key, e))
+                    .ok()
+            })
+            .collect();
+        entries.sort_by(ReflogEntry::chronological);
+        Ok(entries)
+    }
+
+    /// Finds the commits that can't be reached from the head, e.g. because
+    /// their backup records were deleted: those named in the reflog, and
+    /// their ancestors. They're returned in the order the reflog mentions
+    /// them, each followed by its otherwise unreachable ancestors. Commits
+    /// whose data has since been removed altogether are skipped, and those
+    /// that predate key stretching are listed apart.
+    pub async fn orphaned_commits(&'_ self) -> Result<Orphans<'_>, RepoError> {
+        let mut seen: HashSet<SHA1> = self
+            .history()
+            .map_ok(|c| c.id().clone())
+            .try_collect()
+            .await?;
+
+        let mut orphans = Orphans {
+            commits: Vec::new(),
+            unsupported: Vec::new(),
+        };
+        for entry in self.reflog().await? {
+            let old_head_stretch_key = entry.old_head_stretch_key;
+            let mut pending: VecDeque<_> = entry
+                .old_head
+                .map(|id| (id, old_head_stretch_key))
+                .into_iter()
+                .chain(Some((entry.new_head, entry.new_head_stretch_key)))
+                .collect();
+
+            while let Some((id, stretched)) = pending.pop_front() {
+                if !seen.insert(id.clone()) {
+                    continue;
+                }
+                match self.get_parent_commit(id.clone(), stretched).await {
+                    Ok(commit) => {
+                        pending.extend(commit.parent_keys().map(|(p, s)| (p.clone(), s)));
+                        orphans.commits.push(commit);
+                    }
+                    Err(RepoError::Storage(storage::Error::NoSuchObject)) => {
+                        info!("An orphaned commit has been removed from storage");
+                    }
+                    Err(RepoError::Unsupported) => orphans.unsupported.push(id),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// Loads a commit that another refers to, failing with `NoSuchObject` if
+    /// it is no longer in the packset. Commits written before Arq started
+    /// stretching keys are flagged as such by their children; we only ever
+    /// derive stretched keys, so those are `Unsupported`.
+    async fn get_parent_commit(
+        &'_ self,
+        id: SHA1,
+        stretched: bool,
+    ) -> Result<Commit<'_>, RepoError> {
+        if !self.packset.contains(&id) {
+            return Err(RepoError::Storage(storage::Error::NoSuchObject));
+        }
+        if !stretched {
+            warn!(
+                "Commit {} predates key stretching, which isn't supported",
+                id
+            );
+            return Err(RepoError::Unsupported);
+        }
+        self.get_commit(id).await
+    }
+
     pub fn local_path(&self) -> &Path {
         &self.info.local_path
     }
+
+    /// Turns a path on the backed-up machine, under the folder's local
+    /// path, into one relative to the root of the backup. Any other path is
+    /// taken to be relative to the backup already.
+    pub fn relative_path<'p>(&self, path: &'p Path) -> &'p Path {
+        path.strip_prefix(self.local_path()).unwrap_or(path)
+    }
+
+    /// Writes the content of a file into `out`. Each blob is decrypted and
+    /// decompressed as it is written, so only one encrypted blob is held in
+    /// memory at a time. Returns the number of bytes written.
+    ///
+    /// With blob verification on, a blob that doesn't match its SHA1 fails
+    /// the restore with a `RepoError::CorruptBlob` naming the node, but only
+    /// after its data has been written.
+    pub async fn restore_file(&self, node: &Node, out: &mut dyn Write) -> Result<u64, RepoError> {
+        if node.is_tree {
+            return Err(RepoError::InputError);
+        }
+
+        let path = Path::new(&node.name);
+        let mut written = 0;
+        for key in node.data_blob_keys.iter() {
+            let object = self.fetch_object(&key.sha).await?;
+            written += unpack_blob(
+                &object,
+                key,
+                self.decrypter.as_ref(),
+                node.data_compression_type,
+                self.blob_salt.as_deref(),
+                path,
+                out,
+            )?;
+        }
+        Ok(written)
+    }
+
+    /// Fetches an encrypted blob. Large blobs are stored as objects in their
+    /// own right, everything else is in the folder's blobs packset.
+    async fn fetch_object(&self, sha: &SHA1) -> Result<Vec<u8>, RepoError> {
+        let key = storage::Key::from(format!("{}/objects/{}", self.computer_id, sha.as_string()));
+        match self.packset.store().get(key).await {
+            Ok(content) => return Ok(content),
+            Err(storage::Error::NoSuchObject) => {}
+            Err(e) => return Err(RepoError::Storage(e)),
+        }
+
+        let blobs = self.blob_packset().await?;
+        blobs.load(sha).await.map(|obj| obj.content)
+    }
+
+    async fn blob_packset(&self) -> Result<Arc<Packset>, RepoError> {
+        let mut blobs = self.blobs.lock().await;
+        if let Some(packset) = blobs.as_ref() {
+            return Ok(packset.clone());
+        }
+
+        info!("Fetching blob pack index");
+        let key = storage::Key::from(format!(
+            "{}/packsets/{}-blobs/",
+            self.computer_id,
+            format_uuid(&self.info.id)
+        ));
+        let packset = Arc::new(Packset::new(key, self.packset.store()).await?);
+        *blobs = Some(packset.clone());
+        Ok(packset)
+    }
 }
 
 async fn load_packset(
@@ -108,13 +547,393 @@ async fn load_packset(
 
 #[cfg(test)]
 mod test {
-    use super::FolderInfo;
+    use super::*;
+    use crate::{
+        crypto::{ObjectDecrypterV2, ObjectEncrypter, ObjectEncrypterV2},
+        mocks::{
+            commit_record, keys, node, sha, tree_objects, write_packset, MemoryStore,
+            MemoryTreeCache, TestNode,
+        },
+    };
+    use chrono::prelude::*;
+    use futures::TryStreamExt;
+
+    const FOLDER: &str = "408E376B-ECF7-4688-902A-1E7671BC5B9A";
+
+    /// Stores a chain of commits, each following on from the ones listed
+    /// with it, and makes the first one the head
+    async fn folder_with_commits(commits: &[(u8, &[u8])]) -> (Arc<MemoryStore>, Folder) {
+        let commits: Vec<_> = commits
+            .iter()
+            .map(|(id, parents)| (*id, *parents, 0xEE))
+            .collect();
+        folder_with_backups(&commits, &[]).await
+    }
+
+    /// As `folder_with_commits`, but each commit names its root tree, and
+    /// the trees are stored too
+    async fn folder_with_backups(
+        commits: &[(u8, &[u8], u8)],
+        trees: &[(u8, Vec<TestNode<'_>>)],
+    ) -> (Arc<MemoryStore>, Folder) {
+        let encrypter = ObjectEncrypterV2::new(keys());
+        let mut objects: Vec<_> = commits
+            .iter()
+            .map(|(id, parents, tree)| {
+                let parents: Vec<_> = parents.iter().map(|p| sha(*p)).collect();
+                let timestamp = Utc.timestamp(1_600_000_000 + *id as i64 * 86400, 0);
+                let record = commit_record(&parents, &sha(*tree), &timestamp);
+                (sha(*id), encrypter.encrypt_object(&record).ok().unwrap())
+            })
+            .collect();
+        objects.extend(tree_objects(trees));
+
+        let store = Arc::new(MemoryStore::default());
+        let trees = format!("C0FFEE/packsets/{}-trees/", FOLDER);
+        write_packset(&store, &trees, &objects);
+        if let Some((head, _, _)) = commits.first() {
+            store.objects.lock().unwrap().insert(
+                storage::Key::from(format!("C0FFEE/bucketdata/{}/refs/heads/master", FOLDER)),
+                format!("{}\n", sha(*head)).into_bytes(),
+            );
+        }
+
+        let info = FolderInfo {
+            id: Uuid::parse_str(FOLDER).unwrap(),
+            name: "larq".to_string(),
+            local_path: PathBuf::from("/Users/larq"),
+        };
+        let decrypter: Arc<dyn ObjectDecrypter> = Arc::new(ObjectDecrypterV2::new(keys()));
+        let dyn_store: Arc<dyn Store> = store.clone();
+        let folder = Folder::new("C0FFEE", info, &dyn_store, &decrypter)
+            .await
+            .unwrap();
+        (store, folder)
+    }
+
+    #[tokio::test]
+    async fn commits_are_cached() {
+        let (store, folder) = folder_with_commits(&[(2, &[1]), (1, &[])]).await;
+        let cache = Arc::new(MemoryTreeCache::default());
+        let folder = folder.with_tree_cache(Some(cache.clone()));
+
+        let commit = folder.get_commit(sha(2)).await.unwrap();
+        assert_eq!(commit.parents().collect::<Vec<_>>(), vec![&sha(1)]);
+        assert!(cache.load(&sha(2)).is_some());
+        assert!(cache.load(&sha(1)).is_none());
+
+        // Without the packs, only the cached commit can be loaded
+        store
+            .objects
+            .lock()
+            .unwrap()
+            .retain(|k, _| !k.as_str().ends_with(".pack"));
+        let commit = folder.get_commit(sha(2)).await.unwrap();
+        assert_eq!(commit.id(), &sha(2));
+        assert!(folder.get_commit(sha(1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn versions_of_a_path_are_found() {
+        // docs/a.txt is added, changed, then changed back and deleted. The
+        // second backup only adds b.txt, so its docs tree is the first's.
+        // Changing it back gives it the old content but a new time.
+        let (store, folder) = folder_with_backups(
+            &[
+                (5, &[4], 0x15),
+                (4, &[3], 0x14),
+                (3, &[2], 0x13),
+                (2, &[1], 0x12),
+                (1, &[], 0x11),
+            ],
+            &[
+                (0x11, vec![node("docs", true, 0x21)]),
+                (0x21, vec![node("a.txt", false, 0xF1)]),
+                (
+                    0x12,
+                    vec![node("b.txt", false, 0xF2), node("docs", true, 0x21)],
+                ),
+                (0x13, vec![node("docs", true, 0x23)]),
+                (0x23, vec![node("a.txt", false, 0xF3)]),
+                (0x14, vec![node("docs", true, 0x24)]),
+                (
+                    0x24,
+                    vec![
+                        node("a.txt", false, 0xF1).with_mod_time(86400),
+                        node("c", true, 0x25),
+                    ],
+                ),
+                (0x15, vec![]),
+            ],
+        )
+        .await;
+        let head = CommitSelector::default();
+
+        let before = store.gets.load(std::sync::atomic::Ordering::SeqCst);
+        let versions = folder
+            .path_history(Path::new("docs/a.txt"), &head)
+            .await
+            .unwrap();
+        let summary: Vec<_> = versions
+            .iter()
+            .map(|v| {
+                let commits: Vec<_> = v.commits.iter().map(|c| c.id.clone()).collect();
+                (v.node.data_blob_keys[0].sha.clone(), commits)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (sha(0xF1), vec![sha(4)]),
+                (sha(0xF3), vec![sha(3)]),
+                (sha(0xF1), vec![sha(2), sha(1)]),
+            ]
+        );
+        assert_eq!(versions[0].node.mod_time, Utc.timestamp(86400, 0));
+        assert_eq!(versions[2].node.mod_time, Utc.timestamp(0, 0));
+
+        // The head, five commits and five root trees, but only three docs
+        // trees, since the first backup's is the second's
+        let gets = store.gets.load(std::sync::atomic::Ordering::SeqCst) - before;
+        assert_eq!(gets, 1 + 5 + 5 + 3);
+
+        assert_eq!(
+            folder
+                .path_history(Path::new("docs/a.txt/x"), &head)
+                .await
+                .unwrap()
+                .len(),
+            0
+        );
+        assert_eq!(
+            folder
+                .path_history(Path::new("docs/c"), &head)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            folder.path_history(Path::new(""), &head).await.unwrap_err(),
+            RepoError::InputError
+        );
+
+        // Only the history of the selected commit is searched
+        let versions = folder
+            .path_history(Path::new("docs/a.txt"), &CommitSelector::Sha(sha(3)))
+            .await
+            .unwrap();
+        let blobs: Vec<_> = versions
+            .iter()
+            .map(|v| v.node.data_blob_keys[0].sha.clone())
+            .collect();
+        assert_eq!(blobs, vec![sha(0xF3), sha(0xF1)]);
+
+        // Paths on the backed-up machine work too
+        let versions = folder
+            .path_history(Path::new("/Users/larq/docs/a.txt"), &head)
+            .await
+            .unwrap();
+        assert_eq!(versions.len(), 3);
+        assert_eq!(
+            folder.relative_path(Path::new("/Users/larq/docs")),
+            Path::new("docs")
+        );
+        assert_eq!(folder.relative_path(Path::new("docs")), Path::new("docs"));
+    }
+
+    #[tokio::test]
+    async fn history_follows_parents_newest_first() {
+        // 3 and 2 both name 1 as a parent, and 1's parent has been thinned
+        // out
+        let (_, folder) = folder_with_commits(&[(3, &[2, 1]), (2, &[1]), (1, &[0])]).await;
+
+        let history: Vec<_> = folder
+            .history()
+            .map_ok(|c| (c.id().clone(), c.timestamp().day()))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(history, vec![(sha(3), 16), (sha(2), 15), (sha(1), 14)]);
+    }
+
+    #[tokio::test]
+    async fn history_needs_a_head() {
+        let (store, folder) = folder_with_commits(&[(1, &[])]).await;
+        store
+            .objects
+            .lock()
+            .unwrap()
+            .retain(|k, _| !k.as_str().contains("refs"));
+
+        let result: Result<Vec<_>, _> = folder
+            .history()
+            .map_ok(|c| c.id().clone())
+            .try_collect()
+            .await;
+        assert_eq!(
+            result,
+            Err(RepoError::Storage(storage::Error::NoSuchObject))
+        );
+    }
+
+    #[tokio::test]
+    async fn parents_are_loaded_if_supported() {
+        let (_, folder) = folder_with_commits(&[(2, &[1]), (1, &[])]).await;
+
+        let parent = folder.get_parent_commit(sha(1), true).await;
+        assert_eq!(parent.map(|c| c.id().clone()), Ok(sha(1)));
+        assert_eq!(
+            folder.get_parent_commit(sha(1), false).await.err(),
+            Some(RepoError::Unsupported)
+        );
+        assert_eq!(
+            folder.get_parent_commit(sha(9), true).await.err(),
+            Some(RepoError::Storage(storage::Error::NoSuchObject))
+        );
+
+        // Asking for a commit by ID presumes it's there
+        assert_eq!(
+            folder.get_commit(sha(9)).await.err(),
+            Some(RepoError::MalformedData)
+        );
+    }
+
+    #[tokio::test]
+    async fn commits_are_selected() {
+        let (_, folder) = folder_with_commits(&[(3, &[2]), (2, &[1]), (1, &[])]).await;
+        let select = |selector: CommitSelector| {
+            let folder = &folder;
+            async move {
+                folder
+                    .select_commit(&selector)
+                    .await
+                    .map(|c| c.id().clone())
+            }
+        };
+        let not_found = Err(RepoError::Storage(storage::Error::NoSuchObject));
+
+        assert_eq!(select(CommitSelector::Head(0)).await, Ok(sha(3)));
+        assert_eq!(select(CommitSelector::Head(2)).await, Ok(sha(1)));
+        assert_eq!(select(CommitSelector::Head(3)).await, not_found);
+
+        assert_eq!(select(CommitSelector::Sha(sha(2))).await, Ok(sha(2)));
+        assert_eq!(
+            select(CommitSelector::Prefix("0202".to_string())).await,
+            Ok(sha(2))
+        );
+        assert_eq!(
+            select(CommitSelector::Prefix("ffff".to_string())).await,
+            not_found
+        );
+
+        // The commits are at 12:26:40 on the 14th, 15th and 16th
+        let as_of = |d, h, m, s| CommitSelector::AsOf(Utc.ymd(2020, 9, d).and_hms(h, m, s));
+        assert_eq!(select(as_of(15, 13, 0, 0)).await, Ok(sha(2)));
+        assert_eq!(select(as_of(15, 12, 26, 40)).await, Ok(sha(2)));
+        assert_eq!(select(as_of(15, 12, 26, 39)).await, Ok(sha(1)));
+        assert_eq!(select(as_of(14, 0, 0, 0)).await, not_found);
+    }
+
+    #[tokio::test]
+    async fn as_of_picks_the_newest_commit_anywhere_in_the_history() {
+        // The head, 2, is older than its parent 3
+        let (_, folder) = folder_with_commits(&[(2, &[3]), (3, &[1]), (1, &[])]).await;
+        let as_of = |d| CommitSelector::AsOf(Utc.ymd(2020, 9, d).and_hms(23, 0, 0));
+
+        let commit = folder.select_commit(&as_of(16)).await.unwrap();
+        assert_eq!(commit.id(), &sha(3));
+        let commit = folder.select_commit(&as_of(15)).await.unwrap();
+        assert_eq!(commit.id(), &sha(2));
+    }
+
+    fn reflog_entry(old_head: Option<u8>, new_head: u8, stretched: bool) -> Vec<u8> {
+        let old_head = old_head
+            .map(|id| format!("<key>oldHeadSHA1</key><string>{}</string>", sha(id)))
+            .unwrap_or_default();
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <plist version="1.0">
+            <dict>
+                {}
+                <key>newHeadSHA1</key>
+                <string>{}</string>
+                <key>newHeadStretchKey</key>
+                <{}/>
+                <key>isRewrite</key>
+                <{}/>
+            </dict>
+            </plist>"#,
+            old_head,
+            sha(new_head),
+            stretched,
+            old_head.is_empty()
+        )
+        .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn orphaned_commits_are_found_through_the_reflog() {
+        // 4 was the head until its backup record was deleted, and 5 has been
+        // removed from storage entirely. 6 and its parent 7 were deleted
+        // too, and 7's entry is gone. 8 predates key stretching.
+        let (store, folder) = folder_with_commits(&[
+            (3, &[2]),
+            (2, &[1]),
+            (1, &[]),
+            (4, &[2]),
+            (6, &[7]),
+            (7, &[2]),
+            (8, &[]),
+        ])
+        .await;
+
+        let entries = [
+            ("1000", None, 1),
+            ("2000", Some(1), 2),
+            ("3000", Some(2), 5),
+            ("3500", Some(5), 4),
+            ("3700", Some(4), 6),
+            ("10000", Some(6), 3),
+        ];
+        for (name, old_head, new_head) in entries.iter() {
+            store.objects.lock().unwrap().insert(
+                storage::Key::from(format!(
+                    "C0FFEE/bucketdata/{}/refs/logs/master/{}",
+                    FOLDER, name
+                )),
+                reflog_entry(*old_head, *new_head, true),
+            );
+        }
+        store.objects.lock().unwrap().insert(
+            storage::Key::from(format!("C0FFEE/bucketdata/{}/refs/logs/master/500", FOLDER)),
+            reflog_entry(None, 8, false),
+        );
+        store.objects.lock().unwrap().insert(
+            storage::Key::from(format!(
+                "C0FFEE/bucketdata/{}/refs/logs/master/3600",
+                FOLDER
+            )),
+            b"not a plist".to_vec(),
+        );
+
+        let reflog = folder.reflog().await.unwrap();
+        let ids: Vec<_> = reflog.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec!["500", "1000", "2000", "3000", "3500", "3700", "10000"]
+        );
+        assert_eq!(reflog[1].old_head, None);
+        assert_eq!(reflog[6].new_head, sha(3));
+
+        let orphans = folder.orphaned_commits().await.unwrap();
+        let ids: Vec<_> = orphans.commits.iter().map(|c| c.id().clone()).collect();
+        assert_eq!(ids, vec![sha(4), sha(6), sha(7)]);
+        assert_eq!(orphans.unsupported, vec![sha(8)]);
+    }
 
     #[test]
     fn test_parse() {
-        use std::path::PathBuf;
-        use uuid::Uuid;
-
         let text = r#"
         <plist version="1.0">
             <dict>
@@ -0,0 +1,243 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use arq_crypto::{
+    hmac_sha256, MasterKeys, ObjectDecrypter, ObjectDecrypterV2, ObjectEncrypter,
+    ObjectEncrypterV2, Password, SecretBytes,
+};
+use log::{debug, warn};
+
+const CACHE_KEY_FILE: &str = "cache.key";
+const CACHE_KEY_LEN: usize = 96;
+const VERIFIER_LEN: usize = 32;
+
+/**
+ * Somewhere to keep the keys derived for each computer between runs, so
+ * that we don't have to pay for key derivation every time.
+ *
+ * Entries are tied to the password and key file they were derived from, so
+ * a wrong password or a re-keyed backup set is never served stale keys.
+ *
+ * Skipping key derivation means a cache can check a password far faster
+ * than the key file can, so anyone who can read a cache can guess at the
+ * password that much faster too. Treat a cache as being as sensitive as
+ * the password itself.
+ */
+pub trait KeyCache: Send + Sync {
+    /// Returns the keys saved for `computer_id`, if they were saved with the
+    /// same password and key file content.
+    fn load(&self, computer_id: &str, password: &Password, key_file: &[u8]) -> Option<SecretBytes>;
+
+    fn save(&self, computer_id: &str, password: &Password, key_file: &[u8], keys: &SecretBytes);
+}
+
+/**
+ * A `KeyCache` that keeps each computer's keys in its own file, encrypted
+ * as an `ARQO` object with a randomly generated cache key.
+ *
+ * The cache key lives in `cache.key` inside the cache directory, and both
+ * are only accessible to their owner, so the cache is only as private as
+ * that directory. Put it somewhere that isn't shared or backed up.
+ *
+ * Each entry holds an HMAC of the password under the cache key, and an HMAC
+ * is cheap to compute. Whoever can read the directory can therefore test
+ * guesses at the password at HMAC speed rather than the key file's PBKDF2
+ * speed, so the directory is as sensitive as the password. Don't use a key
+ * cache where that is a problem.
+ */
+pub struct FileKeyCache {
+    dir: PathBuf,
+    hmac_key: SecretBytes,
+    encrypter: ObjectEncrypterV2,
+    decrypter: ObjectDecrypterV2,
+}
+
+#[cfg(unix)]
+fn private_options(opts: &mut OpenOptions) -> &mut OpenOptions {
+    use std::os::unix::fs::OpenOptionsExt;
+    opts.mode(0o600)
+}
+
+#[cfg(not(unix))]
+fn private_options(opts: &mut OpenOptions) -> &mut OpenOptions {
+    opts
+}
+
+pub(crate) fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
+
+pub(crate) fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut f =
+        private_options(OpenOptions::new().write(true).create(true).truncate(true)).open(path)?;
+    f.write_all(content)
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn master_keys(bytes: &[u8]) -> io::Result<MasterKeys> {
+    MasterKeys::from_secret(SecretBytes::from_slice(bytes))
+        .map_err(|_| invalid_data("Malformed cache key"))
+}
+
+/// Reads the random key that protects the cache in `dir`, creating the
+/// directory and the key if necessary. The first 64 bytes are a set of
+/// master keys for encrypting entries, the rest a key for naming them.
+pub(crate) fn open_cache_key(dir: &Path) -> io::Result<SecretBytes> {
+    create_private_dir(dir)?;
+
+    let key_path = dir.join(CACHE_KEY_FILE);
+    match File::open(&key_path) {
+        Ok(mut f) => {
+            let mut buf = SecretBytes::zeroed(CACHE_KEY_LEN);
+            f.read_exact(buf.as_mut_bytes())?;
+            Ok(buf)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("Creating cache key in {:?}", dir);
+            let keys = MasterKeys::generate().map_err(|_| invalid_data("No randomness"))?;
+            let buf = SecretBytes::from_slice(keys.as_bytes());
+            write_private(&key_path, buf.as_bytes())?;
+            Ok(buf)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+impl FileKeyCache {
+    /// Opens the cache in `dir`, creating it and its cache key if necessary.
+    pub fn open(dir: &Path) -> io::Result<FileKeyCache> {
+        let cache_key = open_cache_key(dir)?;
+        let bytes = cache_key.as_bytes();
+        Ok(FileKeyCache {
+            dir: dir.to_owned(),
+            hmac_key: SecretBytes::from_slice(&bytes[64..]),
+            encrypter: ObjectEncrypterV2::new(master_keys(&bytes[..64])?),
+            decrypter: ObjectDecrypterV2::new(master_keys(&bytes[..64])?),
+        })
+    }
+
+    fn hmac(&self, parts: &[&[u8]]) -> Option<Vec<u8>> {
+        hmac_sha256(self.hmac_key.as_bytes(), parts).ok()
+    }
+
+    /// Entries are named so as not to reveal which computers are cached
+    fn entry_path(&self, computer_id: &str) -> Option<PathBuf> {
+        let name = self.hmac(&[b"entry", computer_id.as_bytes()])?;
+        Some(self.dir.join(hex::encode(name)))
+    }
+
+    fn verifier(&self, password: &Password, key_file: &[u8]) -> Option<Vec<u8>> {
+        self.hmac(&[b"verifier", password.as_str().as_bytes(), &[0], key_file])
+    }
+}
+
+impl KeyCache for FileKeyCache {
+    fn load(&self, computer_id: &str, password: &Password, key_file: &[u8]) -> Option<SecretBytes> {
+        let path = self.entry_path(computer_id)?;
+        let object = fs::read(&path).ok()?;
+        let entry = self
+            .decrypter
+            .decrypt_object(&object)
+            .map(SecretBytes::new)
+            .map_err(|_| warn!("Ignoring unreadable key cache entry {:?}", path))
+            .ok()?;
+
+        let (verifier, keys) = entry.as_bytes().split_at(VERIFIER_LEN.min(entry.len()));
+        if Some(verifier) != self.verifier(password, key_file).as_deref() {
+            debug!("Cached keys for {} don't match", computer_id);
+            return None;
+        }
+
+        Some(SecretBytes::from_slice(keys))
+    }
+
+    fn save(&self, computer_id: &str, password: &Password, key_file: &[u8], keys: &SecretBytes) {
+        let (path, verifier) = match (
+            self.entry_path(computer_id),
+            self.verifier(password, key_file),
+        ) {
+            (Some(path), Some(verifier)) => (path, verifier),
+            _ => return,
+        };
+
+        let mut entry = SecretBytes::zeroed(VERIFIER_LEN + keys.len());
+        entry.as_mut_bytes()[..VERIFIER_LEN].copy_from_slice(&verifier);
+        entry.as_mut_bytes()[VERIFIER_LEN..].copy_from_slice(keys.as_bytes());
+
+        let result = self
+            .encrypter
+            .encrypt_object(entry.as_bytes())
+            .map_err(|_| invalid_data("Encryption failed"))
+            .and_then(|object| write_private(&path, &object));
+        if let Err(e) = result {
+            warn!(
+                "Saving keys for {} to {:?} failed: {}",
+                computer_id, path, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn temp_dir(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("larq-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        TempDir(dir)
+    }
+
+    #[test]
+    fn keys_are_cached_per_password_and_key_file() {
+        let dir = temp_dir("key-cache");
+        let password = Password::from("hunter2");
+        let keys = SecretBytes::from_slice(b"some derived keys");
+
+        {
+            let cache = FileKeyCache::open(&dir.0).unwrap();
+            assert!(cache.load("C0FFEE", &password, b"dat").is_none());
+            cache.save("C0FFEE", &password, b"dat", &keys);
+        }
+
+        // Reopening picks up the same cache key
+        let cache = FileKeyCache::open(&dir.0).unwrap();
+        let loaded = cache.load("C0FFEE", &password, b"dat").unwrap();
+        assert_eq!(loaded.as_bytes(), keys.as_bytes());
+
+        assert!(cache
+            .load("C0FFEE", &Password::from("wrong"), b"dat")
+            .is_none());
+        assert!(cache.load("C0FFEE", &password, b"rekeyed").is_none());
+        assert!(cache.load("DECAF", &password, b"dat").is_none());
+
+        // Nothing on disk gives away the keys or the computer
+        for entry in fs::read_dir(&dir.0).unwrap() {
+            let entry = entry.unwrap();
+            assert!(!entry.file_name().to_string_lossy().contains("C0FFEE"));
+            let content = fs::read(entry.path()).unwrap();
+            assert!(!content.windows(keys.len()).any(|w| w == keys.as_bytes()));
+        }
+    }
+}
@@ -2,11 +2,16 @@ mod commit;
 mod compression;
 mod computer;
 mod constructs;
+mod encryption;
 mod folder;
+mod key_cache;
 mod packset;
+mod reflog;
 mod repository;
+mod selector;
 mod sha;
 mod tree;
+mod tree_cache;
 
 #[cfg(test)]
 mod mocks;
@@ -16,7 +21,12 @@ pub mod storage {
 }
 
 pub mod s3 {
-    pub use arq_s3::Store;
+    pub use arq_s3::{Provider, Store, UnknownProvider};
+}
+
+#[cfg(feature = "sftp")]
+pub mod sftp {
+    pub use arq_sftp::{Auth, Error, Options, Store};
 }
 
 pub mod crypto {
@@ -29,13 +39,32 @@ pub enum RepoError {
     MalformedData,
     CryptoError, // probably bad key
     InputError,
+    Io(std::io::ErrorKind), // writing restored data failed
+    Unsupported,            // e.g. commits that predate key stretching
+
+    /// A blob's data doesn't match its SHA1. `path` is the file it belongs
+    /// to or, for a tree, the directory it describes. It's empty for a
+    /// commit.
+    CorruptBlob {
+        sha: SHA1,
+        path: std::path::PathBuf,
+    },
 }
 
+pub use commit::{
+    Change, Commit, CommitSummary, DiffEntry, FileError, FileQuery, FileSearch, PathFilter,
+    WalkEntry,
+};
 pub use computer::{Computer, ComputerInfo};
-pub use folder::{Folder, FolderInfo};
+pub use folder::{Folder, FolderInfo, Orphans, PathVersion};
+pub use key_cache::{FileKeyCache, KeyCache};
 pub use packset::Packset;
+pub use reflog::ReflogEntry;
 pub use repository::Repository;
+pub use selector::CommitSelector;
 pub use sha::SHA1;
+pub use tree::{BlobKey, Node, StorageType};
+pub use tree_cache::{FileTreeCache, TreeCache};
 
 pub fn format_uuid(id: &uuid::Uuid) -> String {
     let mut buf = uuid::Uuid::encode_buffer();
@@ -1,10 +1,30 @@
+use std::{convert::TryFrom, sync::Arc};
+
 use async_trait::async_trait;
+use chrono::prelude::*;
 
 use crate::{
-    crypto::{CryptoError, ObjectDecrypter},
+    crypto::{
+        CryptoError, MasterKeys, ObjectDecrypter, ObjectDecrypterV2, ObjectEncrypter,
+        ObjectEncrypterV2,
+    },
     storage::{self, Include, Key, ObjectInfo, Store},
+    Commit, Packset, TreeCache, SHA1,
 };
 
+/// Where the tests keep the trees of their backups
+pub const TREES: &str = "C0FFEE/packsets/F-trees/";
+
+/// A SHA1 of 20 `n` bytes
+pub fn sha(n: u8) -> SHA1 {
+    SHA1::try_from(&[n; 20][..]).unwrap()
+}
+
+/// The keys every test object is encrypted with
+pub fn keys() -> MasterKeys {
+    MasterKeys::new([1; 32], [2; 32], Some([3; 32]))
+}
+
 struct NullStore {}
 
 #[async_trait]
@@ -25,3 +45,278 @@ impl ObjectDecrypter for NullDecrypter {
         Ok(Vec::new())
     }
 }
+
+/// A tree cache that keeps everything in memory
+#[derive(Default)]
+pub struct MemoryTreeCache {
+    pub entries: std::sync::Mutex<std::collections::HashMap<SHA1, Vec<u8>>>,
+}
+
+impl TreeCache for MemoryTreeCache {
+    fn load(&self, sha: &SHA1) -> Option<Vec<u8>> {
+        self.entries.lock().unwrap().get(sha).cloned()
+    }
+
+    fn save(&self, sha: &SHA1, data: &[u8]) {
+        self.entries.lock().unwrap().insert(sha.clone(), data.to_vec());
+    }
+}
+
+/// A store that keeps everything in memory, for tests that need to write
+#[derive(Default)]
+pub struct MemoryStore {
+    pub objects: std::sync::Mutex<std::collections::BTreeMap<Key, Vec<u8>>>,
+
+    /// How many objects have been fetched
+    pub gets: std::sync::atomic::AtomicUsize,
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn list_contents(&self, path: &str, flags: Include) -> storage::Result<Vec<ObjectInfo>> {
+        let objects = self.objects.lock().unwrap();
+        let mut result: Vec<ObjectInfo> = Vec::new();
+        for (key, content) in objects.iter().filter(|(k, _)| k.as_str().starts_with(path)) {
+            let rest = &key.as_str()[path.len()..];
+            match rest.find('/') {
+                Some(n) if flags.contains(Include::DIRS) => {
+                    let dir = Key::from(format!("{}{}", path, &rest[..n + 1]));
+                    if !result.iter().any(|o| o.key == dir) {
+                        result.push(ObjectInfo { key: dir, size: 0 });
+                    }
+                }
+                None if flags.contains(Include::FILES) => result.push(ObjectInfo {
+                    key: key.clone(),
+                    size: content.len() as i64,
+                }),
+                _ => {}
+            }
+        }
+        Ok(result)
+    }
+
+    async fn get(&self, key: Key) -> storage::Result<Vec<u8>> {
+        self.gets.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.objects
+            .lock()
+            .unwrap()
+            .get(&key)
+            .cloned()
+            .ok_or(storage::Error::NoSuchObject)
+    }
+
+    async fn put(&self, key: Key, content: Vec<u8>) -> storage::Result<()> {
+        self.objects.lock().unwrap().insert(key, content);
+        Ok(())
+    }
+
+    async fn delete(&self, key: Key) -> storage::Result<()> {
+        self.objects
+            .lock()
+            .unwrap()
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(storage::Error::NoSuchObject)
+    }
+}
+
+/// Appends a string in Arq's format: a presence flag, then the length and
+/// the UTF-8 bytes
+pub fn put_string(out: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            out.push(1);
+            out.extend_from_slice(&(s.len() as u64).to_be_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+        None => out.push(0),
+    }
+}
+
+pub fn put_date_time(out: &mut Vec<u8>, t: &DateTime<Utc>) {
+    out.push(1);
+    out.extend_from_slice(&(t.timestamp_millis() as u64).to_be_bytes());
+}
+
+/// A format version 9 commit record with the given parents
+pub fn commit_record(parents: &[SHA1], tree: &SHA1, timestamp: &DateTime<Utc>) -> Vec<u8> {
+    let mut out = b"CommitV009".to_vec();
+    put_string(&mut out, Some("larq"));
+    put_string(&mut out, None);
+    out.extend_from_slice(&(parents.len() as u64).to_be_bytes());
+    for p in parents {
+        put_string(&mut out, Some(&p.as_string()));
+        out.push(1);
+    }
+    put_string(&mut out, Some(&tree.as_string()));
+    out.push(1); // expand key
+    out.push(0); // trees aren't compressed
+    put_string(&mut out, Some("file:///Users/larq"));
+    put_date_time(&mut out, timestamp);
+    out.extend_from_slice(&0u64.to_be_bytes()); // file errors
+    out.push(0); // missing nodes
+    out.push(1); // complete
+    out.extend_from_slice(&0u64.to_be_bytes()); // bucket plist
+    out
+}
+
+fn put_blob_key(out: &mut Vec<u8>, sha: Option<&SHA1>) {
+    put_string(out, sha.map(SHA1::as_string).as_deref());
+    out.push(1); // stretched key
+    out.extend_from_slice(&1u32.to_be_bytes()); // S3
+    put_string(out, None); // archive ID
+    out.extend_from_slice(&0u64.to_be_bytes()); // size
+    out.push(0); // upload date
+}
+
+/// The parts of a node that tests care about. The node's data is a single
+/// uncompressed blob.
+#[derive(Clone)]
+pub struct TestNode<'a> {
+    pub name: &'a str,
+    pub is_tree: bool,
+    pub blob: SHA1,
+    pub size: u64,
+    pub mode: i32,
+
+    /// Seconds since the epoch
+    pub mod_time: i64,
+}
+
+impl<'a> TestNode<'a> {
+    pub fn with_size(self, size: u64) -> TestNode<'a> {
+        TestNode { size, ..self }
+    }
+
+    pub fn with_mode(self, mode: i32) -> TestNode<'a> {
+        TestNode { mode, ..self }
+    }
+
+    pub fn with_mod_time(self, mod_time: i64) -> TestNode<'a> {
+        TestNode { mod_time, ..self }
+    }
+}
+
+/// A node of 100 bytes whose data is the blob `sha(blob)`
+pub fn node(name: &str, is_tree: bool, blob: u8) -> TestNode<'_> {
+    TestNode {
+        name,
+        is_tree,
+        blob: sha(blob),
+        size: 100,
+        mode: 0o644,
+        mod_time: 0,
+    }
+}
+
+/// A format version 18 tree record holding `nodes`
+pub fn tree_record(nodes: &[TestNode]) -> Vec<u8> {
+    let mut out = b"TreeV018".to_vec();
+    out.extend_from_slice(&[0, 0]); // xattrs and ACL aren't compressed
+    put_blob_key(&mut out, None);
+    out.extend_from_slice(&0u64.to_be_bytes()); // xattrs size
+    put_blob_key(&mut out, None);
+    out.extend_from_slice(&[0; 3 * 4 + 2 * 8 + 8 + 2 * 4]); // uid to finder flags
+    out.extend_from_slice(&[0; 4 * 4 + 2 * 8 + 8 + 4]); // st_dev to st_blksize
+    out.extend_from_slice(&[0; 2 * 8]); // creation time
+    out.extend_from_slice(&0u32.to_be_bytes()); // missing nodes
+
+    out.extend_from_slice(&(nodes.len() as u32).to_be_bytes());
+    for node in nodes {
+        put_string(&mut out, Some(node.name));
+        out.push(node.is_tree as u8);
+        out.push(0); // no missing items
+        out.extend_from_slice(&[0, 0, 0]); // nothing is compressed
+        out.extend_from_slice(&1u32.to_be_bytes());
+        put_blob_key(&mut out, Some(&node.blob));
+        out.extend_from_slice(&node.size.to_be_bytes());
+        put_blob_key(&mut out, None);
+        out.extend_from_slice(&0u64.to_be_bytes()); // xattrs size
+        put_blob_key(&mut out, None);
+        out.extend_from_slice(&[0; 2 * 4]); // uid and gid
+        out.extend_from_slice(&node.mode.to_be_bytes());
+        out.extend_from_slice(&node.mod_time.to_be_bytes());
+        out.extend_from_slice(&[0; 8 + 8 + 2 * 4]); // mtime nsec to finder flags
+        put_string(&mut out, None); // file type
+        put_string(&mut out, None); // creator
+        out.push(0); // hide extension
+        out.extend_from_slice(&[0; 4 * 4 + 4 * 8 + 8 + 4]); // st_dev to st_blksize
+    }
+    out
+}
+
+/// Writes `objects` into `root` as one pack file and its index, named after
+/// the first of the objects. Writing other objects adds another pack.
+pub fn write_packset(store: &MemoryStore, root: &str, objects: &[(SHA1, Vec<u8>)]) {
+    let mut objects: Vec<_> = objects.iter().collect();
+    objects.sort_by_key(|(sha, _)| sha.as_string());
+    let pack_id = objects
+        .first()
+        .map_or_else(|| "0".repeat(40), |(sha, _)| sha.as_string());
+
+    let mut pack = b"PACK".to_vec();
+    pack.extend_from_slice(&2u32.to_be_bytes());
+    pack.extend_from_slice(&(objects.len() as u64).to_be_bytes());
+
+    let mut counts = [0u32; 256];
+    let mut entries = Vec::new();
+    for (sha, content) in objects {
+        let offset = pack.len() as u64;
+        put_string(&mut pack, None);
+        put_string(&mut pack, None);
+        pack.extend_from_slice(&(content.len() as u64).to_be_bytes());
+        pack.extend_from_slice(content);
+
+        let first_byte = u8::from_str_radix(&sha.as_string()[..2], 16).unwrap();
+        for count in counts[first_byte as usize..].iter_mut() {
+            *count += 1;
+        }
+        entries.extend_from_slice(&offset.to_be_bytes());
+        entries.extend_from_slice(&(pack.len() as u64 - offset).to_be_bytes());
+        entries.extend_from_slice(&hex::decode(sha.as_string()).unwrap());
+        entries.extend_from_slice(&[0; 4]);
+    }
+
+    let mut index = vec![0xff, 0x74, 0x4f, 0x63];
+    index.extend_from_slice(&2u32.to_be_bytes());
+    for count in counts.iter() {
+        index.extend_from_slice(&count.to_be_bytes());
+    }
+    index.extend_from_slice(&entries);
+
+    let mut store_objects = store.objects.lock().unwrap();
+    store_objects.insert(Key::from(format!("{}{}.index", root, pack_id)), index);
+    store_objects.insert(Key::from(format!("{}{}.pack", root, pack_id)), pack);
+}
+
+/// Each of `trees` as a tree record encrypted with `keys()`, keyed by the
+/// SHA1 made of its number
+pub fn tree_objects(trees: &[(u8, Vec<TestNode>)]) -> Vec<(SHA1, Vec<u8>)> {
+    let encrypter = ObjectEncrypterV2::new(keys());
+    trees
+        .iter()
+        .map(|(id, nodes)| {
+            let tree = encrypter.encrypt_object(&tree_record(nodes)).ok().unwrap();
+            (sha(*id), tree)
+        })
+        .collect()
+}
+
+pub async fn open_packset(store: Arc<dyn Store>) -> Packset {
+    Packset::new(Key::from(TREES), &store).await.unwrap()
+}
+
+/// Writes `trees` into a new store as a single pack, and opens it
+pub async fn tree_packset(trees: &[(u8, Vec<TestNode<'_>>)]) -> (Arc<MemoryStore>, Packset) {
+    let store = Arc::new(MemoryStore::default());
+    write_packset(&store, TREES, &tree_objects(trees));
+    let packset = open_packset(store.clone()).await;
+    (store, packset)
+}
+
+/// A parentless commit `sha(id)` of the tree `sha(tree)`
+pub fn commit(packset: &Packset, id: u8, tree: u8) -> Commit<'_> {
+    let decrypter: Arc<dyn ObjectDecrypter> = Arc::new(ObjectDecrypterV2::new(keys()));
+    let record = commit_record(&[], &sha(tree), &Utc.timestamp(1_600_000_000, 0));
+    Commit::parse(sha(id), &record, packset, &decrypter).unwrap()
+}
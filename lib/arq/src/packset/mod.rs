@@ -1,7 +1,12 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::{BoxFuture, FutureExt, Shared};
 
 use crate::{
-    storage::{Key, Store},
+    storage::{self, Key, Store},
     RepoError, SHA1,
 };
 
@@ -17,6 +22,29 @@ pub struct Packset {
     root: Key,
     index: PackIndex,
     store: Arc<dyn Store>,
+
+    // The pack files being fetched right now, so that objects wanted at the
+    // same time from the same pack share a single download
+    fetches: Mutex<HashMap<SHA1, Arc<PackFetch>>>,
+}
+
+type PackFetch = Shared<BoxFuture<'static, storage::Result<Arc<Vec<u8>>>>>;
+
+// Forgets a pack fetch once whoever started it is done with it, however
+// that happens. Anyone else still waiting on it keeps their own handle.
+struct FetchGuard<'a> {
+    fetches: &'a Mutex<HashMap<SHA1, Arc<PackFetch>>>,
+    pack_id: SHA1,
+    fetch: Arc<PackFetch>,
+}
+
+impl Drop for FetchGuard<'_> {
+    fn drop(&mut self) {
+        let mut fetches = self.fetches.lock().unwrap();
+        if fetches.get(&self.pack_id).is_some_and(|f| Arc::ptr_eq(f, &self.fetch)) {
+            fetches.remove(&self.pack_id);
+        }
+    }
 }
 
 impl Packset {
@@ -29,6 +57,7 @@ impl Packset {
             root: key,
             index: i,
             store: store.clone(),
+            fetches: Mutex::new(HashMap::new()),
         })
     }
 
@@ -44,18 +73,52 @@ impl Packset {
             loc.offset
         );
 
-        let packfile_key = (&self.root) / &(loc.pack_id.as_string() + ".pack");
-        log::info!("Fetching blob {}", packfile_key.as_str());
         let packfile_data = self
-            .store
-            .get(packfile_key)
+            .fetch_pack(&loc.pack_id)
             .await
             .map_err(RepoError::Storage)?;
 
         // Perhaps verify that this is a pack file here?
         log::info!("Extracting blob...");
         let start = loc.offset as usize;
-        pack::parse_object(&packfile_data[start..])
+        let data = packfile_data.get(start..).ok_or(RepoError::MalformedData)?;
+        pack::parse_object(data)
+    }
+
+    // Fetches a whole pack file, or joins a fetch of it that is already
+    // under way.
+    async fn fetch_pack(&self, pack_id: &SHA1) -> storage::Result<Arc<Vec<u8>>> {
+        let (fetch, _guard) = {
+            let mut fetches = self.fetches.lock().unwrap();
+            match fetches.get(pack_id) {
+                Some(fetch) => {
+                    log::debug!("Joining the fetch of pack {}", pack_id);
+                    (PackFetch::clone(fetch), None)
+                }
+                None => {
+                    let key = (&self.root) / &(pack_id.as_string() + ".pack");
+                    log::info!("Fetching pack {}", key.as_str());
+                    let store = self.store.clone();
+                    let fetch: PackFetch = async move { store.get(key).await.map(Arc::new) }
+                        .boxed()
+                        .shared();
+                    let shared = Arc::new(fetch.clone());
+                    fetches.insert(pack_id.clone(), shared.clone());
+                    let guard = FetchGuard {
+                        fetches: &self.fetches,
+                        pack_id: pack_id.clone(),
+                        fetch: shared,
+                    };
+                    (fetch, Some(guard))
+                }
+            }
+        };
+        fetch.await
+    }
+
+    // Checks whether the packset has a blob, without fetching it
+    pub fn contains(&self, id: &SHA1) -> bool {
+        self.index.contains_key(id)
     }
 
     // Returns a reference to the underlyig blob store 
@@ -0,0 +1,157 @@
+use std::cmp::Ordering;
+
+use serde::Deserialize;
+
+use crate::{computer::PlistEnvelope, crypto::ObjectDecrypter, RepoError, SHA1};
+
+/**
+ * A record of Arq moving a folder's head to a new commit. Arq writes one to
+ * `bucketdata/<folder>/refs/logs/master/` for every commit it creates, and
+ * keeps them when a backup record is deleted, which makes the reflog the
+ * only way to find commits that are no longer reachable from the head.
+ *
+ * doc/format.txt describes what an entry holds but not its plist keys, and
+ * the names below haven't been checked against an entry written by Arq.
+ * `Folder::reflog` skips entries that don't parse rather than failing, and
+ * a missing stretch flag is taken to mean a stretched key, so that a wrong
+ * name can't hide every commit as unreadable.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ReflogEntry {
+    /// The name of the entry's file, which is the time it was written
+    #[serde(skip)]
+    pub id: String,
+
+    /// The head before this entry. Absent for a folder's first commit.
+    #[serde(rename = "oldHeadSHA1")]
+    pub old_head: Option<SHA1>,
+
+    #[serde(rename = "oldHeadStretchKey", default = "stretched")]
+    pub old_head_stretch_key: bool,
+
+    #[serde(rename = "newHeadSHA1")]
+    pub new_head: SHA1,
+
+    #[serde(rename = "newHeadStretchKey", default = "stretched")]
+    pub new_head_stretch_key: bool,
+
+    /// The pack file the new head was written to
+    #[serde(rename = "packSHA1")]
+    pub pack: Option<SHA1>,
+
+    /// Whether the history was rewritten, e.g. by deleting a backup record
+    #[serde(rename = "isRewrite", default)]
+    pub is_rewrite: bool,
+}
+
+/// Every commit larq can read has a stretched key
+fn stretched() -> bool {
+    true
+}
+
+impl ReflogEntry {
+    /// Orders entries by the time in their names, oldest first
+    pub(crate) fn chronological(a: &ReflogEntry, b: &ReflogEntry) -> Ordering {
+        (a.id.len(), &a.id).cmp(&(b.id.len(), &b.id))
+    }
+}
+
+/// Parses a reflog entry, decrypting it with the folder's decrypter if it's
+/// encrypted at all
+pub(crate) fn decode_entry(
+    id: &str,
+    object: &[u8],
+    decrypter: &dyn ObjectDecrypter,
+) -> Result<ReflogEntry, RepoError> {
+    let plist = match PlistEnvelope::detect(object) {
+        PlistEnvelope::Legacy(obj) | PlistEnvelope::Object(obj) => decrypter
+            .decrypt_object(obj)
+            .map_err(|_| RepoError::CryptoError)?,
+        PlistEnvelope::Plain(plist) => plist.to_vec(),
+    };
+
+    let mut entry: ReflogEntry = plist::from_bytes(&plist).map_err(|_| RepoError::MalformedData)?;
+    entry.id = id.to_owned();
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        crypto::{ObjectDecrypterV2, ObjectEncrypter, ObjectEncrypterV2},
+        mocks::keys,
+    };
+    use std::convert::TryFrom;
+
+    // Written by hand, not captured from Arq
+    const ENTRY: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <plist version="1.0">
+        <dict>
+            <key>oldHeadSHA1</key>
+            <string>f5f17fbb8c5662f6682785c8c457ff0f5570e836</string>
+            <key>oldHeadStretchKey</key>
+            <true/>
+            <key>newHeadSHA1</key>
+            <string>fdb0857877dcc79273df06cb02ed482f0b2608c4</string>
+            <key>newHeadStretchKey</key>
+            <true/>
+            <key>packSHA1</key>
+            <string>0123456789abcdef0123456789abcdef01234567</string>
+            <key>isRewrite</key>
+            <false/>
+        </dict>
+        </plist>"#;
+
+    #[test]
+    fn entries_are_parsed() {
+        let decrypter = ObjectDecrypterV2::new(keys());
+        let expected = ReflogEntry {
+            id: "1446266810000".to_string(),
+            old_head: SHA1::try_from("f5f17fbb8c5662f6682785c8c457ff0f5570e836").ok(),
+            old_head_stretch_key: true,
+            new_head: SHA1::try_from("fdb0857877dcc79273df06cb02ed482f0b2608c4").unwrap(),
+            new_head_stretch_key: true,
+            pack: SHA1::try_from("0123456789abcdef0123456789abcdef01234567").ok(),
+            is_rewrite: false,
+        };
+
+        let plain = decode_entry("1446266810000", ENTRY.as_bytes(), &decrypter);
+        assert_eq!(plain, Ok(expected.clone()));
+
+        let encrypted = ObjectEncrypterV2::new(keys())
+            .encrypt_object(ENTRY.as_bytes())
+            .ok()
+            .unwrap();
+        let decrypted = decode_entry("1446266810000", &encrypted, &decrypter);
+        assert_eq!(decrypted, Ok(expected));
+    }
+
+    #[test]
+    fn keys_are_stretched_unless_flagged_otherwise() {
+        let decrypter = ObjectDecrypterV2::new(keys());
+        // Both flags are <true/>, and nothing else is
+        let unflagged = ENTRY
+            .replace("<key>oldHeadStretchKey</key>", "")
+            .replace("<key>newHeadStretchKey</key>", "")
+            .replace("<true/>", "");
+        let entry = decode_entry("1", unflagged.as_bytes(), &decrypter).unwrap();
+        assert!(entry.old_head_stretch_key);
+        assert!(entry.new_head_stretch_key);
+
+        let unstretched = ENTRY.replace("<true/>", "<false/>");
+        let entry = decode_entry("1", unstretched.as_bytes(), &decrypter).unwrap();
+        assert!(!entry.old_head_stretch_key);
+        assert!(!entry.new_head_stretch_key);
+    }
+
+    #[test]
+    fn entries_sort_by_time() {
+        let decrypter = ObjectDecrypterV2::new(keys());
+        let entry = |id: &str| decode_entry(id, ENTRY.as_bytes(), &decrypter).unwrap();
+        let mut entries = [entry("100"), entry("99"), entry("1000"), entry("101")];
+        entries.sort_by(ReflogEntry::chronological);
+        let ids: Vec<_> = entries.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec!["99", "100", "101", "1000"]);
+    }
+}
@@ -1,20 +1,43 @@
 use futures::future;
-use std::sync::Arc;
+use log::{debug, warn};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 use uuid::Uuid;
 
 use crate::{
     computer::{Computer, ComputerInfo},
-    RepoError,
+    encryption::{self, Keyset},
+    tree_cache::EncryptedTreeCache,
+    KeyCache, RepoError, TreeCache,
 };
-use arq_crypto::{CryptoKey, ObjectDecrypter, ObjectDecrypterV1};
+use arq_crypto::{ObjectDecrypter, Password, SecretBytes};
 use arq_storage::{Include, Key as StorageKey, Store};
 
+/// What we derive from the password for each computer
+#[derive(Clone)]
+struct ComputerKeys {
+    decrypter: Arc<dyn ObjectDecrypter>,
+    blob_salt: Option<Arc<SecretBytes>>,
+    tree_cache_keys: Option<Arc<SecretBytes>>,
+}
+
 /**
- * Wraps up access to a backup repository
+ * Wraps up access to a backup repository. The password is never kept: the
+ * keys derived from it for each computer are, once it has been unlocked.
  */
 pub struct Repository {
     store: Arc<dyn Store>,
-    secret: String,
+
+    // Deriving keys is deliberately expensive, so we only want to do it
+    // once per computer
+    keys: Mutex<HashMap<String, ComputerKeys>>,
+    bucket_decrypter: Arc<dyn ObjectDecrypter>,
+    key_cache: Option<Box<dyn KeyCache>>,
+    tree_cache: Option<Arc<dyn TreeCache>>,
+    encrypt_tree_cache: bool,
+    verify_blobs: bool,
 }
 
 async fn fetch_computer_info(store: &dyn Store, id: StorageKey) -> Result<ComputerInfo, RepoError> {
@@ -32,41 +55,190 @@ async fn fetch_computer_info(store: &dyn Store, id: StorageKey) -> Result<Comput
 }
 
 impl Repository {
-    pub fn new(secret: &str, store: Arc<dyn Store>) -> Repository {
-        Repository {
-            secret: secret.to_owned(),
+    /// Opens the repository in `store`. Only the decrypter for legacy
+    /// bucket plists is derived from `password` here; each computer's own
+    /// keys are derived by `unlock`.
+    pub fn new(password: &Password, store: Arc<dyn Store>) -> Result<Repository, RepoError> {
+        Ok(Repository {
             store,
+            keys: Mutex::new(HashMap::new()),
+            bucket_decrypter: encryption::bucket_decrypter(password.as_str())?,
+            key_cache: None,
+            tree_cache: None,
+            encrypt_tree_cache: false,
+            verify_blobs: false,
+        })
+    }
+
+    /// Persists the keys derived for each computer in `cache`, so that later
+    /// runs can skip key derivation.
+    pub fn with_key_cache(self, cache: Box<dyn KeyCache>) -> Repository {
+        Repository {
+            key_cache: Some(cache),
+            ..self
+        }
+    }
+
+    /// Keeps the trees and commits loaded through this repository in
+    /// `cache`, and looks for them there before going to storage.
+    pub fn with_tree_cache(self, cache: Arc<dyn TreeCache>) -> Repository {
+        Repository {
+            tree_cache: Some(cache),
+            ..self
+        }
+    }
+
+    /// Like `with_tree_cache`, but encrypts what goes into `cache` with keys
+    /// derived from each computer's own, so that reading it needs the
+    /// password.
+    pub fn with_encrypted_tree_cache(self, cache: Arc<dyn TreeCache>) -> Repository {
+        Repository {
+            tree_cache: Some(cache),
+            encrypt_tree_cache: true,
+            ..self
+        }
+    }
+
+    /// Checks that the data of every blob loaded through this repository
+    /// hashes to the SHA1 it is stored under. Blobs in legacy backup sets
+    /// (those without a key file) are not checked.
+    pub fn with_blob_verification(self) -> Repository {
+        Repository {
+            verify_blobs: true,
+            ..self
+        }
+    }
+
+    /// Derives the keys for a computer's backups from `password`, so that
+    /// `get_computer` can open it. A computer that is already unlocked is
+    /// left as it is.
+    pub async fn unlock(&self, computer_id: &str, password: &Password) -> Result<(), RepoError> {
+        if self.keys.lock().unwrap().contains_key(computer_id) {
+            return Ok(());
+        }
+
+        let key_file = encryption::fetch_encryption_dat(self.store.as_ref(), computer_id)
+            .await?
+            .map(|(_, content)| content);
+        let key_file_content = key_file.as_deref().unwrap_or(&[]);
+
+        let cached = self
+            .key_cache
+            .as_ref()
+            .and_then(|cache| cache.load(computer_id, password, key_file_content))
+            .and_then(|bytes| Keyset::from_secret(bytes.as_bytes()));
+
+        let keyset = match cached {
+            Some(keyset) => {
+                debug!("Using cached keys for {}", computer_id);
+                keyset
+            }
+            None => {
+                let keyset = encryption::derive_keyset(
+                    self.store.as_ref(),
+                    computer_id,
+                    password.as_str(),
+                    key_file.as_deref(),
+                )
+                .await?;
+                if let Some(cache) = self.key_cache.as_ref() {
+                    cache.save(computer_id, password, key_file_content, &keyset.to_secret());
+                }
+                keyset
+            }
+        };
+
+        let keys = ComputerKeys {
+            blob_salt: keyset.blob_salt(computer_id).map(Arc::new),
+            tree_cache_keys: keyset.tree_cache_keys().map(Arc::new),
+            decrypter: keyset.into_decrypter(),
+        };
+        self.keys
+            .lock()
+            .unwrap()
+            .insert(computer_id.to_owned(), keys);
+        Ok(())
+    }
+
+    fn computer_keys(&self, computer_id: &str) -> Result<ComputerKeys, RepoError> {
+        match self.keys.lock().unwrap().get(computer_id) {
+            Some(keys) => Ok(keys.clone()),
+            None => {
+                warn!("{} has not been unlocked", computer_id);
+                Err(RepoError::CryptoError)
+            }
         }
     }
 
+    /// The tree cache for a computer, encrypted with its keys if need be. If
+    /// they can't be had, the computer goes without a cache rather than
+    /// writing plaintext into one meant to be encrypted.
+    fn computer_tree_cache(&self, keys: &ComputerKeys) -> Option<Arc<dyn TreeCache>> {
+        let cache = self.tree_cache.clone()?;
+        if !self.encrypt_tree_cache {
+            return Some(cache);
+        }
+        let keys = keys.tree_cache_keys.as_ref()?;
+        match EncryptedTreeCache::new(cache, keys.as_bytes()) {
+            Ok(cache) => Some(Arc::new(cache)),
+            Err(e) => {
+                warn!("Encrypting the tree cache failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Opens a computer that has been unlocked with `unlock`.
     pub async fn get_computer(&self, id: String) -> Result<Computer, RepoError> {
         let machine_key = StorageKey::from(id);
 
-        let salt = self
-            .store
-            .get(machine_key.clone() / "salt")
-            .await
-            .map_err(RepoError::Storage)?;
+        let keys = self.computer_keys(machine_key.as_str())?;
 
-        let object_decrypter = CryptoKey::new(&self.secret, &salt[..])
-            .map(ObjectDecrypterV1::new)
-            .map(|d| Arc::new(d) as Arc<dyn ObjectDecrypter>)
-            .map_err(|_| RepoError::CryptoError)?;
+        let info = fetch_computer_info(self.store.as_ref(), machine_key.clone()).await?;
 
-        // if repo version == 1, otherwise re-use object decrypter
-        let bucket_decrypter = CryptoKey::new(&self.secret, "BucketPL".as_bytes())
-            .map(ObjectDecrypterV1::new)
-            .map(|d| Arc::new(d) as Arc<dyn ObjectDecrypter>)
-            .map_err(|_| RepoError::CryptoError)?;
+        // Bucket plists in the legacy envelope use the bucket decrypter; the
+        // others use the computer's own decrypter
+        let computer = Computer::new(info, &keys.decrypter, &self.bucket_decrypter, &self.store)
+            .with_tree_cache(self.computer_tree_cache(&keys));
+        if !self.verify_blobs {
+            return Ok(computer);
+        }
+        match keys.blob_salt {
+            Some(salt) => Ok(computer.with_blob_salt(salt)),
+            None => {
+                warn!("Blobs in legacy backup sets can't be verified");
+                Ok(computer)
+            }
+        }
+    }
 
-        let info = fetch_computer_info(self.store.as_ref(), machine_key.clone()).await?;
+    /// Checks whether `password` is correct for a computer, without having
+    /// to decrypt any of its backup data.
+    pub async fn verify_password(
+        &self,
+        computer_id: &str,
+        password: &Password,
+    ) -> Result<bool, RepoError> {
+        encryption::verify_password(self.store.as_ref(), computer_id, password.as_str()).await
+    }
 
-        Ok(Computer::new(
-            info,
-            &object_decrypter,
-            &bucket_decrypter,
-            &self.store,
-        ))
+    /// Re-encrypts a computer's master keys so that they are protected by
+    /// `new_password` instead of `old_password`. Backup sets without an
+    /// `encryptionv3.dat` or `encryptionv2.dat` file can't have their
+    /// password changed this way.
+    pub async fn change_password(
+        &self,
+        computer_id: &str,
+        old_password: &Password,
+        new_password: &Password,
+    ) -> Result<(), RepoError> {
+        encryption::change_password(
+            self.store.as_ref(),
+            computer_id,
+            old_password.as_str(),
+            new_password.as_str(),
+        )
+        .await
     }
 
     // pub async fn get_computer(&self, id: &str) -> Result<Computer, RepoError> {
@@ -109,3 +281,115 @@ impl Repository {
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mocks::MemoryStore;
+    use arq_crypto::{EncryptionDat, MasterKeys};
+    use arq_storage::CachedStore;
+
+    /// Remembers what it is given, and counts how often it's asked
+    #[derive(Default)]
+    struct TestKeyCache {
+        entries: Mutex<HashMap<String, Vec<u8>>>,
+        hits: Mutex<usize>,
+    }
+
+    impl KeyCache for Arc<TestKeyCache> {
+        fn load(&self, computer_id: &str, _: &Password, _: &[u8]) -> Option<SecretBytes> {
+            let entries = self.entries.lock().unwrap();
+            let entry = entries.get(computer_id)?;
+            *self.hits.lock().unwrap() += 1;
+            Some(SecretBytes::from_slice(entry))
+        }
+
+        fn save(&self, computer_id: &str, _: &Password, _: &[u8], keys: &SecretBytes) {
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(computer_id.to_owned(), keys.as_bytes().to_vec());
+        }
+    }
+
+    fn test_store() -> Arc<MemoryStore> {
+        let keys = MasterKeys::generate().ok().unwrap();
+        let dat = EncryptionDat::seal(&keys, "pw").ok().unwrap();
+        let store = MemoryStore::default();
+        store
+            .objects
+            .lock()
+            .unwrap()
+            .insert(StorageKey::from("C0FFEE/encryptionv3.dat"), dat.to_bytes());
+        Arc::new(store)
+    }
+
+    #[tokio::test]
+    async fn decrypters_are_derived_once() {
+        let store = test_store();
+        let repo = Repository::new(&Password::from("pw"), store.clone()).unwrap();
+        assert!(repo.computer_keys("C0FFEE").is_err());
+
+        repo.unlock("C0FFEE", &Password::from("pw")).await.unwrap();
+        let first = repo.computer_keys("C0FFEE").ok().unwrap();
+
+        // Later calls don't go back to the store at all
+        store.objects.lock().unwrap().clear();
+        repo.unlock("C0FFEE", &Password::from("pw")).await.unwrap();
+        let second = repo.computer_keys("C0FFEE").ok().unwrap();
+        assert!(Arc::ptr_eq(&first.decrypter, &second.decrypter));
+    }
+
+    #[tokio::test]
+    async fn derived_keys_are_shared_through_key_cache() {
+        let store = test_store();
+        let cache = Arc::new(TestKeyCache::default());
+
+        let password = Password::from("pw");
+        let repo = Repository::new(&password, store.clone())
+            .unwrap()
+            .with_key_cache(Box::new(cache.clone()));
+        repo.unlock("C0FFEE", &password).await.unwrap();
+        let derived = repo.computer_keys("C0FFEE").ok().unwrap();
+        assert_eq!(*cache.hits.lock().unwrap(), 0);
+        assert!(cache.entries.lock().unwrap().contains_key("C0FFEE"));
+
+        let repo = Repository::new(&password, store)
+            .unwrap()
+            .with_key_cache(Box::new(cache.clone()));
+        repo.unlock("C0FFEE", &password).await.unwrap();
+        let cached = repo.computer_keys("C0FFEE").ok().unwrap();
+        assert_eq!(*cache.hits.lock().unwrap(), 1);
+
+        // The blob salt comes along with the cached keys
+        assert!(derived.blob_salt.is_some());
+        assert_eq!(
+            cached.blob_salt.map(|s| s.as_bytes().to_vec()),
+            derived.blob_salt.map(|s| s.as_bytes().to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn changed_passwords_are_seen_through_the_cache() {
+        let store = test_store();
+        let dir = std::env::temp_dir().join(format!("larq-cached-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cached: Arc<dyn Store> = Arc::new(CachedStore::new(store.clone(), dir.clone()));
+
+        let (old, new) = (Password::from("pw"), Password::from("new"));
+        let repo = Repository::new(&old, cached).unwrap();
+        assert_eq!(repo.verify_password("C0FFEE", &old).await, Ok(true));
+
+        // larq-restore makes the change straight to the backend
+        Repository::new(&old, store)
+            .unwrap()
+            .change_password("C0FFEE", &old, &new)
+            .await
+            .unwrap();
+
+        assert_eq!(repo.verify_password("C0FFEE", &new).await, Ok(true));
+        assert_eq!(repo.verify_password("C0FFEE", &old).await, Ok(false));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,109 @@
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use chrono::prelude::*;
+
+use crate::SHA1;
+
+/// The shortest SHA1 prefix we accept, as for git
+const MIN_PREFIX_LEN: usize = 4;
+
+/**
+ * Picks out one commit of a folder. The string forms are `HEAD`, `HEAD~n`
+ * for the n-th ancestor of the head, a full SHA1, or a hex prefix of one;
+ * `AsOf` has no string form, since it's usually chosen with a separate
+ * option.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommitSelector {
+    /// The head, or one of its ancestors through first parents
+    Head(usize),
+
+    Sha(SHA1),
+
+    /// A hex prefix of the SHA1 of exactly one commit in the history
+    Prefix(String),
+
+    /// The newest commit in the history at or before this time
+    AsOf(DateTime<Utc>),
+}
+
+impl Default for CommitSelector {
+    fn default() -> Self {
+        CommitSelector::Head(0)
+    }
+}
+
+impl FromStr for CommitSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CommitSelector, String> {
+        if s == "HEAD" {
+            return Ok(CommitSelector::Head(0));
+        }
+        if let Some(n) = s.strip_prefix("HEAD~") {
+            return n
+                .parse()
+                .map(CommitSelector::Head)
+                .map_err(|_| format!("Not a number of commits: {:?}", n));
+        }
+
+        if s.len() < MIN_PREFIX_LEN || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "Expected HEAD, HEAD~n or at least {} hex digits of a SHA1, not {:?}",
+                MIN_PREFIX_LEN, s
+            ));
+        }
+        match SHA1::try_from(s) {
+            Ok(sha) => Ok(CommitSelector::Sha(sha)),
+            Err(_) if s.len() < 40 => Ok(CommitSelector::Prefix(s.to_ascii_lowercase())),
+            Err(e) => Err(e.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for CommitSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommitSelector::Head(0) => f.write_str("HEAD"),
+            CommitSelector::Head(n) => write!(f, "HEAD~{}", n),
+            CommitSelector::Sha(sha) => write!(f, "{}", sha),
+            CommitSelector::Prefix(prefix) => f.write_str(prefix),
+            CommitSelector::AsOf(t) => write!(f, "as of {}", t.to_rfc3339()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn selectors_are_parsed() {
+        let sha = "f5f17fbb8c5662f6682785c8c457ff0f5570e836";
+        let parse = |s: &str| s.parse::<CommitSelector>();
+
+        assert_eq!(parse("HEAD"), Ok(CommitSelector::Head(0)));
+        assert_eq!(parse("HEAD~3"), Ok(CommitSelector::Head(3)));
+        assert_eq!(
+            parse(sha),
+            Ok(CommitSelector::Sha(SHA1::try_from(sha).unwrap()))
+        );
+        assert_eq!(
+            parse("F5F17F"),
+            Ok(CommitSelector::Prefix("f5f17f".to_string()))
+        );
+
+        assert!(parse("HEAD~").is_err());
+        assert!(parse("HEAD~x").is_err());
+        assert!(parse("f5f").is_err());
+        assert!(parse("master").is_err());
+        assert!(parse(&format!("{}00", sha)).is_err());
+    }
+
+    #[test]
+    fn selectors_round_trip() {
+        for s in ["HEAD", "HEAD~2", "f5f17f"].iter() {
+            assert_eq!(s.parse::<CommitSelector>().unwrap().to_string(), *s);
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::{convert::TryFrom, fmt};
 
 #[derive(Clone, PartialEq, Eq, Hash)]
@@ -59,3 +60,17 @@ impl fmt::Display for SHA1 {
         f.write_str(&hex::encode(a))
     }
 }
+
+/// SHA1s are serialised as hex strings, as they appear everywhere else
+impl Serialize for SHA1 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.as_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for SHA1 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SHA1, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        SHA1::try_from(text).map_err(de::Error::custom)
+    }
+}
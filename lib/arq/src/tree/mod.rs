@@ -11,7 +11,7 @@ pub use index::*;
 pub use parser::parse;
 
 /// The storage types we know about
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum StorageType {
     None,      // Does not refer to a physical object
     S3,        // Normal immediate-access storage
@@ -38,7 +38,7 @@ impl TryFrom<u32> for StorageType {
 
 /// A blob key describes both the identity of a blob and the parameters
 /// you need to retrieve it.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BlobKey {
     /// The identity of the blob 
     pub sha: SHA1,
@@ -50,7 +50,7 @@ pub struct BlobKey {
     pub upload_date: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Node {
     pub name: String,
     pub is_tree: bool,
@@ -82,6 +82,14 @@ pub struct Node {
     pub st_block_size: i32,
 }
 
+impl Node {
+    /// Whether two nodes have the same data, going by their blobs' SHA1s
+    pub fn same_content(&self, other: &Node) -> bool {
+        let shas = |n: &Node| n.data_blob_keys.iter().map(|k| k.sha.clone()).collect::<Vec<_>>();
+        shas(self) == shas(other)
+    }
+}
+
 #[derive(Debug)]
 pub struct Tree {
     pub version: usize,
@@ -0,0 +1,222 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use arq_crypto::{ObjectDecrypter, ObjectDecrypterV2, ObjectEncrypter, ObjectEncrypterV2};
+use log::{debug, warn};
+
+use crate::{
+    key_cache::{create_private_dir, master_keys, write_private},
+    SHA1,
+};
+
+/**
+ * Somewhere to keep the decrypted trees and commits of a backup set between
+ * runs, so that browsing the same backups again doesn't mean downloading,
+ * decrypting and decompressing them all over again.
+ *
+ * Entries are keyed by the SHA1 they are stored under, and the content
+ * behind a SHA1 never changes, so they can't go stale.
+ */
+pub trait TreeCache: Send + Sync {
+    /// Returns the decrypted, decompressed object stored under `sha`, if it
+    /// has been cached.
+    fn load(&self, sha: &SHA1) -> Option<Vec<u8>>;
+
+    fn save(&self, sha: &SHA1, data: &[u8]);
+}
+
+/// Tells apart the temporary files of concurrent writes from this process
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/**
+ * A `TreeCache` that keeps each object in its own file, named for its SHA1
+ * in the same way as a sharded objects directory.
+ *
+ * Entries are stored as they are given. The objects describe every file in
+ * the backups, so unless the repository is asked to encrypt them (see
+ * `Repository::with_encrypted_tree_cache`) the cache is only as private as
+ * its directory.
+ */
+pub struct FileTreeCache {
+    dir: PathBuf,
+}
+
+impl FileTreeCache {
+    /// Opens the cache in `dir`, creating it if necessary.
+    pub fn open(dir: &Path) -> io::Result<FileTreeCache> {
+        create_private_dir(dir)?;
+        Ok(FileTreeCache {
+            dir: dir.to_owned(),
+        })
+    }
+
+    fn entry_path(&self, sha: &SHA1) -> PathBuf {
+        let name = sha.as_string();
+        self.dir.join(&name[..2]).join(&name[2..])
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        if let Some(shard) = path.parent() {
+            create_private_dir(shard)?;
+        }
+
+        // Write somewhere else first, so that nobody reading the cache at
+        // the same time can see half an entry
+        let tmp = temp_path(path);
+        write_private(&tmp, data)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Somewhere next to `path` to write its content before moving it into
+/// place. The name is unique, so that writers in this or another process
+/// can't clobber each other's half-written entries.
+fn temp_path(path: &Path) -> PathBuf {
+    path.with_extension(format!(
+        "tmp.{}.{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+impl TreeCache for FileTreeCache {
+    fn load(&self, sha: &SHA1) -> Option<Vec<u8>> {
+        let path = self.entry_path(sha);
+        fs::read(&path).ok()
+    }
+
+    fn save(&self, sha: &SHA1, data: &[u8]) {
+        let path = self.entry_path(sha);
+        debug!("Caching {} in {:?}", sha, path);
+        if let Err(e) = self.write(&path, data) {
+            warn!("Caching {} in {:?} failed: {}", sha, path, e);
+        }
+    }
+}
+
+/**
+ * Encrypts entries on their way into another `TreeCache`, with keys derived
+ * from those of the computer the entries belong to. Reading them back needs
+ * the password, just like reading the backups themselves.
+ */
+pub(crate) struct EncryptedTreeCache {
+    inner: Arc<dyn TreeCache>,
+    encrypter: ObjectEncrypterV2,
+    decrypter: ObjectDecrypterV2,
+}
+
+impl EncryptedTreeCache {
+    /// `keys` is a set of master keys, laid out as in an encryption dat
+    /// file
+    pub(crate) fn new(inner: Arc<dyn TreeCache>, keys: &[u8]) -> io::Result<EncryptedTreeCache> {
+        Ok(EncryptedTreeCache {
+            inner,
+            encrypter: ObjectEncrypterV2::new(master_keys(keys)?),
+            decrypter: ObjectDecrypterV2::new(master_keys(keys)?),
+        })
+    }
+}
+
+impl TreeCache for EncryptedTreeCache {
+    fn load(&self, sha: &SHA1) -> Option<Vec<u8>> {
+        let content = self.inner.load(sha)?;
+        self.decrypter
+            .decrypt_object(&content)
+            .map_err(|_| debug!("Ignoring tree cache entry {} written with other keys", sha))
+            .ok()
+    }
+
+    fn save(&self, sha: &SHA1, data: &[u8]) {
+        match self.encrypter.encrypt_object(data) {
+            Ok(content) => self.inner.save(sha, &content),
+            Err(e) => warn!("Encrypting {} for the tree cache failed: {:?}", sha, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mocks::sha;
+
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn temp_dir(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("larq-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        TempDir(dir)
+    }
+
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut result = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                result.append(&mut files(&path));
+            } else {
+                result.push(path);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn trees_are_cached_by_sha() {
+        let dir = temp_dir("tree-cache");
+        {
+            let cache = FileTreeCache::open(&dir.0).unwrap();
+            assert_eq!(cache.load(&sha(1)), None);
+            cache.save(&sha(1), b"a tree");
+        }
+
+        let cache = FileTreeCache::open(&dir.0).unwrap();
+        assert_eq!(cache.load(&sha(1)), Some(b"a tree".to_vec()));
+        assert_eq!(cache.load(&sha(2)), None);
+        assert_eq!(files(&dir.0), vec![dir.0.join("01").join("01".repeat(19))]);
+    }
+
+    #[test]
+    fn entries_are_written_through_unique_temp_files() {
+        let path = Path::new("cache").join("01").join("01".repeat(19));
+        let a = temp_path(&path);
+        let b = temp_path(&path);
+        assert_ne!(a, b);
+        assert_eq!(a.parent(), path.parent());
+        assert_eq!(b.parent(), path.parent());
+    }
+
+    #[test]
+    fn encrypted_entries_are_unreadable_with_other_keys() {
+        let dir = temp_dir("encrypted-tree-cache");
+        let inner: Arc<dyn TreeCache> = Arc::new(FileTreeCache::open(&dir.0).unwrap());
+        let keys = [1u8; 64];
+        {
+            let cache = EncryptedTreeCache::new(inner.clone(), &keys).unwrap();
+            cache.save(&sha(1), b"a secret tree");
+        }
+
+        let cache = EncryptedTreeCache::new(inner.clone(), &keys).unwrap();
+        assert_eq!(cache.load(&sha(1)), Some(b"a secret tree".to_vec()));
+
+        for path in files(&dir.0) {
+            let content = fs::read(path).unwrap();
+            assert!(!content.windows(6).any(|w| w == b"secret"));
+        }
+
+        // Other keys make the entries useless, but not harmful
+        let cache = EncryptedTreeCache::new(inner, &[2u8; 64]).unwrap();
+        assert_eq!(cache.load(&sha(1)), None);
+    }
+}